@@ -0,0 +1,200 @@
+//! ACPI table discovery.
+//! We only locate and validate tables here, their users interpret them.
+//! https://uefi.org/specs/ACPI/6.4/05_ACPI_Software_Programming_Model/ACPI_Software_Programming_Model.html
+
+use core::mem::size_of;
+use core::ptr::{addr_of, read_unaligned};
+use core::{slice, str};
+
+use spin::Once;
+
+use crate::boot::StivaleStruct;
+use crate::memory::phys_to_virt;
+
+/// Root System Description Pointer, given to us by the bootloader.
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Only valid if revision >= 2
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// Size of the ACPI 1.0 part of the RSDP.
+const RSDP_V1_SIZE: usize = 20;
+
+/// Header common to every System Description Table.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    pub fn signature(&self) -> &str {
+        str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    /// The whole table, header included.
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, self.length as usize) }
+    }
+}
+
+/// Generic Address Structure, describes where a register lives.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// 0: system memory, 1: system I/O
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+}
+
+/// IA-PC High Precision Event Timer Table
+/// https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/software-developers-hpet-spec-1-0a.pdf
+#[repr(C, packed)]
+pub struct HpetTable {
+    pub header: SdtHeader,
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+/// Root table: either the RSDT (32-bit entries) or the XSDT (64-bit entries).
+struct RootTable {
+    header: &'static SdtHeader,
+    entry_size: usize,
+}
+
+static ROOT: Once<RootTable> = Once::new();
+
+/// All bytes of an ACPI structure must sum to 0.
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Map a physical table address to its header, checking it's valid.
+unsafe fn table_at(phys: u64) -> Option<&'static SdtHeader> {
+    let header = &*(phys_to_virt(phys) as *const SdtHeader);
+    if checksum_ok(header.bytes()) {
+        Some(header)
+    } else {
+        warn!("ACPI table {} has a bad checksum", header.signature());
+        None
+    }
+}
+
+pub fn init(boot_info: &StivaleStruct) {
+    let rsdp = match boot_info.rsdp() {
+        Some(rsdp) => unsafe { &*(rsdp as *const Rsdp) },
+        None => {
+            warn!("No RSDP, ACPI tables unavailable");
+            return;
+        }
+    };
+
+    let v1_bytes = unsafe { slice::from_raw_parts(rsdp as *const Rsdp as *const u8, RSDP_V1_SIZE) };
+    if &rsdp.signature != b"RSD PTR " || !checksum_ok(v1_bytes) {
+        warn!("Invalid RSDP");
+        return;
+    }
+
+    let root = unsafe {
+        if rsdp.revision >= 2 {
+            let length = read_unaligned(addr_of!(rsdp.length)) as usize;
+            let bytes = slice::from_raw_parts(rsdp as *const Rsdp as *const u8, length);
+            if !checksum_ok(bytes) {
+                warn!("Invalid extended RSDP");
+                return;
+            }
+            table_at(read_unaligned(addr_of!(rsdp.xsdt_address))).map(|header| RootTable {
+                header,
+                entry_size: size_of::<u64>(),
+            })
+        } else {
+            table_at(read_unaligned(addr_of!(rsdp.rsdt_address)) as u64).map(|header| RootTable {
+                header,
+                entry_size: size_of::<u32>(),
+            })
+        }
+    };
+
+    match root {
+        Some(root) => {
+            debug!("ACPI root table: {}", root.header.signature());
+            ROOT.call_once(|| root);
+        }
+        None => warn!("Invalid ACPI root table"),
+    }
+}
+
+/// Iterate over every valid table pointed to by the root table.
+pub fn tables() -> impl Iterator<Item = &'static SdtHeader> {
+    let (entries, entry_size): (&[u8], usize) = match ROOT.r#try() {
+        Some(root) => (
+            &root.header.bytes()[size_of::<SdtHeader>()..],
+            root.entry_size,
+        ),
+        None => (&[], 1),
+    };
+
+    entries.chunks_exact(entry_size).filter_map(|entry| {
+        let mut address = [0u8; 8];
+        address[..entry.len()].copy_from_slice(entry);
+        unsafe { table_at(u64::from_le_bytes(address)) }
+    })
+}
+
+/// Find the table with `signature`, e.g. `b"HPET"`.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    tables().find(|table| &table.signature == signature)
+}
+
+pub fn hpet_table() -> Option<&'static HpetTable> {
+    let header = find_table(b"HPET")?;
+    let length = header.length as usize;
+    if length < size_of::<HpetTable>() {
+        warn!("HPET table too short ({} bytes)", length);
+        return None;
+    }
+    Some(unsafe { &*(header as *const SdtHeader as *const HpetTable) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn checksum() {
+        assert!(checksum_ok(&[0x01, 0xff]));
+        assert!(!checksum_ok(&[0x01, 0xfe]));
+    }
+
+    #[test_case]
+    fn qemu_has_madt() {
+        assert!(find_table(b"APIC").is_some());
+    }
+}
@@ -0,0 +1,130 @@
+//! Demangling of legacy Rust symbols, e.g.
+//! `_ZN4core9panicking5panic17h0123456789abcdefE` -> `core::panicking::panic`.
+//! Other symbols are printed as they are.
+
+use core::fmt::{self, Write};
+
+pub struct Demangle<'a>(pub &'a str);
+
+/// `h` followed by 16 hex digits.
+fn is_hash(component: &str) -> bool {
+    component.len() == 17
+        && component.starts_with('h')
+        && component[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The `$...$` escapes and `..` separators inside a path component.
+fn write_component(f: &mut fmt::Formatter, mut component: &str) -> fmt::Result {
+    if component.starts_with("_$") {
+        component = &component[1..];
+    }
+    while !component.is_empty() {
+        if let Some(rest) = component.strip_prefix("..") {
+            f.write_str("::")?;
+            component = rest;
+        } else if let Some(rest) = component.strip_prefix('$') {
+            let end = match rest.find('$') {
+                Some(end) => end,
+                None => return f.write_str(component),
+            };
+            let escaped = match &rest[..end] {
+                "SP" => '@',
+                "BP" => '*',
+                "RF" => '&',
+                "LT" => '<',
+                "GT" => '>',
+                "LP" => '(',
+                "RP" => ')',
+                "C" => ',',
+                code => match code
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                {
+                    Some(c) => c,
+                    None => {
+                        f.write_char('$')?;
+                        component = rest;
+                        continue;
+                    }
+                },
+            };
+            f.write_char(escaped)?;
+            component = &rest[end + 1..];
+        } else {
+            let end = component[1..]
+                .find(['$', '.'])
+                .map_or(component.len(), |i| i + 1);
+            f.write_str(&component[..end])?;
+            component = &component[end..];
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = match self.0.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) {
+            Some(rest) => rest,
+            None => return f.write_str(self.0),
+        };
+
+        let mut first = true;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let len = match rest[..digits].parse::<usize>() {
+                Ok(len) if digits + len <= rest.len() => len,
+                _ => return f.write_str(self.0),
+            };
+            let component = &rest[digits..digits + len];
+            rest = &rest[digits + len..];
+            if rest.is_empty() && is_hash(component) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_component(f, component)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Compare without allocating.
+    struct Expect<'a>(&'a str);
+
+    impl Write for Expect<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            match self.0.strip_prefix(s) {
+                Some(rest) => {
+                    self.0 = rest;
+                    Ok(())
+                }
+                None => Err(fmt::Error),
+            }
+        }
+    }
+
+    fn demangles_to(mangled: &str, expected: &str) -> bool {
+        let mut expect = Expect(expected);
+        write!(expect, "{}", Demangle(mangled)).is_ok() && expect.0.is_empty()
+    }
+
+    #[test_case]
+    fn legacy_symbols() {
+        assert!(demangles_to(
+            "_ZN4core9panicking5panic17h0123456789abcdefE",
+            "core::panicking::panic"
+        ));
+        assert!(demangles_to(
+            "_ZN71_$LT$barebones..serial..Serial$u20$as$u20$core..fmt..Write$GT$9write_str17h0123456789abcdefE",
+            "<barebones::serial::Serial as core::fmt::Write>::write_str"
+        ));
+        assert!(demangles_to("kernel_main", "kernel_main"));
+    }
+}
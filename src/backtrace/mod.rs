@@ -0,0 +1,142 @@
+//! Stack backtraces, printed to serial.
+//!
+//! The kernel is built with frame pointers: each frame starts with the
+//! caller's RBP, followed by the return address. Addresses are resolved with
+//! the symbol table of the kernel ELF, which the bootloader hands us.
+
+mod demangle;
+
+use core::slice;
+
+use spin::Once;
+
+use crate::boot::StivaleStruct;
+use crate::elf::Elf;
+use crate::memory::paging;
+use crate::serial_println;
+use crate::x86;
+
+pub use demangle::Demangle;
+
+/// Stop there, in case the chain loops.
+const MAX_FRAMES: usize = 64;
+
+static KERNEL: Once<Elf<'static>> = Once::new();
+
+/// Find the kernel symbol table.
+pub fn init(boot_info: &StivaleStruct) {
+    let (address, size) = match boot_info.kernel_file() {
+        Some(file) => file,
+        None => {
+            warn!("No kernel file tag, backtraces won't have symbols");
+            return;
+        }
+    };
+    let size = size.unwrap_or_else(|| unsafe { Elf::image_size(address as *const u8) } as u64);
+    let data = unsafe { slice::from_raw_parts(address as *const u8, size as usize) };
+    match Elf::parse(data) {
+        Ok(elf) => {
+            KERNEL.call_once(|| elf);
+        }
+        Err(err) => warn!("Can't parse the kernel file: {:?}", err),
+    }
+}
+
+/// The function containing `address`, and the offset of `address` in it.
+pub fn symbolize(address: u64) -> Option<(&'static str, u64)> {
+    KERNEL.r#try()?.symbolize(address)
+}
+
+/// Call `f` with each return address of the frame chain starting at `rbp`.
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        // Frames live on kernel stacks, in the higher half.
+        if rbp < paging::KERNEL_HALF_START || rbp & 7 != 0 {
+            break;
+        }
+        // The chain may be corrupted: a fault here would only be retried,
+        // hanging the panic instead of printing.
+        if paging::translate(rbp).is_none() || paging::translate(rbp + 8).is_none() {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let (next, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            break;
+        }
+        f(return_address);
+        // Callers' frames are higher up the stack.
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+fn print_frame(index: usize, address: u64) {
+    // Return addresses point after the call, which may be the start of
+    // another function.
+    match symbolize(address - 1) {
+        Some((name, offset)) => {
+            serial_println!(
+                "  {:2}: {:#018x} {}+{:#x}",
+                index,
+                address,
+                Demangle(name),
+                offset + 1
+            );
+        }
+        None => {
+            serial_println!("  {:2}: {:#018x} <unknown>", index, address);
+        }
+    }
+}
+
+/// Print the frames from `rbp` up, after the instruction at `ip`.
+pub fn print_from(ip: u64, rbp: u64) {
+    serial_println!("Backtrace:");
+    // `ip` is the faulting instruction itself, not a return address.
+    print_frame(0, ip + 1);
+    let mut index = 1;
+    walk(rbp, |address| {
+        print_frame(index, address);
+        index += 1;
+    });
+}
+
+/// Print the backtrace of the caller.
+#[inline(never)]
+pub fn print() {
+    serial_println!("Backtrace:");
+    let mut index = 0;
+    walk(x86::frame_pointer(), |address| {
+        print_frame(index, address);
+        index += 1;
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn walks_own_stack() {
+        let mut frames = 0;
+        let mut found = false;
+        walk(x86::frame_pointer(), |address| {
+            frames += 1;
+            if let Some((name, _)) = symbolize(address - 1) {
+                found |= name.contains("test_runner");
+            }
+        });
+        assert!(frames >= 2);
+        assert!(found, "test_runner isn't in the backtrace");
+    }
+
+    #[test_case]
+    fn stops_at_unmapped_frame() {
+        let mut frames = 0;
+        walk(0xffff_fd7f_ffff_f000, |_| frames += 1);
+        assert_eq!(frames, 0);
+    }
+}
@@ -2,18 +2,21 @@
 //! https://github.com/stivale/stivale/blob/master/STIVALE2.md
 //! https://github.com/stivale/stivale2-barebones
 
+use core::ffi::{c_char, CStr};
 use core::mem::MaybeUninit;
-use core::{mem, ptr};
+use core::{mem, ptr, slice};
 
 use crate::kernel_main;
 
 #[repr(C, align(0x1000))]
 struct Align<T>(T);
 
+/// Only used until `kernel_main` switches to a stack with a guard page.
 const STACK_SIZE: usize = 0x1000 * 8;
 static STACK: Align<[u8; STACK_SIZE]> = Align([0; STACK_SIZE]);
 
 #[derive(Debug)]
+#[repr(C)]
 pub struct Tag {
     pub identifier: u64,
     pub next: *const Tag,
@@ -21,6 +24,13 @@ pub struct Tag {
 
 impl Tag {
     pub const MEMORY_MAP: u64 = 0x2187f79e8612de07;
+    pub const RSDP: u64 = 0x9e1786930a375e78;
+    pub const HHDM: u64 = 0xb0ed257db18cb58f;
+    pub const EPOCH: u64 = 0x566a7bed888e1407;
+    pub const KERNEL_FILE: u64 = 0xe599d90c2975584a;
+    pub const KERNEL_FILE_V2: u64 = 0x37c13018a02c6ea2;
+    pub const CMDLINE: u64 = 0xe5e76a1b4597a781;
+    pub const MODULES: u64 = 0x4b6fe466aade04ce;
 }
 
 unsafe impl Send for Tag {}
@@ -62,6 +72,56 @@ impl StivaleStruct {
         None
     }
 
+    /// Read the first field of a tag made of a single u64.
+    fn tag_value(&self, identifier: u64) -> Option<u64> {
+        self.get_tag(identifier)
+            .map(|tag| unsafe { *((tag as *const u8).add(mem::size_of::<Tag>()) as *const u64) })
+    }
+
+    /// Virtual address of the ACPI RSDP structure.
+    pub fn rsdp(&self) -> Option<u64> {
+        self.tag_value(Tag::RSDP)
+    }
+
+    /// Virtual address where the higher half direct map of physical memory
+    /// starts.
+    pub fn hhdm(&self) -> Option<u64> {
+        self.tag_value(Tag::HHDM)
+    }
+
+    /// UNIX time at boot, as read by the bootloader.
+    pub fn epoch(&self) -> Option<u64> {
+        self.tag_value(Tag::EPOCH)
+    }
+
+    /// The kernel ELF file as loaded by the bootloader: its address, and
+    /// its size if the bootloader told us.
+    pub fn kernel_file(&self) -> Option<(u64, Option<u64>)> {
+        if let Some(tag) = self.get_tag(Tag::KERNEL_FILE_V2) {
+            let fields = unsafe { (tag as *const u8).add(mem::size_of::<Tag>()) as *const u64 };
+            return Some(unsafe { (*fields, Some(*fields.add(1))) });
+        }
+        self.tag_value(Tag::KERNEL_FILE)
+            .map(|address| (address, None))
+    }
+
+    /// The kernel command line, as set in the bootloader configuration.
+    pub fn cmdline(&self) -> Option<&'static str> {
+        let ptr = self.tag_value(Tag::CMDLINE)? as *const c_char;
+        unsafe { CStr::from_ptr(ptr) }.to_str().ok()
+    }
+
+    /// Files loaded along with the kernel, see `MODULE_PATH` in limine.cfg.
+    pub fn modules(&self) -> &'static [Module] {
+        match self.get_tag(Tag::MODULES) {
+            Some(tag) => unsafe {
+                let count = (tag as *const u8).add(mem::size_of::<Tag>()) as *const u64;
+                slice::from_raw_parts(count.add(1) as *const Module, *count as usize)
+            },
+            None => &[],
+        }
+    }
+
     pub fn memmap(&self) -> &'static mut MemmapStructTag {
         unsafe {
             let ptr = self.get_tag(Tag::MEMORY_MAP).unwrap() as *mut usize;
@@ -71,6 +131,28 @@ impl StivaleStruct {
     }
 }
 
+#[repr(C)]
+pub struct Module {
+    begin: u64,
+    end: u64,
+    string: [u8; 128],
+}
+
+impl Module {
+    /// Contents of the module, in memory the bootloader reserved for it.
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self.begin as *const u8, (self.end - self.begin) as usize) }
+    }
+
+    /// The string given with the module in limine.cfg.
+    pub fn name(&self) -> &str {
+        CStr::from_bytes_until_nul(&self.string)
+            .ok()
+            .and_then(|name| name.to_str().ok())
+            .unwrap_or("")
+    }
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct MemmapStructTag {
@@ -92,16 +174,16 @@ impl MemmapStructTag {
 #[repr(C)]
 pub struct MemmapEntry {
     /// Physical address of base of the memory section
-    base: u64,
+    pub base: u64,
     /// Length of the section
-    length: u64,
-    mm_type: MemmapType,
+    pub length: u64,
+    pub mm_type: MemmapType,
     unused: MaybeUninit<u32>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
-enum MemmapType {
+pub enum MemmapType {
     Usable = 1,
     Reserved = 2,
     AcpiReclaimable = 3,
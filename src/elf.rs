@@ -0,0 +1,249 @@
+//! ELF64 parsing.
+//! https://refspecs.linuxfoundation.org/elf/gabi4+/contents.html
+//!
+//! Works on an ELF image in memory, without copying: structures are read
+//! unaligned, and every offset is bounds checked.
+
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use core::str;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const MACHINE_X86_64: u16 = 62;
+
+pub const ET_EXEC: u16 = 2;
+pub const SHT_SYMTAB: u32 = 2;
+pub const STT_FUNC: u8 = 2;
+
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// A structure extends past the end of the image.
+    Truncated,
+    BadMagic,
+    /// Not a 64-bit, little endian ELF.
+    UnsupportedClass,
+    /// Not for x86_64.
+    WrongMachine(u16),
+    /// Not a statically linked executable.
+    NotExecutable,
+    /// A loadable segment is malformed, or outside of user space.
+    BadSegment,
+    /// The entry point isn't in an executable segment.
+    BadEntry,
+    /// The arguments and environment don't fit on the initial stack.
+    ArgumentsTooLarge,
+    /// No memory left to load the program.
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Header {
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SectionHeader {
+    pub name: u32,
+    pub section_type: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub addralign: u64,
+    pub entsize: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Symbol {
+    pub name: u32,
+    pub info: u8,
+    pub other: u8,
+    pub shndx: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl Symbol {
+    pub fn symbol_type(&self) -> u8 {
+        self.info & 0xf
+    }
+}
+
+/// Read a `T` at `offset` in `data`.
+fn read<T: Copy>(data: &[u8], offset: u64) -> Result<T, ElfError> {
+    let offset = offset as usize;
+    match offset.checked_add(size_of::<T>()) {
+        Some(end) if end <= data.len() => {
+            Ok(unsafe { read_unaligned(data.as_ptr().add(offset) as *const T) })
+        }
+        _ => Err(ElfError::Truncated),
+    }
+}
+
+/// A parsed ELF image.
+#[derive(Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub header: Header,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let header: Header = read(data, 0)?;
+        if header.ident[..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != CLASS_64 || header.ident[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if header.machine != MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine(header.machine));
+        }
+        Ok(Self { data, header })
+    }
+
+    /// Size of the image, as far as its headers tell, when we only know
+    /// where it starts.
+    ///
+    /// # Safety
+    /// `start` must point to a mapped ELF image.
+    pub unsafe fn image_size(start: *const u8) -> usize {
+        let header = read_unaligned(start as *const Header);
+        let sections = header.shoff + header.shnum as u64 * header.shentsize as u64;
+        let segments = header.phoff + header.phnum as u64 * header.phentsize as u64;
+        sections.max(segments) as usize
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn section(&self, index: usize) -> Result<SectionHeader, ElfError> {
+        let offset = self.header.shoff + (index * self.header.shentsize as usize) as u64;
+        read(self.data, offset)
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = SectionHeader> + 'a {
+        let elf = *self;
+        (0..self.header.shnum as usize).filter_map(move |i| elf.section(i).ok())
+    }
+
+    /// The bytes of `section` in the file.
+    pub fn section_data(&self, section: &SectionHeader) -> Result<&'a [u8], ElfError> {
+        let start = section.offset as usize;
+        let end = start
+            .checked_add(section.size as usize)
+            .ok_or(ElfError::Truncated)?;
+        self.data.get(start..end).ok_or(ElfError::Truncated)
+    }
+
+    pub fn program_header(&self, index: usize) -> Result<ProgramHeader, ElfError> {
+        let offset = self.header.phoff + (index * self.header.phentsize as usize) as u64;
+        read(self.data, offset)
+    }
+
+    /// Every program header. Unlike `sections`, a truncated header is an
+    /// error: a program can't be loaded without all of them.
+    pub fn program_headers(&self) -> impl Iterator<Item = Result<ProgramHeader, ElfError>> + 'a {
+        let elf = *self;
+        (0..self.header.phnum as usize).map(move |i| elf.program_header(i))
+    }
+
+    /// The bytes of `segment` in the file.
+    pub fn segment_data(&self, segment: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        let start = segment.offset as usize;
+        let end = start
+            .checked_add(segment.filesz as usize)
+            .ok_or(ElfError::Truncated)?;
+        self.data.get(start..end).ok_or(ElfError::Truncated)
+    }
+
+    /// Null terminated string at `offset` in string table `strtab`.
+    fn string(&self, strtab: &SectionHeader, offset: u32) -> Option<&'a str> {
+        let table = self.section_data(strtab).ok()?;
+        let bytes = table.get(offset as usize..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        str::from_utf8(&bytes[..len]).ok()
+    }
+
+    /// Every symbol of the symbol table, with its name.
+    pub fn symbols(&self) -> impl Iterator<Item = (Symbol, &'a str)> + 'a {
+        let elf = *self;
+        let symtab = self.sections().find(|s| s.section_type == SHT_SYMTAB);
+        let table = symtab.and_then(|symtab| {
+            let strtab = elf.section(symtab.link as usize).ok()?;
+            Some((symtab, strtab))
+        });
+        let count = table.map_or(0, |(symtab, _)| symtab.size / size_of::<Symbol>() as u64);
+        (0..count).filter_map(move |i| {
+            let (symtab, strtab) = table?;
+            let symbol: Symbol =
+                read(elf.data, symtab.offset + i * size_of::<Symbol>() as u64).ok()?;
+            Some((symbol, elf.string(&strtab, symbol.name)?))
+        })
+    }
+
+    /// The function containing `address`, and the offset of `address` in it.
+    pub fn symbolize(&self, address: u64) -> Option<(&'a str, u64)> {
+        self.symbols()
+            .find(|(symbol, _)| {
+                symbol.symbol_type() == STT_FUNC
+                    && symbol.value <= address
+                    && address < symbol.value + symbol.size.max(1)
+            })
+            .map(|(symbol, name)| (name, address - symbol.value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn rejects_bad_headers() {
+        assert_eq!(Elf::parse(&[0x7f, b'E']).err(), Some(ElfError::Truncated));
+        let mut data = [0u8; size_of::<Header>()];
+        assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadMagic));
+        data[..4].copy_from_slice(&MAGIC);
+        data[4] = 1;
+        assert_eq!(Elf::parse(&data).err(), Some(ElfError::UnsupportedClass));
+    }
+}
@@ -0,0 +1,104 @@
+//! Local APIC, in xAPIC (MMIO) mode.
+//! Each CPU has its own, at the same physical address.
+//! https://wiki.osdev.org/APIC
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::interrupts::{self, ExceptionStackFrame};
+use crate::memory::phys_to_virt;
+use crate::x86::Msr;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Register offsets
+pub const ID: usize = 0x020;
+pub const EOI: usize = 0x0b0;
+pub const SPURIOUS: usize = 0x0f0;
+pub const ICR_LOW: usize = 0x300;
+pub const ICR_HIGH: usize = 0x310;
+pub const LVT_TIMER: usize = 0x320;
+pub const TIMER_INITIAL_COUNT: usize = 0x380;
+pub const TIMER_CURRENT_COUNT: usize = 0x390;
+pub const TIMER_DIVIDE: usize = 0x3e0;
+
+/// Spurious-Interrupt Vector Register: APIC Software Enable
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+
+/// LVT entries: the interrupt is masked
+pub const LVT_MASKED: u32 = 1 << 16;
+
+// Interrupt Command Register fields
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// How long to wait for an IPI to be sent.
+const IPI_TIMEOUT: usize = 100_000;
+
+/// Vector for spurious interrupts. Its low 4 bits must be set on old CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Virtual address of the local APIC registers, 0 until `init`.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+fn base() -> u64 {
+    BASE.load(Ordering::Relaxed)
+}
+
+pub fn read(register: usize) -> u32 {
+    unsafe { read_volatile((base() as usize + register) as *const u32) }
+}
+
+pub fn write(register: usize, value: u32) {
+    unsafe { write_volatile((base() as usize + register) as *mut u32, value) }
+}
+
+/// Whether `init` ran.
+pub fn is_enabled() -> bool {
+    base() != 0
+}
+
+/// APIC ID of the current CPU.
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Signal the end of an interrupt delivered by the local APIC.
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+/// Send an NMI to every other CPU. NMIs get through even with interrupts
+/// disabled. Returns false if the IPI wasn't sent in time.
+pub fn send_nmi_to_others() -> bool {
+    write(ICR_HIGH, 0);
+    write(ICR_LOW, ICR_ALL_EXCLUDING_SELF | ICR_DELIVERY_NMI);
+    for _ in 0..IPI_TIMEOUT {
+        if read(ICR_LOW) & ICR_SEND_PENDING == 0 {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+extern "x86-interrupt" fn spurious_handler(_frame: ExceptionStackFrame) {
+    // Spurious interrupts must not be acknowledged.
+}
+
+/// Enable the local APIC of the current CPU.
+/// Legacy IRQs keep going through the PIC.
+pub fn init() {
+    let apic_base = unsafe { Msr::APIC_BASE.read() };
+    unsafe { Msr::APIC_BASE.write(apic_base | APIC_BASE_ENABLE) };
+    BASE.store(
+        phys_to_virt(apic_base & APIC_BASE_ADDRESS_MASK),
+        Ordering::Relaxed,
+    );
+
+    interrupts::set_handler(SPURIOUS_VECTOR, spurious_handler);
+    write(SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+    debug!("Local APIC {} enabled", id());
+}
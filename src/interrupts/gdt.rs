@@ -3,12 +3,15 @@
 //! Its main use is for privilege level switching and the TSS.  
 //! Because it's a set-once structure, this module is very infexible.
 
-use core::arch::asm;
 use core::mem::{size_of, MaybeUninit};
 
 use bitflags::bitflags;
 
-use crate::interrupts::{DescriptorTableRegister, KERNEL_CS, KERNEL_DS, TSS_SELECTOR};
+use crate::interrupts::{KERNEL_CS, KERNEL_DS, TSS_SELECTOR};
+#[cfg(test)]
+use crate::interrupts::{USER_CS, USER_DS};
+use crate::memory::stack::Stack;
+use crate::x86::tables::{self, DescriptorTableRegister};
 
 bitflags! {
     struct SegmentUpperFlags: u8 {
@@ -40,6 +43,10 @@ bitflags! {
          | Self::EXECUTE.bits | Self::ACCESSIBLE.bits;
         const KERNEL_DATA = Self::PRESENT.bits | Self::DPL_0.bits | Self::USER_DESCRIPTOR.bits
          | Self::ACCESSIBLE.bits;
+        const USER_CODE = Self::PRESENT.bits | Self::DPL_3.bits | Self::USER_DESCRIPTOR.bits
+         | Self::EXECUTE.bits | Self::ACCESSIBLE.bits;
+        const USER_DATA = Self::PRESENT.bits | Self::DPL_3.bits | Self::USER_DESCRIPTOR.bits
+         | Self::ACCESSIBLE.bits;
         /// See Table 4.6,  System-Segment Descriptor Types—Long Mode AMD64
         const TSS_SEGMENT = Self::PRESENT.bits | 0b1001 | Self::SYSTEM_DESCRIPTOR.bits ;
     }
@@ -147,7 +154,7 @@ impl Tss {
 #[repr(C)]
 #[derive(Debug)]
 struct Gdt {
-    user_segments: [UserSegmentDescriptor; 3],
+    user_segments: [UserSegmentDescriptor; 5],
     tss: SystemSegmentDescriptor,
 }
 
@@ -156,81 +163,106 @@ static mut GDT: Gdt = Gdt {
         UserSegmentDescriptor::NULL,
         UserSegmentDescriptor::new(SegmentUpperFlags::LONG_MODE, SegmentLowerFlags::KERNEL_CODE),
         UserSegmentDescriptor::new(SegmentUpperFlags::LONG_MODE, SegmentLowerFlags::KERNEL_DATA),
+        // SYSRET takes user data then user code, right after kernel data.
+        UserSegmentDescriptor::new(SegmentUpperFlags::LONG_MODE, SegmentLowerFlags::USER_DATA),
+        UserSegmentDescriptor::new(SegmentUpperFlags::LONG_MODE, SegmentLowerFlags::USER_CODE),
     ],
     tss: SystemSegmentDescriptor::tss(),
 };
 
-#[repr(C, align(8))]
-struct Align8<T>(T);
+/// Interrupt Stack Table slots: interrupts that must not run on the
+/// interrupted stack, which may be overflowed or in the middle of a switch.
+/// Each one gets its own stack, so they can nest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IstSlot {
+    DoubleFault = 0,
+    Nmi = 1,
+    MachineCheck = 2,
+    Debug = 3,
+}
+
+impl IstSlot {
+    pub const ALL: [IstSlot; 4] = [
+        IstSlot::DoubleFault,
+        IstSlot::Nmi,
+        IstSlot::MachineCheck,
+        IstSlot::Debug,
+    ];
+
+    /// Index in `Tss::ist_list`.
+    pub const fn index(self) -> usize {
+        self as usize
+    }
 
-const DOUBLE_FAULT_IST: usize = 0;
+    /// Value of the IST field of IDT entries using this slot: 0 means no
+    /// stack switch, so it's one based.
+    pub const fn ist_field(self) -> u8 {
+        self as u8 + 1
+    }
+}
+
+/// Enough for the panic handler, which prints backtraces.
+const IST_STACK_PAGES: usize = 4;
 
 static mut TSS: Tss = Tss::new();
 
+/// Give `slot` a stack of its own, with a guard page.
+fn allocate_ist_stack(slot: IstSlot) {
+    let stack = Stack::allocate(IST_STACK_PAGES).expect("No memory for IST stacks");
+    // Never freed.
+    unsafe { TSS.ist_list[slot.index()] = stack.top() as usize };
+}
+
+/// Stack the CPU switches to when an interrupt or trap leaves ring 3,
+/// until threads have their own.
+const KERNEL_STACK_PAGES: usize = 4;
+
+/// Set the stack the CPU switches to when entering the kernel from ring 3.
+pub fn set_kernel_stack(top: u64) {
+    unsafe { TSS.rsp_list[0] = top as usize };
+}
+
+pub fn kernel_stack() -> u64 {
+    unsafe { TSS.rsp_list[0] as u64 }
+}
+
+/// Requires the frame allocator, for the IST stacks.
 pub fn load() {
-    let double_fault_stack = Align8::<[u8; 4096]>([0; 4096]);
+    for slot in IstSlot::ALL {
+        allocate_ist_stack(slot);
+    }
+    let stack = Stack::allocate(KERNEL_STACK_PAGES).expect("No memory for the ring 0 stack");
+    set_kernel_stack(stack.top());
 
     unsafe {
-        TSS.ist_list[DOUBLE_FAULT_IST] =
-            double_fault_stack.0.as_ptr_range().end as *const u8 as usize;
-
         // Set the TSS entry in the GDT.
         let tss_ref = &mut GDT.tss;
         tss_ref.set_base(&TSS).set_limit(size_of::<Tss>() as u16);
 
         // Flush the GDT, update CS and other segments registers.
-        //
-        // Note that we can't update CS directly, since it would be a JMP:
-        // instructions are fetched from CS:IP.
-        // https://stackoverflow.com/questions/52490438/why-cant-mov-set-cs-the-code-segment-register-even-though-it-can-set-others
-        // There are no long jumps in long mode, so we'll use the far return instruction,
-        // which pops IP and CS off the stack.
         let register_format = DescriptorTableRegister {
             limit: (size_of::<Gdt>() - 1) as u16,
             base: &GDT as *const Gdt as *const u64,
         };
-
-        asm!(
-            r#"
-lgdt [{gdt}]
-lea {ip}, [rip + 0f]
-push {cs}    
-push {ip}
-retfq
-0:
-mov ax, {ds}
-mov ds, ax
-mov es, ax
-mov fs, ax
-mov gs, ax
-mov ss, ax
-        "#,
-            gdt = in(reg) &register_format,
-            cs = const KERNEL_CS,
-            ip = lateout(reg) _,
-            ds = const KERNEL_DS,
-            options(readonly, nostack, preserves_flags)
-        );
+        tables::lgdt(&register_format);
+        tables::set_cs(KERNEL_CS);
+        tables::load_data_segments(KERNEL_DS);
 
         // Load the TSS.
-        asm!("ltr {:x}", in(reg) TSS_SELECTOR, options(readonly, nostack, preserves_flags));
+        tables::ltr(TSS_SELECTOR);
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::interrupts::idt::IDT;
+    use crate::interrupts::idt::{self, IDT};
 
     use super::*;
 
     fn get_gdt() -> &'static Gdt {
         // Assumes the Gdt struct is correct
-        unsafe {
-            let mut gdt: MaybeUninit<DescriptorTableRegister> = MaybeUninit::uninit();
-            asm!("sgdt [{}]", in(reg) &mut gdt);
-            let gdt = gdt.assume_init().base as *const Gdt;
-            &*gdt
-        }
+        unsafe { &*(tables::sgdt().base as *const Gdt) }
     }
 
     fn get_tss() -> &'static Tss {
@@ -263,6 +295,24 @@ mod test {
             .contains(SegmentLowerFlags::KERNEL_DATA));
     }
 
+    #[test_case]
+    fn user_selectors_match() {
+        let gdt = get_gdt();
+        let cs_entry = &gdt.user_segments[(USER_CS >> 3) as usize];
+        assert!(cs_entry.lower_flags.contains(SegmentLowerFlags::USER_CODE));
+        let ds_entry = &gdt.user_segments[(USER_DS >> 3) as usize];
+        assert!(ds_entry.lower_flags.contains(SegmentLowerFlags::USER_DATA));
+        assert_eq!(USER_CS & 3, 3);
+        assert_eq!(USER_DS & 3, 3);
+
+        // SYSRET loads SS from STAR + 8 and CS from STAR + 16.
+        assert_eq!(USER_DS & !3, KERNEL_DS + 8);
+        assert_eq!(USER_CS & !3, KERNEL_DS + 16);
+
+        let rsp0 = get_tss().rsp_list[0];
+        assert_ne!(rsp0, 0);
+    }
+
     #[test_case]
     fn tss_selector_matches() {
         unsafe {
@@ -289,9 +339,31 @@ mod test {
     fn double_fault_stack_is_setup() {
         let tss = get_tss();
         unsafe {
-            let ist = IDT[14].assume_init().ist as usize;
-            let ist_contents = tss.ist_list[ist];
+            let ist = IDT[8].assume_init().ist;
+            assert_eq!(ist, IstSlot::DoubleFault.ist_field());
+            let ist_contents = tss.ist_list[ist as usize - 1];
             assert_ne!(ist_contents, 0);
         }
     }
+
+    #[test_case]
+    fn ist_slots_have_their_own_stacks() {
+        let stacks = get_tss().ist_list;
+        for (i, slot) in IstSlot::ALL.iter().enumerate() {
+            let top = stacks[slot.index()];
+            assert_ne!(top, 0);
+            for other in &IstSlot::ALL[i + 1..] {
+                assert_ne!(top, stacks[other.index()]);
+            }
+        }
+        for (vector, slot) in [
+            (idt::DEBUG, IstSlot::Debug),
+            (idt::NMI, IstSlot::Nmi),
+            (idt::DOUBLE_FAULT, IstSlot::DoubleFault),
+            (idt::MACHINE_CHECK, IstSlot::MachineCheck),
+        ] {
+            let ist = unsafe { IDT[vector as usize].assume_init().ist };
+            assert_eq!(ist, slot.ist_field());
+        }
+    }
 }
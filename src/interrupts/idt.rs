@@ -1,9 +1,14 @@
-use core::arch::asm;
 use core::mem::{size_of, MaybeUninit};
 
 use bitflags::bitflags;
 
-use crate::interrupts::{DescriptorTableRegister, KERNEL_CS};
+use crate::backtrace;
+use crate::interrupts::{IstSlot, KERNEL_CS};
+use crate::memory::stack;
+use crate::userspace;
+use crate::x86;
+use crate::x86::registers::Cr2;
+use crate::x86::tables::{self, DescriptorTableRegister};
 
 bitflags! {
     struct GateFlags: u8 {
@@ -28,18 +33,25 @@ pub struct IdtDescriptor {
 }
 
 impl IdtDescriptor {
-    pub fn new(handler: usize, ist: u8) -> Self {
+    /// Gate to `handler`, switching to the stack of `ist` if there is one.
+    pub fn new(handler: usize, ist: Option<IstSlot>) -> Self {
         let addr = handler;
         Self {
             offset_15_0: addr as u16,
             offset_31_16: (addr >> 16) as u16,
             offset_63_32: (addr >> 32) as u32,
             segment_selector: KERNEL_CS,
-            ist,
+            ist: ist.map_or(0, IstSlot::ist_field),
             attributes: GateFlags::INTERRUPT_GATE | GateFlags::PRESENT | GateFlags::RING_0,
             _ignored: MaybeUninit::uninit(),
         }
     }
+
+    /// Let ring 3 raise this vector with `int`.
+    fn user_callable(mut self) -> Self {
+        self.attributes = GateFlags::INTERRUPT_GATE | GateFlags::PRESENT | GateFlags::RING_3;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -52,31 +64,145 @@ pub struct ExceptionStackFrame {
     pub ss: u64,
 }
 
-extern "x86-interrupt" fn handler(_frame: ExceptionStackFrame) {
-    log!("got handled!");
+// Exceptions running on IST stacks
+pub const DEBUG: u8 = 1;
+pub const NMI: u8 = 2;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const MACHINE_CHECK: u8 = 18;
+
+extern "x86-interrupt" fn debug_handler(frame: ExceptionStackFrame) {
+    debug!("Debug exception at {:#x}", frame.ip);
+}
+
+/// Replaced by the panic module's handler.
+extern "x86-interrupt" fn nmi_handler(_frame: ExceptionStackFrame) {
+    warn!("Unexpected NMI");
+}
+
+extern "x86-interrupt" fn machine_check_handler(frame: ExceptionStackFrame) {
+    // Not restartable: the state of the interrupted code is lost.
+    panic!("Machine check at {:#x}", frame.ip);
+}
+
+// Exceptions user code can raise
+pub const DIVIDE_ERROR: u8 = 0;
+pub const INVALID_OPCODE: u8 = 6;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const X87_FLOATING_POINT: u8 = 16;
+pub const ALIGNMENT_CHECK: u8 = 17;
+pub const SIMD_FLOATING_POINT: u8 = 19;
+
+/// If `frame` is from ring 3, end the user program with the exit code of
+/// exception `vector`. Returns if the kernel itself faulted.
+fn end_user_program(vector: u8, frame: &ExceptionStackFrame, error_code: u64) {
+    if frame.cs & 3 != 3 {
+        return;
+    }
+    warn!(
+        "User program killed by exception {} at {:#x}, error code {:#x}",
+        vector, frame.ip, error_code
+    );
+    // Retrying the instruction would only fault again.
+    unsafe { userspace::return_to_kernel(userspace::fault_exit_code(vector)) }
+}
+
+extern "x86-interrupt" fn divide_error_handler(frame: ExceptionStackFrame) {
+    end_user_program(DIVIDE_ERROR, &frame, 0);
+    panic!("Division by zero at {:#x}", frame.ip);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(frame: ExceptionStackFrame) {
+    end_user_program(INVALID_OPCODE, &frame, 0);
+    panic!("Invalid opcode at {:#x}", frame.ip);
 }
 
-extern "x86-interrupt" fn seg_handler(_frame: ExceptionStackFrame) {
-    log!("got seg");
+/// Print the backtrace of the kernel code that faulted, which can't go on.
+/// Always inlined, so the frame pointer is the handler's.
+#[inline(always)]
+fn print_fault_backtrace(frame: &ExceptionStackFrame) {
+    // The handler's prologue pushed the interrupted code's RBP.
+    let rbp = unsafe { *(x86::frame_pointer() as *const u64) };
+    backtrace::print_from(frame.ip, rbp);
 }
 
-extern "x86-interrupt" fn stack_handler(_frame: ExceptionStackFrame) {
-    log!("got seg");
+extern "x86-interrupt" fn seg_handler(frame: ExceptionStackFrame, error_code: u64) {
+    end_user_program(SEGMENT_NOT_PRESENT, &frame, error_code);
+    print_fault_backtrace(&frame);
+    panic!(
+        "Segment not present at {:#x}, error code {:#x}: {:#x?}",
+        frame.ip, error_code, frame
+    );
 }
 
-extern "x86-interrupt" fn page_fault_handler(_frame: ExceptionStackFrame, _error_code: u64) {
-    log!("got pf");
+extern "x86-interrupt" fn stack_handler(frame: ExceptionStackFrame, error_code: u64) {
+    end_user_program(STACK_FAULT, &frame, error_code);
+    print_fault_backtrace(&frame);
+    panic!(
+        "Stack segment fault at {:#x}, error code {:#x}: {:#x?}",
+        frame.ip, error_code, frame
+    );
+}
+
+extern "x86-interrupt" fn page_fault_handler(frame: ExceptionStackFrame, error_code: u64) {
+    let address = Cr2::read();
+    if frame.cs & 3 == 3 {
+        warn!("User page fault at {:#x}", address);
+    }
+    end_user_program(PAGE_FAULT, &frame, error_code);
+    print_fault_backtrace(&frame);
+    panic!(
+        "Page fault at {:#x} accessing {:#x}, error code {:#x}: {:#x?}",
+        frame.ip, address, error_code, frame
+    );
 }
 
 extern "x86-interrupt" fn gpf_handler(frame: ExceptionStackFrame, error_code: u64) {
-    log!("frame: {:#x?}, error_code: {:#x?}", frame, error_code);
+    end_user_program(GENERAL_PROTECTION, &frame, error_code);
+    print_fault_backtrace(&frame);
+    panic!(
+        "General protection fault at {:#x}, error code {:#x}: {:#x?}",
+        frame.ip, error_code, frame
+    );
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(frame: ExceptionStackFrame) {
+    end_user_program(X87_FLOATING_POINT, &frame, 0);
+    panic!("x87 floating point exception at {:#x}", frame.ip);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(frame: ExceptionStackFrame, error_code: u64) {
+    end_user_program(ALIGNMENT_CHECK, &frame, error_code);
+    panic!("Alignment check at {:#x}", frame.ip);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(frame: ExceptionStackFrame) {
+    end_user_program(SIMD_FLOATING_POINT, &frame, 0);
+    panic!("SIMD floating point exception at {:#x}", frame.ip);
 }
 
 extern "x86-interrupt" fn double_fault_handler(frame: ExceptionStackFrame, error_code: u64) {
     error!("Exception frame: {:#x?}. Error code: {}", frame, error_code);
+    print_fault_backtrace(&frame);
+    // A page fault on a guard page can't be delivered on the same stack.
+    let address = Cr2::read();
+    if stack::is_guard_page(address) {
+        panic!(
+            "Double fault: kernel stack overflow (RSP {:#x}, fault at {:#x})",
+            frame.sp, address
+        );
+    }
     panic!("Double fault");
 }
 
+/// Signature of handlers for interrupts that don't push an error code.
+pub type InterruptHandler = extern "x86-interrupt" fn(ExceptionStackFrame);
+
+/// Signature of handlers for exceptions that push an error code.
+type ErrorCodeHandler = extern "x86-interrupt" fn(ExceptionStackFrame, u64);
+
 const NB_ENTRIES: usize = 256;
 
 pub type IdtType = [MaybeUninit<IdtDescriptor>; NB_ENTRIES];
@@ -85,18 +211,79 @@ pub static mut IDT: IdtType = unsafe { MaybeUninit::uninit().assume_init() };
 
 pub fn load() {
     unsafe {
-        IDT[8] = MaybeUninit::new(IdtDescriptor::new(double_fault_handler as usize, 1));
-        IDT[11] = MaybeUninit::new(IdtDescriptor::new(seg_handler as usize, 0));
-        IDT[12] = MaybeUninit::new(IdtDescriptor::new(stack_handler as usize, 0));
-        IDT[13] = MaybeUninit::new(IdtDescriptor::new(gpf_handler as usize, 0));
-        IDT[14] = MaybeUninit::new(IdtDescriptor::new(page_fault_handler as usize, 0));
-        IDT[33] = MaybeUninit::new(IdtDescriptor::new(handler as usize, 0));
+        IDT[DEBUG as usize] = MaybeUninit::new(IdtDescriptor::new(
+            debug_handler as InterruptHandler as usize,
+            Some(IstSlot::Debug),
+        ));
+        IDT[NMI as usize] = MaybeUninit::new(IdtDescriptor::new(
+            nmi_handler as InterruptHandler as usize,
+            Some(IstSlot::Nmi),
+        ));
+        IDT[DOUBLE_FAULT as usize] = MaybeUninit::new(IdtDescriptor::new(
+            double_fault_handler as ErrorCodeHandler as usize,
+            Some(IstSlot::DoubleFault),
+        ));
+        let handlers: [(u8, InterruptHandler); 4] = [
+            (DIVIDE_ERROR, divide_error_handler),
+            (INVALID_OPCODE, invalid_opcode_handler),
+            (X87_FLOATING_POINT, x87_floating_point_handler),
+            (SIMD_FLOATING_POINT, simd_floating_point_handler),
+        ];
+        for (vector, handler) in handlers {
+            IDT[vector as usize] = MaybeUninit::new(IdtDescriptor::new(handler as usize, None));
+        }
+        let handlers: [(u8, ErrorCodeHandler); 5] = [
+            (SEGMENT_NOT_PRESENT, seg_handler),
+            (STACK_FAULT, stack_handler),
+            (GENERAL_PROTECTION, gpf_handler),
+            (PAGE_FAULT, page_fault_handler),
+            (ALIGNMENT_CHECK, alignment_check_handler),
+        ];
+        for (vector, handler) in handlers {
+            IDT[vector as usize] = MaybeUninit::new(IdtDescriptor::new(handler as usize, None));
+        }
+        IDT[MACHINE_CHECK as usize] = MaybeUninit::new(IdtDescriptor::new(
+            machine_check_handler as InterruptHandler as usize,
+            Some(IstSlot::MachineCheck),
+        ));
 
         let register_format = DescriptorTableRegister {
             limit: (size_of::<IdtType>() - 1) as u16,
             base: IDT.as_ptr() as *const u64,
         };
 
-        asm!("lidt [{}]", in(reg) &register_format, options(readonly, nostack, preserves_flags));
+        tables::lidt(&register_format);
+    }
+}
+
+/// Install `handler` for `vector`, on the current stack.
+pub fn set_handler(vector: u8, handler: InterruptHandler) {
+    unsafe {
+        IDT[vector as usize] = MaybeUninit::new(IdtDescriptor::new(handler as usize, None));
+    }
+}
+
+/// Install `handler` for `vector`, which ring 3 may raise with `int`.
+pub fn set_user_handler(vector: u8, handler: InterruptHandler) {
+    unsafe {
+        IDT[vector as usize] =
+            MaybeUninit::new(IdtDescriptor::new(handler as usize, None).user_callable());
+    }
+}
+
+/// Install an assembly entry stub for `vector`, which ring 3 may raise with
+/// `int`.
+///
+/// # Safety
+/// `entry` must preserve the interrupted state and return with `iretq`.
+pub unsafe fn set_user_entry(vector: u8, entry: unsafe extern "C" fn()) {
+    IDT[vector as usize] =
+        MaybeUninit::new(IdtDescriptor::new(entry as usize, None).user_callable());
+}
+
+/// Install `handler` for `vector`, on the stack of `slot`.
+pub fn set_handler_on_stack(vector: u8, handler: InterruptHandler, slot: IstSlot) {
+    unsafe {
+        IDT[vector as usize] = MaybeUninit::new(IdtDescriptor::new(handler as usize, Some(slot)));
     }
 }
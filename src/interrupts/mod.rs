@@ -1,17 +1,11 @@
 //! Manage x86 interrupts.
 
-/// Format for use by LIDT and LGDT
-#[repr(C, packed(2))]
-struct DescriptorTableRegister {
-    /// Size of the DT in bytes.
-    pub limit: u16,
-    // Base address of the DT.
-    pub base: *const u64,
-}
+use crate::x86;
 
 #[derive(Debug)]
 pub enum Ring {
     Ring0 = 0,
+    Ring3 = 3,
 }
 
 // Segment selectors (4.5 AMD64 manual)
@@ -21,12 +15,52 @@ const fn new_segment(index: u16, rpl: Ring) -> SegmentSelector {
     index << 3 | (rpl as u16)
 }
 
-const KERNEL_CS: SegmentSelector = new_segment(1, Ring::Ring0);
-const KERNEL_DS: SegmentSelector = new_segment(2, Ring::Ring0);
-const TSS_SELECTOR: SegmentSelector = new_segment(3, Ring::Ring0);
+pub const KERNEL_CS: SegmentSelector = new_segment(1, Ring::Ring0);
+pub const KERNEL_DS: SegmentSelector = new_segment(2, Ring::Ring0);
+pub const USER_DS: SegmentSelector = new_segment(3, Ring::Ring3);
+pub const USER_CS: SegmentSelector = new_segment(4, Ring::Ring3);
+const TSS_SELECTOR: SegmentSelector = new_segment(5, Ring::Ring0);
 
+pub mod apic;
 mod gdt;
 mod idt;
+pub mod pic;
+
+pub use gdt::{kernel_stack, set_kernel_stack, IstSlot};
+#[cfg(test)]
+pub use idt::set_user_handler;
+pub use idt::{
+    set_handler, set_handler_on_stack, set_user_entry, ExceptionStackFrame, InterruptHandler, NMI,
+};
+
+/// Vector of the first legacy IRQ, once the PIC is remapped.
+pub const IRQ_BASE: u8 = 32;
+
+/// Route a legacy IRQ line to `handler` and unmask it.
+/// Handlers must call `end_of_interrupt` before returning.
+pub fn set_irq_handler(irq: u8, handler: InterruptHandler) {
+    idt::set_handler(IRQ_BASE + irq, handler);
+    pic::unmask(irq);
+}
+
+pub fn end_of_interrupt(irq: u8) {
+    pic::end_of_interrupt(irq);
+}
+
+extern "x86-interrupt" fn spurious_master_handler(_frame: ExceptionStackFrame) {
+    if !pic::is_spurious(7) {
+        pic::end_of_interrupt(7);
+    }
+}
+
+extern "x86-interrupt" fn spurious_slave_handler(_frame: ExceptionStackFrame) {
+    if pic::is_spurious(15) {
+        // The master did see an interrupt on the cascade line.
+        pic::end_of_interrupt(pic::CASCADE_IRQ);
+    } else {
+        pic::end_of_interrupt(15);
+    }
+}
 
 pub fn init() {
     // Segment selectors (4.5 AMD64 manual)
@@ -34,6 +68,11 @@ pub fn init() {
     gdt::load();
     debug!("Initializing IDT");
     idt::load();
+    debug!("Initializing PIC");
+    pic::init(IRQ_BASE);
+    idt::set_handler(IRQ_BASE + 7, spurious_master_handler);
+    idt::set_handler(IRQ_BASE + 15, spurious_slave_handler);
+    x86::sti();
     // unsafe {
     //     let p = 0xffff_ffff_0000_0000 as *const usize;
     //     ptr::read(p);
@@ -0,0 +1,104 @@
+//! 8259 Programmable Interrupt Controller.
+//! Two cascaded PICs deliver the 16 legacy IRQs. By default they use vectors
+//! 0x08-0x0f, which clash with CPU exceptions, so we remap them after
+//! `IRQ_BASE`.
+//! https://wiki.osdev.org/8259_PIC
+
+use crate::io::{inb, outb};
+
+const PIC1_CMD: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_CMD: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+/// Initialization, ICW4 will be sent
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+/// Read In-Service Register
+const OCW3_READ_ISR: u8 = 0x0b;
+const EOI: u8 = 0x20;
+
+/// IRQ line the slave PIC is connected to, on the master.
+pub const CASCADE_IRQ: u8 = 2;
+
+/// Give the PIC time to process a command, by writing to an unused port.
+fn io_wait() {
+    unsafe { outb(0x80, 0) };
+}
+
+/// Remap both PICs to `base..base + 16` and mask every IRQ but the cascade.
+pub fn init(base: u8) {
+    unsafe {
+        outb(PIC1_CMD, ICW1_INIT);
+        io_wait();
+        outb(PIC2_CMD, ICW1_INIT);
+        io_wait();
+        // ICW2: vector offsets
+        outb(PIC1_DATA, base);
+        io_wait();
+        outb(PIC2_DATA, base + 8);
+        io_wait();
+        // ICW3: master has a slave on IRQ2, slave has cascade identity 2
+        outb(PIC1_DATA, 1 << CASCADE_IRQ);
+        io_wait();
+        outb(PIC2_DATA, CASCADE_IRQ);
+        io_wait();
+        // ICW4
+        outb(PIC1_DATA, ICW4_8086);
+        io_wait();
+        outb(PIC2_DATA, ICW4_8086);
+        io_wait();
+
+        outb(PIC1_DATA, !(1 << CASCADE_IRQ));
+        outb(PIC2_DATA, 0xff);
+    }
+}
+
+fn data_port(irq: u8) -> (u16, u8) {
+    if irq < 8 {
+        (PIC1_DATA, irq)
+    } else {
+        (PIC2_DATA, irq - 8)
+    }
+}
+
+pub fn mask(irq: u8) {
+    let (port, line) = data_port(irq);
+    unsafe { outb(port, inb(port) | (1 << line)) };
+}
+
+pub fn unmask(irq: u8) {
+    let (port, line) = data_port(irq);
+    unsafe { outb(port, inb(port) & !(1 << line)) };
+}
+
+/// Mask every IRQ, e.g. when switching to the APIC.
+pub fn disable() {
+    unsafe {
+        outb(PIC1_DATA, 0xff);
+        outb(PIC2_DATA, 0xff);
+    }
+}
+
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(PIC2_CMD, EOI);
+        }
+        outb(PIC1_CMD, EOI);
+    }
+}
+
+/// IRQ 7 and 15 may be raised spuriously, in which case the In-Service
+/// Register bit is not set and no EOI must be sent to that PIC.
+pub fn is_spurious(irq: u8) -> bool {
+    let (cmd, line) = match irq {
+        7 => (PIC1_CMD, 7),
+        15 => (PIC2_CMD, 7),
+        _ => return false,
+    };
+    unsafe {
+        outb(cmd, OCW3_READ_ISR);
+        inb(cmd) & (1 << line) == 0
+    }
+}
@@ -1,27 +1,15 @@
 //! Various wrappers for IO.
+//!
+//! Shorthands for `x86::Port`, for the common byte-wide accesses.
 
-use core::arch::asm;
+use crate::x86::Port;
 
 #[inline]
 pub unsafe fn outb(port: u16, value: u8) {
-    asm!(
-       "out dx, al",
-       in("dx") port,
-       in("al") value,
-       options(preserves_flags, nomem, nostack)
-    );
+    Port::<u8>::new(port).write(value)
 }
 
 #[inline]
 pub unsafe fn inb(port: u16) -> u8 {
-    let ret: u8;
-
-    asm!(
-        "in al, dx",
-        in("dx") port,
-        out("al") ret,
-        options(preserves_flags, nomem, nostack)
-    );
-
-    ret
+    Port::<u8>::new(port).read()
 }
@@ -1,9 +1,19 @@
 //! Log data with various log levels.
 //! NIH from log crate:
+//!
+//! Records are dispatched to every attached [`Sink`] whose level threshold
+//! they meet. Each sink picks its own [`Format`], so the serial port can get
+//! ANSI colors while the VGA screen gets plain text.
 
 use core::fmt::{self, write, Write};
+use core::str;
 
+use spin::Mutex;
+
+use crate::io::outb;
+use crate::ring::RingBuffer;
 use crate::serial::COM1;
+use crate::x86;
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
 pub enum Level {
@@ -25,32 +35,277 @@ pub struct Record<'a> {
     pub level: Level,
 }
 
-struct Logger;
-impl Write for Logger {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
+/// How a sink wants its records to look.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Plain,
+    /// Colored level and location, for terminals.
+    Ansi,
+}
+
+/// A destination for log records.
+pub trait Sink: Sync {
+    fn write_str(&self, s: &str);
+}
+
+/// The first serial port.
+pub struct SerialSink;
+impl Sink for SerialSink {
+    fn write_str(&self, s: &str) {
         COM1.lock().write_str(s).unwrap();
+    }
+}
+
+/// The VGA text buffer, shared with `println!`.
+pub struct VgaSink;
+impl Sink for VgaSink {
+    fn write_str(&self, s: &str) {
+        crate::vga::_print(format_args!("{}", s));
+    }
+}
+
+/// QEMU and Bochs debug console: every byte written to port 0xE9 is
+/// printed by the emulator (`-debugcon stdio` in QEMU).
+/// On real hardware, nothing listens on that port.
+pub struct DebugconSink;
+impl DebugconSink {
+    const PORT: u16 = 0xe9;
+}
+impl Sink for DebugconSink {
+    fn write_str(&self, s: &str) {
+        for byte in s.bytes() {
+            unsafe { outb(DebugconSink::PORT, byte) };
+        }
+    }
+}
+
+const RING_SIZE: usize = 0x4000;
+
+/// Keeps the most recent log output in memory, so it can be dumped later
+/// (e.g. on panic) even if no other sink was listening.
+pub struct RingSink {
+    buf: Mutex<RingBuffer<u8, RING_SIZE>>,
+}
+impl RingSink {
+    /// Write the buffered history to `w`, oldest first.
+    /// Invalid UTF-8, like a character cut in half when the ring wrapped
+    /// around, is replaced with U+FFFD.
+    pub fn dump(&self, w: &mut impl Write) -> fmt::Result {
+        x86::without_interrupts(|| {
+            let ring = self.buf.lock();
+            let mut bytes = ring.iter();
+            let mut chunk = [0; 256];
+            let mut len = 0;
+            loop {
+                for (slot, byte) in chunk[len..].iter_mut().zip(&mut bytes) {
+                    *slot = byte;
+                    len += 1;
+                }
+                let last = len < chunk.len();
+
+                let mut rest = &chunk[..len];
+                while !rest.is_empty() {
+                    match str::from_utf8(rest) {
+                        Ok(s) => {
+                            w.write_str(s)?;
+                            rest = &[];
+                        }
+                        Err(err) => {
+                            let (valid, invalid) = rest.split_at(err.valid_up_to());
+                            w.write_str(str::from_utf8(valid).unwrap())?;
+                            match err.error_len() {
+                                // Incomplete, the rest is in the next chunk.
+                                None if !last => break,
+                                None => rest = &[],
+                                Some(n) => rest = &invalid[n..],
+                            }
+                            w.write_char(char::REPLACEMENT_CHARACTER)?;
+                        }
+                    }
+                }
+                if last {
+                    return Ok(());
+                }
+                let carry = rest.len();
+                chunk.copy_within(len - carry..len, 0);
+                len = carry;
+            }
+        })
+    }
+
+    pub fn clear(&self) {
+        x86::without_interrupts(|| self.buf.lock().clear());
+    }
+
+    /// # Safety
+    /// See `force_unlock`.
+    unsafe fn force_unlock(&self) {
+        self.buf.force_unlock();
+    }
+}
+impl Sink for RingSink {
+    fn write_str(&self, s: &str) {
+        let mut buf = self.buf.lock();
+        for byte in s.bytes() {
+            buf.push_overwrite(byte);
+        }
+    }
+}
+
+pub static SERIAL: SerialSink = SerialSink;
+pub static VGA: VgaSink = VgaSink;
+pub static DEBUGCON: DebugconSink = DebugconSink;
+pub static RING: RingSink = RingSink {
+    buf: Mutex::new(RingBuffer::new()),
+};
+
+#[derive(Clone, Copy)]
+struct SinkEntry {
+    sink: &'static dyn Sink,
+    level: Level,
+    format: Format,
+}
+
+/// Handle to an attached sink, used to change or remove it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SinkId(usize);
+
+#[derive(Debug)]
+pub enum AttachError {
+    /// All `MAX_SINKS` slots are taken.
+    Full,
+}
+
+const MAX_SINKS: usize = 8;
+
+/// The serial port is attached from the start, so that anything logged
+/// before `init()` isn't lost.
+/// Only lock with interrupts disabled: exception handlers log too.
+static SINKS: Mutex<[Option<SinkEntry>; MAX_SINKS]> = Mutex::new([
+    Some(SinkEntry {
+        sink: &SERIAL,
+        level: Level::Trace,
+        format: Format::Ansi,
+    }),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+]);
+
+/// Start sending records at or above `level` to `sink`.
+pub fn attach(
+    sink: &'static dyn Sink,
+    level: Level,
+    format: Format,
+) -> Result<SinkId, AttachError> {
+    x86::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        let (i, slot) = sinks
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.is_none())
+            .ok_or(AttachError::Full)?;
+        *slot = Some(SinkEntry {
+            sink,
+            level,
+            format,
+        });
+        Ok(SinkId(i))
+    })
+}
+
+pub fn detach(id: SinkId) {
+    x86::without_interrupts(|| SINKS.lock()[id.0] = None);
+}
+
+pub fn set_level(id: SinkId, level: Level) {
+    x86::without_interrupts(|| {
+        if let Some(entry) = &mut SINKS.lock()[id.0] {
+            entry.level = level;
+        }
+    });
+}
+
+/// Attach the default sinks besides serial.
+pub fn init() {
+    attach(&VGA, Level::Info, Format::Plain).unwrap();
+    attach(&RING, Level::Trace, Format::Plain).unwrap();
+    attach(&DEBUGCON, Level::Debug, Format::Ansi).unwrap();
+}
+
+/// Release the logger locks and those of the default sinks, so a panic can
+/// still log whatever it interrupted.
+///
+/// # Safety
+/// Only for the panic path, once nothing else runs.
+pub unsafe fn force_unlock() {
+    SINKS.force_unlock();
+    RING.force_unlock();
+    crate::serial::force_unlock();
+    crate::vga::force_unlock();
+}
+
+/// Adapter to use `write!` on a sink.
+struct Writer(&'static dyn Sink);
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
         Ok(())
     }
 }
 
-pub fn _log(args: core::fmt::Arguments, record: Record) {
-    let mut logger = Logger {};
-
-    match record.level {
-        Level::Trace => logger.write_str("\x1b[1mTRACE"),
-        Level::Debug => logger.write_str("\x1b[1;36mDEBUG"),
-        Level::Info => logger.write_str("\x1b[1;34mINFO "),
-        Level::Warn => logger.write_str("\x1b[1;33mWARN "),
-        Level::Error => logger.write_str("\x1b[1;31mERROR"),
+fn write_record(
+    w: &mut Writer,
+    format: Format,
+    args: fmt::Arguments,
+    record: &Record,
+) -> fmt::Result {
+    match format {
+        Format::Ansi => {
+            match record.level {
+                Level::Trace => w.write_str("\x1b[1mTRACE"),
+                Level::Debug => w.write_str("\x1b[1;36mDEBUG"),
+                Level::Info => w.write_str("\x1b[1;34mINFO "),
+                Level::Warn => w.write_str("\x1b[1;33mWARN "),
+                Level::Error => w.write_str("\x1b[1;31mERROR"),
+            }?;
+            w.write_str("\x1b[1;39m")?;
+            write!(w, " [{}:{}] ", record.file, record.line)?;
+            w.write_str("\x1b[0m")?;
+        }
+        Format::Plain => {
+            match record.level {
+                Level::Trace => w.write_str("TRACE"),
+                Level::Debug => w.write_str("DEBUG"),
+                Level::Info => w.write_str("INFO "),
+                Level::Warn => w.write_str("WARN "),
+                Level::Error => w.write_str("ERROR"),
+            }?;
+            write!(w, " [{}:{}] ", record.file, record.line)?;
+        }
     }
-    .unwrap();
 
-    logger.write_str("\x1b[1;39m").unwrap();
-    write!(&mut logger, " [{}:{}] ", record.file, record.line).unwrap();
-    logger.write_str("\x1b[0m").unwrap();
+    write(w, args)?;
+    w.write_str("\n")
+}
+
+pub fn _log(args: core::fmt::Arguments, record: Record) {
+    // Sinks take their own locks, which handlers logging would spin on too.
+    x86::without_interrupts(|| {
+        let sinks = SINKS.lock();
 
-    write(&mut logger, args).unwrap();
-    logger.write_str("\n").unwrap();
+        for entry in sinks.iter().flatten() {
+            if record.level < entry.level {
+                continue;
+            }
+            let mut writer = Writer(entry.sink);
+            write_record(&mut writer, entry.format, args, &record).unwrap();
+        }
+    });
 }
 
 #[macro_export]
@@ -76,3 +331,65 @@ macro_rules! warn {
 macro_rules! error {
     ($($arg:tt)+) => (log_macro!($crate::logger::Level::Error, $($arg)+))
 }
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    struct CountingSink(AtomicUsize);
+    impl Sink for CountingSink {
+        fn write_str(&self, _s: &str) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    static COUNTER: CountingSink = CountingSink(AtomicUsize::new(0));
+
+    #[test_case]
+    fn sink_level_is_respected() {
+        let id = attach(&COUNTER, Level::Warn, Format::Plain).unwrap();
+        log!("filtered out");
+        assert_eq!(COUNTER.0.load(Ordering::Relaxed), 0);
+        warn!("kept");
+        assert_ne!(COUNTER.0.load(Ordering::Relaxed), 0);
+        detach(id);
+    }
+
+    /// Collects what is written, up to 512 bytes.
+    struct Collector {
+        buf: [u8; 512],
+        len: usize,
+    }
+    impl Write for Collector {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn ring_dump_keeps_utf8() {
+        static RING: RingSink = RingSink {
+            buf: Mutex::new(RingBuffer::new()),
+        };
+        let ring = &RING;
+        // The 'é' straddles the 256 byte chunks dump decodes.
+        let mut text = [b'a'; 300];
+        text[255..257].copy_from_slice("é".as_bytes());
+        ring.write_str(str::from_utf8(&text).unwrap());
+        ring.buf.lock().push_overwrite(0xff);
+
+        let mut out = Collector {
+            buf: [0; 512],
+            len: 0,
+        };
+        ring.dump(&mut out).unwrap();
+        let out = str::from_utf8(&out.buf[..out.len]).unwrap();
+        assert_eq!(out.chars().nth(255), Some('é'));
+        assert_eq!(out.chars().last(), Some(char::REPLACEMENT_CHARACTER));
+        assert_eq!(out.chars().count(), 300);
+    }
+}
@@ -12,15 +12,27 @@
 use core::panic::PanicInfo;
 
 use crate::boot::StivaleStruct;
-use crate::x86::hlt;
+use crate::memory::stack::Stack;
 
 mod boot;
+mod elf;
 mod io;
 #[macro_use]
 mod logger;
+mod acpi;
+mod backtrace;
 mod interrupts;
+mod memory;
+mod panic;
+mod ps2;
+mod qemu;
+mod random;
+mod ring;
 mod serial;
 mod test;
+mod thread;
+mod time;
+mod userspace;
 mod vga;
 mod x86;
 
@@ -28,16 +40,39 @@ mod x86;
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    error!("PANIC: {}", info);
-    println!("PANIC: {}", info);
-    loop {
-        hlt();
-    }
+    panic::handle(info)
 }
 
+/// Size of the stack `kernel_init` runs on.
+const BOOT_STACK_PAGES: usize = 8;
+
 #[no_mangle]
 extern "C" fn kernel_main(boot_info: &'static StivaleStruct) -> ! {
+    logger::init();
+    x86::cpuid::init();
+    x86::protection::init();
+    memory::init(boot_info);
+
+    // The bootloader's stack has nothing under it to catch an overflow.
+    let stack = Stack::allocate(BOOT_STACK_PAGES).expect("No memory for the boot stack");
+    unsafe { stack.switch_to(kernel_init, boot_info as *const StivaleStruct as u64) }
+}
+
+extern "C" fn kernel_init(boot_info: u64) -> ! {
+    let boot_info = unsafe { &*(boot_info as *const StivaleStruct) };
     interrupts::init();
+    panic::init(boot_info);
+    userspace::init();
+    #[cfg(feature = "simd")]
+    x86::fpu::init();
+    serial::init();
+    backtrace::init(boot_info);
+    acpi::init(boot_info);
+    time::init(boot_info);
+    thread::init();
+    random::init();
+    ps2::init();
+    userspace::run_modules(boot_info);
 
     let memmap = boot_info.memmap();
 
@@ -50,7 +85,6 @@ extern "C" fn kernel_main(boot_info: &'static StivaleStruct) -> ! {
     #[cfg(test)]
     test_main();
 
-    loop {
-        hlt();
-    }
+    // The idle thread takes over.
+    thread::exit(0)
 }
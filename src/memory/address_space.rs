@@ -0,0 +1,354 @@
+//! Address spaces: a PML4 of their own for the lower half, the kernel's
+//! tables for the higher half.
+//!
+//! The memory of an address space is described by its areas (VMAs), each
+//! backed by frames as soon as it's mapped. Dropping the address space frees
+//! all of them, along with its page tables.
+//!
+//! With PCIDs, every address space tags its TLB entries with its own PCID,
+//! so switching between them doesn't flush the TLB.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::memory::frame;
+use crate::memory::paging::{self, MapError, PageFlags, PAGE_SIZE};
+use crate::memory::phys_to_virt;
+use crate::memory::user::USER_END;
+use crate::x86;
+use crate::x86::cpuid::{self, Features};
+use crate::x86::registers::{Cr3, Cr4, Cr4Flags};
+
+/// Areas an address space can have. We have no heap to grow the list.
+pub const MAX_AREAS: usize = 32;
+
+/// PCIDs are 12 bits. 0 is the kernel's, and that of every address space
+/// when PCIDs are disabled or all taken.
+const PCIDS: usize = 4096;
+
+/// The bootloader's PML4, which the kernel runs on.
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+/// One bit per PCID, set when taken.
+static USED_PCIDS: Mutex<[u64; PCIDS / 64]> = Mutex::new([0; PCIDS / 64]);
+
+/// A range of pages of an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Area {
+    pub start: u64,
+    pub end: u64,
+    pub flags: PageFlags,
+}
+
+impl Area {
+    pub fn contains(&self, address: u64) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    pub fn pages(&self) -> impl Iterator<Item = u64> {
+        (self.start..self.end).step_by(PAGE_SIZE as usize)
+    }
+}
+
+pub struct AddressSpace {
+    pml4: u64,
+    pcid: u16,
+    areas: [Option<Area>; MAX_AREAS],
+    /// The TLB may have entries of our PCID that don't match the page
+    /// tables anymore: unmapped while inactive, or left by a previous owner
+    /// of the PCID.
+    stale: AtomicBool,
+}
+
+impl AddressSpace {
+    /// An empty address space.
+    pub fn new() -> Result<Self, MapError> {
+        Ok(Self {
+            pml4: paging::new_address_space()?,
+            pcid: allocate_pcid(),
+            areas: [None; MAX_AREAS],
+            stale: AtomicBool::new(true),
+        })
+    }
+
+    /// Physical address of the PML4.
+    pub fn pml4(&self) -> u64 {
+        self.pml4
+    }
+
+    pub fn is_active(&self) -> bool {
+        paging::active_pml4() == self.pml4
+    }
+
+    /// Switch to this address space.
+    pub fn activate(&self) {
+        let mut cr3 = self.pml4 | self.pcid as u64;
+        if self.pcid != 0 && !self.stale.swap(false, Ordering::Relaxed) {
+            cr3 |= Cr3::NO_FLUSH;
+        }
+        // Safe: the higher half, so the kernel, is mapped like everywhere.
+        unsafe { Cr3::write_raw(cr3) };
+    }
+
+    pub fn areas(&self) -> impl Iterator<Item = &Area> {
+        self.areas.iter().flatten()
+    }
+
+    /// The area containing `address`.
+    pub fn area(&self, address: u64) -> Option<&Area> {
+        self.areas().find(|area| area.contains(address))
+    }
+
+    /// The lowest address at or above `from` where `len` bytes are free.
+    pub fn find_free(&self, from: u64, len: u64) -> Option<u64> {
+        let mut start = from;
+        // Every step skips past an area, there are at most MAX_AREAS.
+        for _ in 0..=MAX_AREAS {
+            let end = start.checked_add(len).filter(|&end| end <= USER_END)?;
+            match self
+                .areas()
+                .find(|area| start < area.end && area.start < end)
+            {
+                Some(area) => start = area.end,
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    /// Map `len` bytes of zeroed memory at `start`, as a new area.
+    pub fn map(&mut self, start: u64, len: u64, flags: PageFlags) -> Result<(), MapError> {
+        let end = start.checked_add(len).ok_or(MapError::BadRange)?;
+        if (start | len) & (PAGE_SIZE - 1) != 0 || len == 0 || end > USER_END {
+            return Err(MapError::BadRange);
+        }
+        if self
+            .areas()
+            .any(|area| start < area.end && area.start < end)
+        {
+            return Err(MapError::AlreadyMapped);
+        }
+        let slot = self
+            .areas
+            .iter()
+            .position(Option::is_none)
+            .ok_or(MapError::TooManyAreas)?;
+
+        let area = Area {
+            start,
+            end,
+            flags: flags | PageFlags::USER,
+        };
+        for page in area.pages() {
+            let result = match frame::allocate_zeroed() {
+                Some(frame) => unsafe {
+                    paging::map_in(self.pml4, page, frame, area.flags)
+                        .inspect_err(|_| frame::free(frame))
+                },
+                None => Err(MapError::OutOfMemory),
+            };
+            if let Err(err) = result {
+                self.unmap_pages(start, page);
+                return Err(err);
+            }
+        }
+        self.areas[slot] = Some(area);
+        Ok(())
+    }
+
+    /// Unmap the area starting at `start`, and free its memory.
+    pub fn unmap(&mut self, start: u64) -> Result<(), MapError> {
+        let slot = self
+            .areas
+            .iter()
+            .position(|area| matches!(area, Some(area) if area.start == start))
+            .ok_or(MapError::BadRange)?;
+        let area = self.areas[slot].take().unwrap();
+        self.unmap_pages(area.start, area.end);
+        Ok(())
+    }
+
+    fn unmap_pages(&self, start: u64, end: u64) {
+        for page in (start..end).step_by(PAGE_SIZE as usize) {
+            if let Some(frame) = unsafe { paging::unmap_in(self.pml4, page) } {
+                unsafe { frame::free(frame) };
+            }
+        }
+        if !self.is_active() {
+            self.stale.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Copy `bytes` to `address`, through the direct map: the address space
+    /// doesn't have to be active, and page permissions don't matter.
+    pub fn write(&self, address: u64, bytes: &[u8]) -> Result<(), MapError> {
+        let mut done = 0;
+        while done < bytes.len() {
+            let virt = address + done as u64;
+            let phys = paging::translate_in(self.pml4, virt).ok_or(MapError::BadRange)?;
+            let len = ((PAGE_SIZE - virt % PAGE_SIZE) as usize).min(bytes.len() - done);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    bytes[done..].as_ptr(),
+                    phys_to_virt(phys) as *mut u8,
+                    len,
+                );
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// A copy of this address space, with the same areas and contents.
+    pub fn try_clone(&self) -> Result<Self, MapError> {
+        let mut clone = Self::new()?;
+        for area in self.areas() {
+            clone.map(area.start, area.end - area.start, area.flags)?;
+            for page in area.pages() {
+                let from = paging::translate_in(self.pml4, page).ok_or(MapError::BadRange)?;
+                let to = paging::translate_in(clone.pml4, page).ok_or(MapError::BadRange)?;
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        phys_to_virt(from) as *const u8,
+                        phys_to_virt(to) as *mut u8,
+                        PAGE_SIZE as usize,
+                    );
+                }
+            }
+        }
+        Ok(clone)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe { paging::destroy_address_space(self.pml4) };
+        free_pcid(self.pcid);
+    }
+}
+
+/// Physical address of the kernel's own PML4.
+pub fn kernel_pml4() -> u64 {
+    KERNEL_PML4.load(Ordering::Relaxed)
+}
+
+/// Switch back to the kernel's own address space.
+pub fn activate_kernel() {
+    unsafe { Cr3::write(KERNEL_PML4.load(Ordering::Relaxed), 0) };
+}
+
+fn allocate_pcid() -> u16 {
+    if !Cr4::read().contains(Cr4Flags::PCID) {
+        return 0;
+    }
+    x86::without_interrupts(|| {
+        let mut used = USED_PCIDS.lock();
+        for (i, word) in used.iter_mut().enumerate() {
+            if *word != u64::MAX {
+                let bit = word.trailing_ones() as usize;
+                *word |= 1 << bit;
+                return (i * 64 + bit) as u16;
+            }
+        }
+        0
+    })
+}
+
+fn free_pcid(pcid: u16) {
+    if pcid != 0 {
+        let pcid = pcid as usize;
+        x86::without_interrupts(|| USED_PCIDS.lock()[pcid / 64] &= !(1 << (pcid % 64)));
+    }
+}
+
+/// Share the kernel half with address spaces to come, and enable PCIDs if
+/// the CPU has them. Requires the frame allocator.
+pub fn init() {
+    paging::preallocate_kernel_tables().expect("No memory for the kernel page tables");
+    // The kernel keeps PCID 0.
+    USED_PCIDS.lock()[0] = 1;
+    let pml4 = paging::active_pml4();
+    KERNEL_PML4.store(pml4, Ordering::Relaxed);
+    if cpuid::has(Features::PCID) {
+        unsafe {
+            // Enabling PCIDs faults unless the current PCID is 0.
+            Cr3::write(pml4, 0);
+            Cr4::update(|flags| flags.insert(Cr4Flags::PCID));
+        }
+    }
+    log!(
+        "Address spaces: PCIDs {}",
+        if cpuid::has(Features::PCID) {
+            "enabled"
+        } else {
+            "unavailable"
+        }
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::user::copy_from_user;
+
+    const BASE: u64 = 0x0000_6000_0020_0000;
+
+    #[test_case]
+    fn areas_are_mapped_and_freed() {
+        let available = frame::available();
+        let mut space = AddressSpace::new().unwrap();
+        let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+        space.map(BASE, 2 * PAGE_SIZE, flags).unwrap();
+        assert_eq!(
+            space.map(BASE + PAGE_SIZE, PAGE_SIZE, flags),
+            Err(MapError::AlreadyMapped)
+        );
+        assert_eq!(
+            space.map(BASE + 1, PAGE_SIZE, flags),
+            Err(MapError::BadRange)
+        );
+        assert_eq!(
+            space.map(USER_END, PAGE_SIZE, flags),
+            Err(MapError::BadRange)
+        );
+        assert_eq!(space.area(BASE + PAGE_SIZE).unwrap().start, BASE);
+        assert!(paging::translate_in(space.pml4(), BASE + PAGE_SIZE).is_some());
+        // Not in the kernel's address space.
+        assert_eq!(paging::translate(BASE), None);
+
+        space.unmap(BASE).unwrap();
+        assert_eq!(paging::translate_in(space.pml4(), BASE), None);
+        assert_eq!(space.unmap(BASE), Err(MapError::BadRange));
+
+        space.map(BASE, PAGE_SIZE, flags).unwrap();
+        space.map(BASE + 2 * PAGE_SIZE, PAGE_SIZE, flags).unwrap();
+        assert_eq!(space.find_free(BASE, PAGE_SIZE), Some(BASE + PAGE_SIZE));
+        assert_eq!(
+            space.find_free(BASE, 2 * PAGE_SIZE),
+            Some(BASE + 3 * PAGE_SIZE)
+        );
+        assert_eq!(space.find_free(USER_END - PAGE_SIZE, 2 * PAGE_SIZE), None);
+        drop(space);
+        assert_eq!(frame::available(), available);
+    }
+
+    #[test_case]
+    fn clones_are_independent() {
+        let mut space = AddressSpace::new().unwrap();
+        space.map(BASE, PAGE_SIZE, PageFlags::WRITABLE).unwrap();
+        space.write(BASE + 8, b"parent").unwrap();
+        let clone = space.try_clone().unwrap();
+        space.write(BASE + 8, b"change").unwrap();
+
+        let mut buf = [0u8; 6];
+        clone.activate();
+        unsafe { copy_from_user(&mut buf, BASE + 8).unwrap() };
+        assert_eq!(&buf, b"parent");
+        space.activate();
+        unsafe { copy_from_user(&mut buf, BASE + 8).unwrap() };
+        assert_eq!(&buf, b"change");
+        activate_kernel();
+        assert!(!space.is_active());
+    }
+}
@@ -0,0 +1,200 @@
+//! Physical frame allocator.
+//!
+//! Frames come from the usable regions of the memory map, handed out in
+//! order. Freed frames go on a free list, linked through the frames
+//! themselves in the direct map, and are reused first.
+
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::boot::{MemmapType, StivaleStruct};
+use crate::memory::phys_to_virt;
+use crate::x86;
+
+pub const FRAME_SIZE: u64 = 0x1000;
+
+/// Low memory is left alone, e.g. for real mode trampolines.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+const MAX_REGIONS: usize = 64;
+
+/// A 4 KiB physical frame.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Frame(u64);
+
+impl Frame {
+    /// The frame containing physical address `address`.
+    pub const fn containing(address: u64) -> Self {
+        Self(address & !(FRAME_SIZE - 1))
+    }
+
+    /// Physical address of the start of the frame.
+    pub const fn start(self) -> u64 {
+        self.0
+    }
+
+    /// Where the frame can be accessed, in the direct map.
+    pub fn virt(self) -> *mut u8 {
+        phys_to_virt(self.0) as *mut u8
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Frame({:#x})", self.0)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Region {
+    start: u64,
+    end: u64,
+}
+
+struct Allocator {
+    regions: [Region; MAX_REGIONS],
+    region_count: usize,
+    /// Region of the next frame never handed out.
+    current: usize,
+    next: u64,
+    /// First freed frame, 0 if none: frame 0 is never handed out.
+    free_list: u64,
+    free_count: u64,
+}
+
+impl Allocator {
+    const fn new() -> Self {
+        Self {
+            regions: [Region { start: 0, end: 0 }; MAX_REGIONS],
+            region_count: 0,
+            current: 0,
+            next: 0,
+            free_list: 0,
+            free_count: 0,
+        }
+    }
+
+    fn add_region(&mut self, start: u64, end: u64) {
+        let start = ((start + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)).max(LOW_MEMORY_END);
+        let end = end & !(FRAME_SIZE - 1);
+        if start >= end {
+            return;
+        }
+        if self.region_count == MAX_REGIONS {
+            warn!("Too many memory regions, ignoring {:#x}..{:#x}", start, end);
+            return;
+        }
+        self.regions[self.region_count] = Region { start, end };
+        if self.region_count == 0 {
+            self.next = start;
+        }
+        self.region_count += 1;
+    }
+
+    fn allocate(&mut self) -> Option<Frame> {
+        if self.free_list != 0 {
+            let frame = Frame(self.free_list);
+            self.free_list = unsafe { *(frame.virt() as *const u64) };
+            self.free_count -= 1;
+            return Some(frame);
+        }
+        while self.current < self.region_count {
+            if self.next < self.regions[self.current].end {
+                let frame = Frame(self.next);
+                self.next += FRAME_SIZE;
+                return Some(frame);
+            }
+            self.current += 1;
+            if self.current < self.region_count {
+                self.next = self.regions[self.current].start;
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, frame: Frame) {
+        unsafe { *(frame.virt() as *mut u64) = self.free_list };
+        self.free_list = frame.start();
+        self.free_count += 1;
+    }
+
+    fn available(&self) -> u64 {
+        let untouched: u64 = self.regions[self.current.min(self.region_count)..self.region_count]
+            .iter()
+            .enumerate()
+            .map(|(i, region)| {
+                let start = if i == 0 { self.next } else { region.start };
+                region.end.saturating_sub(start)
+            })
+            .sum();
+        untouched / FRAME_SIZE + self.free_count
+    }
+}
+
+/// Only lock with interrupts disabled.
+static ALLOCATOR: Mutex<Allocator> = Mutex::new(Allocator::new());
+
+/// Take the usable regions of the memory map.
+pub fn init(boot_info: &StivaleStruct) {
+    let available = x86::without_interrupts(|| {
+        let mut allocator = ALLOCATOR.lock();
+        for entry in boot_info.memmap().values.iter() {
+            if entry.mm_type == MemmapType::Usable {
+                allocator.add_region(entry.base, entry.base + entry.length);
+            }
+        }
+        allocator.available()
+    });
+    debug!(
+        "{} MiB of usable memory",
+        available * FRAME_SIZE / (1024 * 1024)
+    );
+}
+
+pub fn allocate() -> Option<Frame> {
+    x86::without_interrupts(|| ALLOCATOR.lock().allocate())
+}
+
+/// A frame filled with zeroes, e.g. for page tables.
+pub fn allocate_zeroed() -> Option<Frame> {
+    let frame = allocate()?;
+    unsafe { frame.virt().write_bytes(0, FRAME_SIZE as usize) };
+    Some(frame)
+}
+
+/// Give `frame` back.
+///
+/// # Safety
+/// `frame` must come from `allocate`, and nothing may use it anymore.
+pub unsafe fn free(frame: Frame) {
+    x86::without_interrupts(|| ALLOCATOR.lock().free(frame));
+}
+
+/// Number of frames left.
+pub fn available() -> u64 {
+    x86::without_interrupts(|| ALLOCATOR.lock().available())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn freed_frames_are_reused() {
+        let before = available();
+        let a = allocate().unwrap();
+        let b = allocate().unwrap();
+        assert_ne!(a, b);
+        assert_eq!(a.start() % FRAME_SIZE, 0);
+        assert_eq!(available(), before - 2);
+
+        unsafe { free(b) };
+        assert_eq!(allocate(), Some(b));
+        unsafe {
+            free(a);
+            free(b);
+        }
+        assert_eq!(available(), before);
+    }
+}
@@ -0,0 +1,36 @@
+//! Memory management.
+
+pub mod address_space;
+pub mod frame;
+pub mod paging;
+pub mod stack;
+pub mod user;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::boot::StivaleStruct;
+
+/// Where the bootloader maps all physical memory, unless told otherwise by
+/// the HHDM tag.
+const DEFAULT_HHDM_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(DEFAULT_HHDM_OFFSET);
+
+pub fn init(boot_info: &StivaleStruct) {
+    if let Some(offset) = boot_info.hhdm() {
+        HHDM_OFFSET.store(offset, Ordering::Relaxed);
+    }
+    frame::init(boot_info);
+    address_space::init();
+}
+
+/// Virtual address of physical address `phys`, through the direct map.
+/// The bootloader maps at least the first 4 GiB, which covers MMIO devices.
+pub fn phys_to_virt(phys: u64) -> u64 {
+    phys + HHDM_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Inverse of `phys_to_virt`, for addresses inside the direct map.
+pub fn virt_to_phys(virt: u64) -> u64 {
+    virt - HHDM_OFFSET.load(Ordering::Relaxed)
+}
@@ -0,0 +1,331 @@
+//! 4-level page tables.
+//! https://wiki.osdev.org/Paging
+//!
+//! Tables are reached through the direct map, so they can be edited in any
+//! address space, not only the active one.
+
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::memory::frame::{self, Frame};
+use crate::memory::phys_to_virt;
+use crate::x86;
+use crate::x86::cpuid::{self, Features};
+use crate::x86::registers::{Cr3, Cr4, Cr4Flags};
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Start of the higher half, where the kernel lives in every address space.
+pub const KERNEL_HALF_START: u64 = 0xffff_8000_0000_0000;
+
+const ENTRIES: usize = 512;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+bitflags! {
+    pub struct PageFlags: u64 {
+        const PRESENT = 1 << 0;
+        const WRITABLE = 1 << 1;
+        /// Accessible from ring 3.
+        const USER = 1 << 2;
+        const WRITE_THROUGH = 1 << 3;
+        const NO_CACHE = 1 << 4;
+        const ACCESSED = 1 << 5;
+        const DIRTY = 1 << 6;
+        /// In a PDPT or PD entry: maps a 1 GiB or 2 MiB page.
+        const HUGE = 1 << 7;
+        /// Kept in the TLB across CR3 writes.
+        const GLOBAL = 1 << 8;
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// No frame left for a page table.
+    OutOfMemory,
+    /// The page is already mapped.
+    AlreadyMapped,
+    /// The page is part of a huge page.
+    HugePage,
+    /// The range isn't page aligned, or not in user space.
+    BadRange,
+    /// The address space can't track more areas.
+    TooManyAreas,
+}
+
+#[repr(C, align(4096))]
+pub struct PageTable {
+    pub entries: [u64; ENTRIES],
+}
+
+impl PageTable {
+    /// The table at physical address `phys`.
+    ///
+    /// # Safety
+    /// `phys` must be a page table, not otherwise borrowed.
+    pub unsafe fn at(phys: u64) -> &'static mut PageTable {
+        &mut *(phys_to_virt(phys) as *mut PageTable)
+    }
+}
+
+/// Index of `virt` in the table of `level`, 4 being the PML4.
+fn index(virt: u64, level: u32) -> usize {
+    ((virt >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+}
+
+/// Serializes changes to page tables. Only lock with interrupts disabled.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Physical address of the active PML4.
+pub fn active_pml4() -> u64 {
+    Cr3::read().0
+}
+
+/// Create every missing PML4 entry of the higher half in the active address
+/// space. Address spaces copy those entries, so the kernel mappings stay the
+/// same in all of them as long as it runs.
+pub fn preallocate_kernel_tables() -> Result<(), MapError> {
+    x86::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        let pml4 = unsafe { PageTable::at(active_pml4()) };
+        for entry in pml4.entries[index(KERNEL_HALF_START, 4)..].iter_mut() {
+            if *entry & PageFlags::PRESENT.bits() == 0 {
+                let frame = frame::allocate_zeroed().ok_or(MapError::OutOfMemory)?;
+                *entry = frame.start() | (PageFlags::PRESENT | PageFlags::WRITABLE).bits();
+            }
+        }
+        Ok(())
+    })
+}
+
+/// A new PML4 with an empty lower half, sharing the higher half, i.e. the
+/// kernel, with the active address space.
+pub fn new_address_space() -> Result<u64, MapError> {
+    let frame = frame::allocate_zeroed().ok_or(MapError::OutOfMemory)?;
+    let kernel = index(KERNEL_HALF_START, 4);
+    x86::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        let (active, new) = unsafe { (PageTable::at(active_pml4()), PageTable::at(frame.start())) };
+        new.entries[kernel..].copy_from_slice(&active.entries[kernel..]);
+    });
+    Ok(frame.start())
+}
+
+/// Free the lower half of the address space of `pml4`: every mapped page,
+/// the tables, and the PML4 itself.
+///
+/// # Safety
+/// `pml4` must come from `new_address_space`, not be active, and nothing
+/// may use its pages anymore.
+pub unsafe fn destroy_address_space(pml4: u64) {
+    unsafe fn free_table(table: u64, level: u32) {
+        for &entry in PageTable::at(table).entries.iter() {
+            if entry & PageFlags::PRESENT.bits() == 0 {
+                continue;
+            }
+            if level > 1 {
+                free_table(entry & ADDRESS_MASK, level - 1);
+            } else {
+                frame::free(Frame::containing(entry & ADDRESS_MASK));
+            }
+        }
+        frame::free(Frame::containing(table));
+    }
+
+    assert_ne!(pml4, active_pml4(), "Destroying the active address space");
+    x86::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        for &entry in PageTable::at(pml4).entries[..index(KERNEL_HALF_START, 4)].iter() {
+            if entry & PageFlags::PRESENT.bits() != 0 {
+                free_table(entry & ADDRESS_MASK, 3);
+            }
+        }
+        frame::free(Frame::containing(pml4));
+    });
+}
+
+/// The level 1 entry of `virt`, creating missing tables on the way.
+unsafe fn create_entry(pml4: u64, virt: u64, user: bool) -> Result<&'static mut u64, MapError> {
+    let mut table = PageTable::at(pml4);
+    for level in (2..=4).rev() {
+        let entry = &mut table.entries[index(virt, level)];
+        let flags = PageFlags::from_bits_truncate(*entry);
+        if flags.contains(PageFlags::HUGE) {
+            return Err(MapError::HugePage);
+        }
+        if !flags.contains(PageFlags::PRESENT) {
+            let frame = frame::allocate_zeroed().ok_or(MapError::OutOfMemory)?;
+            *entry = frame.start() | (PageFlags::PRESENT | PageFlags::WRITABLE).bits();
+        }
+        // Access rights are the most restrictive of every level.
+        if user {
+            *entry |= PageFlags::USER.bits();
+        }
+        table = PageTable::at(*entry & ADDRESS_MASK);
+    }
+    Ok(&mut table.entries[index(virt, 1)])
+}
+
+/// The level 1 entry of `virt`, if its tables exist.
+unsafe fn find_entry(pml4: u64, virt: u64) -> Option<&'static mut u64> {
+    let mut table = PageTable::at(pml4);
+    for level in (2..=4).rev() {
+        let entry = table.entries[index(virt, level)];
+        let flags = PageFlags::from_bits_truncate(entry);
+        if !flags.contains(PageFlags::PRESENT) || flags.contains(PageFlags::HUGE) {
+            return None;
+        }
+        table = PageTable::at(entry & ADDRESS_MASK);
+    }
+    Some(&mut table.entries[index(virt, 1)])
+}
+
+/// Map the page at `virt` to `frame` in the address space of `pml4`.
+///
+/// # Safety
+/// `pml4` must be a valid PML4, and the mapping must not break memory that
+/// is in use.
+pub unsafe fn map_in(pml4: u64, virt: u64, frame: Frame, flags: PageFlags) -> Result<(), MapError> {
+    x86::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        let entry = create_entry(pml4, virt, flags.contains(PageFlags::USER))?;
+        if *entry & PageFlags::PRESENT.bits() != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
+        *entry = frame.start() | (flags | PageFlags::PRESENT).bits();
+        Ok(())
+    })
+}
+
+/// Unmap the page at `virt` in the address space of `pml4`, and return the
+/// frame it was mapped to. The frame isn't freed.
+///
+/// # Safety
+/// Nothing may use the page anymore.
+pub unsafe fn unmap_in(pml4: u64, virt: u64) -> Option<Frame> {
+    let frame = x86::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        let entry = find_entry(pml4, virt)?;
+        if *entry & PageFlags::PRESENT.bits() == 0 {
+            return None;
+        }
+        let frame = Frame::containing(*entry & ADDRESS_MASK);
+        *entry = 0;
+        Some(frame)
+    })?;
+    if virt >= KERNEL_HALF_START {
+        flush_kernel_page(virt);
+    } else if pml4 == active_pml4() {
+        x86::invlpg(virt);
+    }
+    Some(frame)
+}
+
+/// Invalidate the TLB entries of `virt`, in the higher half. It is shared by
+/// every address space, so with PCIDs, those of other PCIDs may cache it
+/// too: `invlpg` only drops the entry of the current one.
+fn flush_kernel_page(virt: u64) {
+    if !Cr4::read().contains(Cr4Flags::PCID) {
+        x86::invlpg(virt);
+    } else if cpuid::has(Features::INVPCID) {
+        x86::invpcid_all();
+    } else {
+        // Toggling global pages flushes every PCID.
+        x86::without_interrupts(|| unsafe {
+            let cr4 = Cr4::read();
+            Cr4::write(cr4 ^ Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        });
+    }
+}
+
+/// Physical address `virt` maps to in the address space of `pml4`, and the
+/// access rights of every level combined.
+/// Lock free, so it can be used from exception handlers.
+pub fn lookup_in(pml4: u64, virt: u64) -> Option<(u64, PageFlags)> {
+    let mut table = unsafe { PageTable::at(pml4) };
+    let mut rights = PageFlags::USER | PageFlags::WRITABLE;
+    let mut no_execute = PageFlags::empty();
+    for level in (1..=4).rev() {
+        let entry = table.entries[index(virt, level)];
+        let flags = PageFlags::from_bits_truncate(entry);
+        if !flags.contains(PageFlags::PRESENT) {
+            return None;
+        }
+        rights &= flags;
+        no_execute |= flags & PageFlags::NO_EXECUTE;
+        if level == 1 || flags.contains(PageFlags::HUGE) {
+            let page_mask = (1 << (12 + 9 * (level - 1))) - 1;
+            let phys = (entry & ADDRESS_MASK & !page_mask) | (virt & page_mask);
+            return Some((phys, rights | no_execute | PageFlags::PRESENT));
+        }
+        table = unsafe { PageTable::at(entry & ADDRESS_MASK) };
+    }
+    None
+}
+
+/// Physical address `virt` maps to in the address space of `pml4`.
+/// Lock free, see `lookup_in`.
+pub fn translate_in(pml4: u64, virt: u64) -> Option<u64> {
+    lookup_in(pml4, virt).map(|(phys, _)| phys)
+}
+
+/// See `map_in`, in the active address space.
+///
+/// # Safety
+/// See `map_in`.
+pub unsafe fn map(virt: u64, frame: Frame, flags: PageFlags) -> Result<(), MapError> {
+    map_in(active_pml4(), virt, frame, flags)
+}
+
+/// See `unmap_in`, in the active address space.
+///
+/// # Safety
+/// See `unmap_in`.
+pub unsafe fn unmap(virt: u64) -> Option<Frame> {
+    unmap_in(active_pml4(), virt)
+}
+
+/// See `lookup_in`, in the active address space.
+pub fn lookup(virt: u64) -> Option<(u64, PageFlags)> {
+    lookup_in(active_pml4(), virt)
+}
+
+/// See `translate_in`, in the active address space.
+pub fn translate(virt: u64) -> Option<u64> {
+    translate_in(active_pml4(), virt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn map_translate_unmap() {
+        let virt = 0xffff_fd00_0000_0000;
+        let frame = frame::allocate().unwrap();
+        unsafe {
+            map(virt, frame, PageFlags::WRITABLE | PageFlags::NO_EXECUTE).unwrap();
+            assert_eq!(
+                map(virt, frame, PageFlags::WRITABLE),
+                Err(MapError::AlreadyMapped)
+            );
+            assert_eq!(translate(virt + 0x123), Some(frame.start() + 0x123));
+
+            // Same memory, seen through the direct map.
+            *(virt as *mut u64) = 0xdead_beef;
+            assert_eq!(*(frame.virt() as *const u64), 0xdead_beef);
+
+            assert_eq!(unmap(virt), Some(frame));
+            assert_eq!(translate(virt), None);
+            frame::free(frame);
+        }
+    }
+
+    #[test_case]
+    fn direct_map_translates() {
+        let frame = frame::allocate().unwrap();
+        assert_eq!(translate(frame.virt() as u64), Some(frame.start()));
+        unsafe { frame::free(frame) };
+    }
+}
@@ -0,0 +1,96 @@
+//! Kernel stacks, with unmapped guard pages below them.
+//!
+//! Stacks live in their own region, one per fixed size slot. A stack takes
+//! the top of its slot and the pages under it stay unmapped: running off the
+//! end of a stack page faults instead of corrupting whatever lies below.
+//! Since the fault can't be delivered on the overflowed stack, it becomes a
+//! double fault, handled on its own IST stack.
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memory::frame;
+use crate::memory::paging::{self, MapError, PageFlags, PAGE_SIZE};
+use crate::x86;
+
+const REGION: Range<u64> = 0xffff_fe00_0000_0000..0xffff_fe80_0000_0000;
+const SLOT_SIZE: u64 = 0x10000;
+
+/// Largest stack, leaving at least one guard page in the slot.
+pub const MAX_PAGES: usize = (SLOT_SIZE / PAGE_SIZE) as usize - 1;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub struct Stack {
+    bottom: u64,
+    top: u64,
+}
+
+impl Stack {
+    /// Map a stack of `pages` pages in a new slot.
+    pub fn allocate(pages: usize) -> Result<Self, MapError> {
+        assert!((1..=MAX_PAGES).contains(&pages), "Bad stack size");
+        let slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+        let top = REGION.start + (slot + 1) * SLOT_SIZE;
+        assert!(top <= REGION.end, "Out of kernel stack slots");
+        let bottom = top - pages as u64 * PAGE_SIZE;
+
+        for page in (bottom..top).step_by(PAGE_SIZE as usize) {
+            let result = match frame::allocate() {
+                Some(frame) => unsafe {
+                    paging::map(page, frame, PageFlags::WRITABLE | PageFlags::NO_EXECUTE)
+                        .inspect_err(|_| frame::free(frame))
+                },
+                None => Err(MapError::OutOfMemory),
+            };
+            if let Err(err) = result {
+                for mapped in (bottom..page).step_by(PAGE_SIZE as usize) {
+                    unsafe { frame::free(paging::unmap(mapped).unwrap()) };
+                }
+                return Err(err);
+            }
+        }
+        Ok(Self { bottom, top })
+    }
+
+    /// Lowest address of the stack.
+    pub fn bottom(&self) -> u64 {
+        self.bottom
+    }
+
+    /// Initial stack pointer: stacks grow down.
+    pub fn top(&self) -> u64 {
+        self.top
+    }
+
+    /// Run `f(arg)` on this stack, never to come back. The backtrace stops
+    /// at `f`.
+    ///
+    /// # Safety
+    /// Nothing may use the current stack anymore.
+    pub unsafe fn switch_to(&self, f: extern "C" fn(u64) -> !, arg: u64) -> ! {
+        x86::switch_stack(self.top, f, arg)
+    }
+}
+
+/// Whether `address` is in the guard pages of a kernel stack, i.e. whether
+/// a fault there is a stack overflow. Lock free, for exception handlers.
+pub fn is_guard_page(address: u64) -> bool {
+    REGION.contains(&address) && paging::translate(address).is_none()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn stacks_have_guard_pages() {
+        let stack = Stack::allocate(2).unwrap();
+        assert_eq!(stack.top() - stack.bottom(), 2 * PAGE_SIZE);
+        assert!(!is_guard_page(stack.top() - 8));
+        assert!(!is_guard_page(stack.bottom()));
+        assert!(is_guard_page(stack.bottom() - 8));
+        unsafe { *((stack.top() - 8) as *mut u64) = 42 };
+    }
+}
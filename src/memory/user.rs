@@ -0,0 +1,106 @@
+//! Copying to and from user memory.
+//! With SMAP, the kernel faults on user pages unless it asks for access,
+//! so every user access goes through here.
+
+use core::ptr;
+
+use crate::memory::paging::{self, PageFlags, PAGE_SIZE};
+use crate::x86::protection::UserAccess;
+
+/// End of user space, a page short of the end of the lower half: `syscall`
+/// in the last page would return to a non-canonical RIP, and `sysretq`
+/// faults on those in ring 0, on the user's stack.
+pub const USER_END: u64 = 0x0000_7fff_ffff_f000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    /// The range isn't entirely in user space.
+    BadAddress,
+    /// Part of the range isn't mapped for user code, or not writable.
+    NotMapped,
+}
+
+/// Check that `[address, address + len)` is in user space.
+pub fn check_range(address: u64, len: usize) -> Result<(), UserCopyError> {
+    match address.checked_add(len as u64) {
+        Some(end) if end <= USER_END => Ok(()),
+        _ => Err(UserCopyError::BadAddress),
+    }
+}
+
+/// Check that `[address, address + len)` is mapped for user code, and
+/// writable if `write`. Then copying it can't fault, as long as nothing
+/// unmaps it meanwhile.
+pub fn check_mapped(address: u64, len: usize, write: bool) -> Result<(), UserCopyError> {
+    check_range(address, len)?;
+    let mut needed = PageFlags::USER;
+    if write {
+        needed |= PageFlags::WRITABLE;
+    }
+    let start = address & !(PAGE_SIZE - 1);
+    for page in (start..address + len as u64).step_by(PAGE_SIZE as usize) {
+        match paging::lookup(page) {
+            Some((_, flags)) if flags.contains(needed) => {}
+            _ => return Err(UserCopyError::NotMapped),
+        }
+    }
+    Ok(())
+}
+
+/// Copy `dst.len()` bytes from user address `src`.
+///
+/// # Safety
+/// The range must be mapped: we have no exception fixups yet, so a fault
+/// there is fatal.
+pub unsafe fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), UserCopyError> {
+    check_range(src, dst.len())?;
+    let _access = UserAccess::new();
+    ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len());
+    Ok(())
+}
+
+/// Copy `src` to user address `dst`.
+///
+/// # Safety
+/// The range must be mapped and writable, see `copy_from_user`.
+pub unsafe fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), UserCopyError> {
+    check_range(dst, src.len())?;
+    let _access = UserAccess::new();
+    ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn rejects_kernel_addresses() {
+        assert_eq!(check_range(0x1000, 0x1000), Ok(()));
+        assert_eq!(check_range(USER_END - 1, 1), Ok(()));
+        assert_eq!(check_range(USER_END - 1, 2), Err(UserCopyError::BadAddress));
+        assert_eq!(check_range(u64::MAX, 2), Err(UserCopyError::BadAddress));
+
+        let mut buf = [0u8; 4];
+        let kernel = buf.as_ptr() as u64;
+        assert_eq!(
+            unsafe { copy_from_user(&mut buf, kernel) },
+            Err(UserCopyError::BadAddress)
+        );
+    }
+
+    #[test_case]
+    fn rejects_unmapped_and_kernel_only_pages() {
+        // Nothing there.
+        assert_eq!(
+            check_mapped(0x0000_5000_0000_0000, 8, false),
+            Err(UserCopyError::NotMapped)
+        );
+        // The bootloader's identity map is kernel only.
+        assert_eq!(
+            check_mapped(0x10_0000, 8, false),
+            Err(UserCopyError::NotMapped)
+        );
+        assert_eq!(check_mapped(0x1000, 0, false), Ok(()));
+    }
+}
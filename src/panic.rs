@@ -0,0 +1,235 @@
+//! What happens when the kernel panics.
+//!
+//! The panicking CPU disables interrupts, stops the other CPUs with an NMI,
+//! and takes over the output locks: whoever held them won't run again. It
+//! then dumps the registers, a backtrace and the log ring to serial, and
+//! applies the policy given by `panic=` on the kernel command line.
+//!
+//! Command line options:
+//! - `panic=halt|reboot|qemu-exit`, `halt` by default.
+//! - `panic_nmi=on|off`, whether to halt the other CPUs, `on` by default.
+
+use core::fmt;
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::boot::StivaleStruct;
+use crate::interrupts::{self, apic, ExceptionStackFrame, IstSlot};
+use crate::qemu::{exit_qemu, ExitCode};
+use crate::serial::{self, COM1};
+use crate::x86::registers::{Cr0, Cr2, Cr3, Cr4};
+use crate::x86::tables::{self, DescriptorTableRegister};
+use crate::{backtrace, logger, println, ps2, serial_println, x86};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Policy {
+    /// Stop the CPU, leaving the output on screen.
+    Halt = 0,
+    Reboot = 1,
+    /// Exit QEMU with a failure code, through the isa-debug-exit device.
+    /// Halts when not running in QEMU.
+    QemuExit = 2,
+}
+
+impl Policy {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "halt" => Some(Policy::Halt),
+            "reboot" => Some(Policy::Reboot),
+            "qemu-exit" => Some(Policy::QemuExit),
+            _ => None,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Policy::Reboot,
+            2 => Policy::QemuExit,
+            _ => Policy::Halt,
+        }
+    }
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(Policy::Halt as u8);
+
+static HALT_OTHER_CPUS: AtomicBool = AtomicBool::new(true);
+
+/// Number of panics entered, to catch panics while panicking.
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn policy() -> Policy {
+    Policy::from_u8(POLICY.load(Ordering::Relaxed))
+}
+
+pub fn set_policy(policy: Policy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Whether a panic is in progress.
+pub fn is_panicking() -> bool {
+    PANICS.load(Ordering::Relaxed) != 0
+}
+
+/// Value of the `key=value` option of `cmdline`. The last one wins.
+fn option<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .rev()
+        .find_map(|word| word.strip_prefix(key)?.strip_prefix('='))
+}
+
+/// Registers of the panicking code, as far as we can still tell.
+struct Registers {
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    fn capture() -> Self {
+        Self {
+            rsp: x86::stack_pointer(),
+            rbp: x86::frame_pointer(),
+            rflags: x86::rflags(),
+            cr0: Cr0::read().bits(),
+            cr2: Cr2::read(),
+            cr3: Cr3::read_raw(),
+            cr4: Cr4::read().bits(),
+        }
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RSP {:#018x} RBP {:#018x} RFLAGS {:#010x}",
+            self.rsp, self.rbp, self.rflags
+        )?;
+        write!(
+            f,
+            "CR0 {:#018x} CR2 {:#018x} CR3 {:#018x} CR4 {:#018x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )
+    }
+}
+
+extern "x86-interrupt" fn nmi_handler(_frame: ExceptionStackFrame) {
+    if is_panicking() {
+        // Another CPU panicked and wants us out of the way.
+        halt();
+    }
+    warn!("Unexpected NMI");
+}
+
+/// Read the panic options from the command line.
+pub fn init(boot_info: &StivaleStruct) {
+    interrupts::set_handler_on_stack(interrupts::NMI, nmi_handler, IstSlot::Nmi);
+
+    let cmdline = boot_info.cmdline().unwrap_or("");
+    if let Some(name) = option(cmdline, "panic") {
+        match Policy::from_name(name) {
+            Some(policy) => set_policy(policy),
+            None => warn!("Unknown panic policy {:?}, keeping {:?}", name, policy()),
+        }
+    }
+    match option(cmdline, "panic_nmi") {
+        Some("on") | None => {}
+        Some("off") => HALT_OTHER_CPUS.store(false, Ordering::Relaxed),
+        Some(value) => warn!("Invalid panic_nmi value {:?}", value),
+    }
+    debug!("Panic policy: {:?}", policy());
+}
+
+/// Stop this CPU for good.
+fn halt() -> ! {
+    loop {
+        x86::cli();
+        x86::hlt();
+    }
+}
+
+fn reboot() -> ! {
+    serial_println!("Rebooting");
+    if let Err(err) = ps2::reset_system() {
+        serial_println!("PS/2 controller reset failed: {:?}", err);
+    }
+    // Any exception with an empty IDT triple faults, which resets the CPU.
+    unsafe {
+        tables::lidt(&DescriptorTableRegister {
+            limit: 0,
+            base: ptr::null(),
+        });
+    }
+    x86::int3();
+    halt()
+}
+
+/// Print everything we know about the panic, then apply the policy.
+pub fn handle(info: &PanicInfo) -> ! {
+    x86::cli();
+    match PANICS.fetch_add(1, Ordering::SeqCst) {
+        0 => {}
+        1 => {
+            // Printing is what failed, most likely: keep it minimal.
+            unsafe { serial::force_unlock() };
+            serial::force_synchronous();
+            serial_println!("PANIC while panicking: {}", info);
+            halt();
+        }
+        _ => halt(),
+    }
+
+    let registers = Registers::capture();
+    if HALT_OTHER_CPUS.load(Ordering::Relaxed) && apic::is_enabled() {
+        apic::send_nmi_to_others();
+    }
+    // Everything else is stopped: the locks' holders won't release them.
+    unsafe { logger::force_unlock() };
+    serial::force_synchronous();
+
+    error!("PANIC: {}", info);
+    println!("PANIC: {}", info);
+    serial_println!("{}", registers);
+    backtrace::print();
+
+    serial_println!("---- Log ring ----");
+    logger::RING.dump(&mut *COM1.lock()).unwrap();
+    serial_println!("---- End of log ring ----");
+
+    match policy() {
+        Policy::Halt => halt(),
+        Policy::Reboot => reboot(),
+        Policy::QemuExit => {
+            exit_qemu(ExitCode::Failure);
+            halt()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parses_options() {
+        let cmdline = "quiet panic=reboot panic_nmi=off panic=qemu-exit";
+        assert_eq!(option(cmdline, "panic"), Some("qemu-exit"));
+        assert_eq!(option(cmdline, "panic_nmi"), Some("off"));
+        assert_eq!(option(cmdline, "quiet"), None);
+        assert_eq!(option("", "panic"), None);
+
+        assert_eq!(Policy::from_name("reboot"), Some(Policy::Reboot));
+        assert_eq!(Policy::from_name("explode"), None);
+        for policy in [Policy::Halt, Policy::Reboot, Policy::QemuExit] {
+            assert_eq!(Policy::from_u8(policy as u8), policy);
+        }
+    }
+}
@@ -0,0 +1,766 @@
+//! PS/2 keyboard driver.
+//! https://wiki.osdev.org/PS/2_Keyboard
+//!
+//! Scancodes (set 1 or 2) are decoded into physical keys, combined with the
+//! modifier state and a [`Layout`] into [`KeyEvent`]s, which are queued for
+//! the rest of the kernel to consume.
+
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::interrupts::{self, ExceptionStackFrame};
+use crate::ps2::layout::Layout;
+use crate::ps2::{self, Port, Ps2Error, ACK, RESEND};
+use crate::random;
+use crate::ring::RingBuffer;
+use crate::x86;
+
+/// Physical keys, named after what they print on a US keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    /// Extra key left of Z on ISO keyboards.
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftMeta,
+    LeftAlt,
+    Space,
+    /// AltGr on non-US layouts.
+    RightAlt,
+    RightMeta,
+    Menu,
+    RightCtrl,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    Up,
+    Left,
+    Down,
+    Right,
+    NumLock,
+    KeypadSlash,
+    KeypadStar,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+bitflags! {
+    pub struct Modifiers: u16 {
+        const LEFT_SHIFT = 1 << 0;
+        const RIGHT_SHIFT = 1 << 1;
+        const LEFT_CTRL = 1 << 2;
+        const RIGHT_CTRL = 1 << 3;
+        const LEFT_ALT = 1 << 4;
+        const RIGHT_ALT = 1 << 5;
+        const LEFT_META = 1 << 6;
+        const RIGHT_META = 1 << 7;
+        const CAPS_LOCK = 1 << 8;
+        const NUM_LOCK = 1 << 9;
+        const SCROLL_LOCK = 1 << 10;
+    }
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.intersects(Modifiers::LEFT_SHIFT | Modifiers::RIGHT_SHIFT)
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.intersects(Modifiers::LEFT_CTRL | Modifiers::RIGHT_CTRL)
+    }
+
+    pub fn alt(&self) -> bool {
+        self.contains(Modifiers::LEFT_ALT)
+    }
+
+    pub fn altgr(&self) -> bool {
+        self.contains(Modifiers::RIGHT_ALT)
+    }
+
+    /// Which modifier a key toggles or holds, if any.
+    fn of(key: KeyCode) -> Option<Modifiers> {
+        Some(match key {
+            KeyCode::LeftShift => Modifiers::LEFT_SHIFT,
+            KeyCode::RightShift => Modifiers::RIGHT_SHIFT,
+            KeyCode::LeftCtrl => Modifiers::LEFT_CTRL,
+            KeyCode::RightCtrl => Modifiers::RIGHT_CTRL,
+            KeyCode::LeftAlt => Modifiers::LEFT_ALT,
+            KeyCode::RightAlt => Modifiers::RIGHT_ALT,
+            KeyCode::LeftMeta => Modifiers::LEFT_META,
+            KeyCode::RightMeta => Modifiers::RIGHT_META,
+            KeyCode::CapsLock => Modifiers::CAPS_LOCK,
+            KeyCode::NumLock => Modifiers::NUM_LOCK,
+            KeyCode::ScrollLock => Modifiers::SCROLL_LOCK,
+            _ => return None,
+        })
+    }
+
+    fn is_lock(&self) -> bool {
+        self.intersects(Modifiers::CAPS_LOCK | Modifiers::NUM_LOCK | Modifiers::SCROLL_LOCK)
+    }
+
+    /// LED bits for the Set LEDs (0xED) command.
+    fn leds(&self) -> u8 {
+        (self.contains(Modifiers::SCROLL_LOCK) as u8)
+            | (self.contains(Modifiers::NUM_LOCK) as u8) << 1
+            | (self.contains(Modifiers::CAPS_LOCK) as u8) << 2
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// Modifiers after this event was applied.
+    pub modifiers: Modifiers,
+    /// Character typed, according to the current layout.
+    /// Only set for key presses.
+    pub char: Option<char>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// IBM PC XT set, also what the controller translates set 2 to.
+    Set1,
+    /// IBM PC AT set, the default of every keyboard.
+    Set2,
+}
+
+const EXTENDED: u8 = 0xe0;
+/// Starts the Pause key sequence, the only one using this prefix.
+const EXTENDED2: u8 = 0xe1;
+/// Set 2 prefix for a release
+const RELEASE: u8 = 0xf0;
+
+fn set1(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Key1,
+        0x03 => Key2,
+        0x04 => Key3,
+        0x05 => Key4,
+        0x06 => Key5,
+        0x07 => Key6,
+        0x08 => Key7,
+        0x09 => Key8,
+        0x0a => Key9,
+        0x0b => Key0,
+        0x0c => Minus,
+        0x0d => Equals,
+        0x0e => Backspace,
+        0x0f => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1a => LeftBracket,
+        0x1b => RightBracket,
+        0x1c => Enter,
+        0x1d => LeftCtrl,
+        0x1e => A,
+        0x1f => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Quote,
+        0x29 => Backtick,
+        0x2a => LeftShift,
+        0x2b => Backslash,
+        0x2c => Z,
+        0x2d => X,
+        0x2e => C,
+        0x2f => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => KeypadStar,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3a => CapsLock,
+        0x3b => F1,
+        0x3c => F2,
+        0x3d => F3,
+        0x3e => F4,
+        0x3f => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Keypad7,
+        0x48 => Keypad8,
+        0x49 => Keypad9,
+        0x4a => KeypadMinus,
+        0x4b => Keypad4,
+        0x4c => Keypad5,
+        0x4d => Keypad6,
+        0x4e => KeypadPlus,
+        0x4f => Keypad1,
+        0x50 => Keypad2,
+        0x51 => Keypad3,
+        0x52 => Keypad0,
+        0x53 => KeypadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+fn set1_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x1c => KeypadEnter,
+        0x1d => RightCtrl,
+        0x35 => KeypadSlash,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => Up,
+        0x49 => PageUp,
+        0x4b => Left,
+        0x4d => Right,
+        0x4f => End,
+        0x50 => Down,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5b => LeftMeta,
+        0x5c => RightMeta,
+        0x5d => Menu,
+        // 0x2a and 0x36 are fake shifts sent around some keys.
+        _ => return None,
+    })
+}
+
+fn set2(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0a => F8,
+        0x0b => F6,
+        0x0c => F4,
+        0x0d => Tab,
+        0x0e => Backtick,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Key1,
+        0x1a => Z,
+        0x1b => S,
+        0x1c => A,
+        0x1d => W,
+        0x1e => Key2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Key4,
+        0x26 => Key3,
+        0x29 => Space,
+        0x2a => V,
+        0x2b => F,
+        0x2c => T,
+        0x2d => R,
+        0x2e => Key5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Key6,
+        0x3a => M,
+        0x3b => J,
+        0x3c => U,
+        0x3d => Key7,
+        0x3e => Key8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Key0,
+        0x46 => Key9,
+        0x49 => Period,
+        0x4a => Slash,
+        0x4b => L,
+        0x4c => Semicolon,
+        0x4d => P,
+        0x4e => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5a => Enter,
+        0x5b => RightBracket,
+        0x5d => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6b => Keypad4,
+        0x6c => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7a => Keypad3,
+        0x7b => KeypadMinus,
+        0x7c => KeypadStar,
+        0x7d => Keypad9,
+        0x7e => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+fn set2_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightCtrl,
+        0x1f => LeftMeta,
+        0x27 => RightMeta,
+        0x2f => Menu,
+        0x4a => KeypadSlash,
+        0x5a => KeypadEnter,
+        0x69 => End,
+        0x6b => Left,
+        0x6c => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => Down,
+        0x74 => Right,
+        0x75 => Up,
+        0x7a => PageDown,
+        0x7c => PrintScreen,
+        0x7d => PageUp,
+        // 0x12 and 0x59 are fake shifts sent around some keys.
+        _ => return None,
+    })
+}
+
+/// Turns a stream of scancode bytes into key presses and releases.
+#[derive(Debug)]
+pub struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    release: bool,
+    /// Bytes of the Pause sequence still to be skipped.
+    pause_remaining: u8,
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            extended: false,
+            release: false,
+            pause_remaining: 0,
+        }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// Feed one byte, returning a key once its sequence is complete.
+    pub fn feed(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        if self.pause_remaining > 0 {
+            self.pause_remaining -= 1;
+            return None;
+        }
+
+        match byte {
+            EXTENDED => {
+                self.extended = true;
+                return None;
+            }
+            EXTENDED2 => {
+                // Pause has no release code, report it as pressed right away.
+                // E1 1D 45 E1 9D C5 in set 1, E1 14 77 E1 F0 14 F0 77 in set 2
+                self.pause_remaining = match self.set {
+                    ScancodeSet::Set1 => 5,
+                    ScancodeSet::Set2 => 7,
+                };
+                return Some((KeyCode::Pause, KeyState::Pressed));
+            }
+            RELEASE if self.set == ScancodeSet::Set2 => {
+                self.release = true;
+                return None;
+            }
+            _ => {}
+        }
+
+        let extended = core::mem::replace(&mut self.extended, false);
+        let (code, state) = match self.set {
+            ScancodeSet::Set1 => {
+                let state = if byte & 0x80 != 0 {
+                    KeyState::Released
+                } else {
+                    KeyState::Pressed
+                };
+                let code = byte & 0x7f;
+                if extended {
+                    (set1_extended(code), state)
+                } else {
+                    (set1(code), state)
+                }
+            }
+            ScancodeSet::Set2 => {
+                let state = if core::mem::replace(&mut self.release, false) {
+                    KeyState::Released
+                } else {
+                    KeyState::Pressed
+                };
+                if extended {
+                    (set2_extended(byte), state)
+                } else {
+                    (set2(byte), state)
+                }
+            }
+        };
+
+        code.map(|code| (code, state))
+    }
+}
+
+/// Device commands
+const SET_LEDS: u8 = 0xed;
+const SCANCODE_SET: u8 = 0xf0;
+const ENABLE_SCANNING: u8 = 0xf4;
+const DISABLE_SCANNING: u8 = 0xf5;
+
+pub struct Keyboard {
+    decoder: Decoder,
+    modifiers: Modifiers,
+    layout: Layout,
+    /// LED state to send once the keyboard acknowledges SET_LEDS.
+    pending_leds: Option<u8>,
+}
+
+impl Keyboard {
+    pub const fn new(set: ScancodeSet, layout: Layout) -> Self {
+        Self {
+            decoder: Decoder::new(set),
+            modifiers: Modifiers::empty(),
+            layout,
+            pending_leds: None,
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Handle a byte from the keyboard, returning the event it completes.
+    /// Any LED update is sent through `send`.
+    fn process(&mut self, byte: u8, mut send: impl FnMut(u8)) -> Option<KeyEvent> {
+        match byte {
+            ACK => {
+                if let Some(leds) = self.pending_leds.take() {
+                    send(leds);
+                }
+                return None;
+            }
+            RESEND => return None,
+            _ => {}
+        }
+
+        let (code, state) = self.decoder.feed(byte)?;
+
+        if let Some(modifier) = Modifiers::of(code) {
+            if modifier.is_lock() {
+                // Locks toggle on press, ignoring repeats.
+                if state == KeyState::Pressed {
+                    self.modifiers.toggle(modifier);
+                    self.pending_leds = Some(self.modifiers.leds());
+                    send(SET_LEDS);
+                }
+            } else {
+                self.modifiers.set(modifier, state == KeyState::Pressed);
+            }
+        }
+
+        let char = match state {
+            KeyState::Pressed => self.layout.map(code, self.modifiers),
+            KeyState::Released => None,
+        };
+
+        Some(KeyEvent {
+            code,
+            state,
+            modifiers: self.modifiers,
+            char,
+        })
+    }
+}
+
+const IRQ: u8 = 1;
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new(ScancodeSet::Set2, Layout::Us));
+
+/// Decoded events, oldest first.
+/// Only lock with interrupts disabled, or the handler could deadlock.
+static EVENTS: Mutex<RingBuffer<KeyEvent, 128>> = Mutex::new(RingBuffer::new());
+
+extern "x86-interrupt" fn keyboard_handler(_frame: ExceptionStackFrame) {
+    let byte = ps2::read_data_now();
+    random::add_interrupt_randomness(byte as u64);
+    let event = KEYBOARD.lock().process(byte, |byte| {
+        // Nothing sensible to do on failure from here.
+        let _ = ps2::write_data(Port::First, byte);
+    });
+    if let Some(event) = event {
+        // Drop events when nobody reads them.
+        let _ = EVENTS.lock().push(event);
+    }
+    interrupts::end_of_interrupt(IRQ);
+}
+
+/// Set the keyboard up and start receiving events.
+/// We use scancode set 2 without translation when the keyboard supports
+/// selecting it, and fall back to translated set 1 otherwise.
+pub fn init() -> Result<(), Ps2Error> {
+    ps2::device_command(Port::First, DISABLE_SCANNING)?;
+
+    let set = match ps2::device_command(Port::First, SCANCODE_SET)
+        .and_then(|_| ps2::device_command(Port::First, 2))
+    {
+        Ok(()) => ScancodeSet::Set2,
+        Err(_) => {
+            ps2::set_translation(true)?;
+            ScancodeSet::Set1
+        }
+    };
+    debug!("PS/2 keyboard using scancode {:?}", set);
+
+    let mut keyboard = KEYBOARD.lock();
+    keyboard.decoder = Decoder::new(set);
+    drop(keyboard);
+
+    ps2::device_command(Port::First, SET_LEDS)?;
+    ps2::device_command(Port::First, 0)?;
+    ps2::device_command(Port::First, ENABLE_SCANNING)?;
+
+    interrupts::set_irq_handler(IRQ, keyboard_handler);
+    ps2::enable_interrupt(Port::First)
+}
+
+pub fn set_layout(layout: Layout) {
+    x86::without_interrupts(|| KEYBOARD.lock().layout = layout);
+}
+
+pub fn modifiers() -> Modifiers {
+    x86::without_interrupts(|| KEYBOARD.lock().modifiers())
+}
+
+/// Return the next key event if there is one, without blocking.
+pub fn next_event() -> Option<KeyEvent> {
+    x86::without_interrupts(|| EVENTS.lock().pop())
+}
+
+/// Wait for the next key event.
+pub fn wait_event() -> KeyEvent {
+    loop {
+        x86::cli();
+        if let Some(event) = EVENTS.lock().pop() {
+            x86::sti();
+            return event;
+        }
+        x86::sti_hlt();
+    }
+}
+
+/// Wait for a key press that types a character.
+pub fn read_char() -> char {
+    loop {
+        if let Some(char) = wait_event().char {
+            return char;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed_all(keyboard: &mut Keyboard, bytes: &[u8]) -> Option<KeyEvent> {
+        let mut last = None;
+        for &byte in bytes {
+            if let Some(event) = keyboard.process(byte, |_| {}) {
+                last = Some(event);
+            }
+        }
+        last
+    }
+
+    #[test_case]
+    fn set1_shifted_letter() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, Layout::Us);
+        let event = feed_all(&mut keyboard, &[0x2a, 0x1e]).unwrap();
+        assert_eq!(event.code, KeyCode::A);
+        assert_eq!(event.char, Some('A'));
+        let event = feed_all(&mut keyboard, &[0xaa, 0x9e]).unwrap();
+        assert_eq!(event.state, KeyState::Released);
+        assert!(!event.modifiers.shift());
+    }
+
+    #[test_case]
+    fn set2_extended_release() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set2, Layout::Us);
+        let event = feed_all(&mut keyboard, &[0xe0, 0x75]).unwrap();
+        assert_eq!((event.code, event.state), (KeyCode::Up, KeyState::Pressed));
+        let event = feed_all(&mut keyboard, &[0xe0, 0xf0, 0x75]).unwrap();
+        assert_eq!((event.code, event.state), (KeyCode::Up, KeyState::Released));
+    }
+
+    #[test_case]
+    fn caps_lock_toggles_and_sets_leds() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set2, Layout::Us);
+        let mut sent = [0u8; 2];
+        let mut nb_sent = 0;
+        let mut send = |byte| {
+            sent[nb_sent] = byte;
+            nb_sent += 1;
+        };
+        keyboard.process(0x58, &mut send);
+        keyboard.process(ACK, &mut send);
+        assert_eq!(sent, [SET_LEDS, 1 << 2]);
+
+        let event = feed_all(&mut keyboard, &[0xf0, 0x58, 0x1c]).unwrap();
+        assert!(event.modifiers.contains(Modifiers::CAPS_LOCK));
+        assert_eq!(event.char, Some('A'));
+    }
+
+    #[test_case]
+    fn pause_sequence_is_one_key() {
+        let mut decoder = Decoder::new(ScancodeSet::Set2);
+        let mut keys = 0;
+        for byte in [0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77, 0x1c] {
+            if decoder.feed(byte).is_some() {
+                keys += 1;
+            }
+        }
+        assert_eq!(keys, 2);
+    }
+}
@@ -0,0 +1,219 @@
+//! Keyboard layouts: what character a physical key types.
+//! Dead keys aren't supported, they type their own character.
+
+use crate::ps2::keyboard::{KeyCode, Modifiers};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// US QWERTY
+    Us,
+    /// French AZERTY
+    French,
+}
+
+/// Characters for a key: unmodified, with shift, with AltGr.
+type Chars = (char, Option<char>, Option<char>);
+
+const fn keys(normal: char, shifted: char) -> Chars {
+    (normal, Some(shifted), None)
+}
+
+const fn keys3(normal: char, shifted: char, altgr: char) -> Chars {
+    (normal, Some(shifted), Some(altgr))
+}
+
+/// Keys which type the same thing on every layout.
+fn common(key: KeyCode, modifiers: Modifiers) -> Option<char> {
+    use KeyCode::*;
+    let num_lock = modifiers.contains(Modifiers::NUM_LOCK) && !modifiers.shift();
+    Some(match key {
+        Space => ' ',
+        Tab => '\t',
+        Enter | KeypadEnter => '\n',
+        Backspace => '\x08',
+        Escape => '\x1b',
+        KeypadSlash => '/',
+        KeypadStar => '*',
+        KeypadMinus => '-',
+        KeypadPlus => '+',
+        Keypad0 if num_lock => '0',
+        Keypad1 if num_lock => '1',
+        Keypad2 if num_lock => '2',
+        Keypad3 if num_lock => '3',
+        Keypad4 if num_lock => '4',
+        Keypad5 if num_lock => '5',
+        Keypad6 if num_lock => '6',
+        Keypad7 if num_lock => '7',
+        Keypad8 if num_lock => '8',
+        Keypad9 if num_lock => '9',
+        KeypadPeriod if num_lock => '.',
+        _ => return None,
+    })
+}
+
+fn us(key: KeyCode) -> Option<Chars> {
+    use KeyCode::*;
+    Some(match key {
+        Backtick => keys('`', '~'),
+        Key1 => keys('1', '!'),
+        Key2 => keys('2', '@'),
+        Key3 => keys('3', '#'),
+        Key4 => keys('4', '$'),
+        Key5 => keys('5', '%'),
+        Key6 => keys('6', '^'),
+        Key7 => keys('7', '&'),
+        Key8 => keys('8', '*'),
+        Key9 => keys('9', '('),
+        Key0 => keys('0', ')'),
+        Minus => keys('-', '_'),
+        Equals => keys('=', '+'),
+        Q => keys('q', 'Q'),
+        W => keys('w', 'W'),
+        E => keys('e', 'E'),
+        R => keys('r', 'R'),
+        T => keys('t', 'T'),
+        Y => keys('y', 'Y'),
+        U => keys('u', 'U'),
+        I => keys('i', 'I'),
+        O => keys('o', 'O'),
+        P => keys('p', 'P'),
+        LeftBracket => keys('[', '{'),
+        RightBracket => keys(']', '}'),
+        Backslash => keys('\\', '|'),
+        A => keys('a', 'A'),
+        S => keys('s', 'S'),
+        D => keys('d', 'D'),
+        F => keys('f', 'F'),
+        G => keys('g', 'G'),
+        H => keys('h', 'H'),
+        J => keys('j', 'J'),
+        K => keys('k', 'K'),
+        L => keys('l', 'L'),
+        Semicolon => keys(';', ':'),
+        Quote => keys('\'', '"'),
+        NonUsBackslash => keys('\\', '|'),
+        Z => keys('z', 'Z'),
+        X => keys('x', 'X'),
+        C => keys('c', 'C'),
+        V => keys('v', 'V'),
+        B => keys('b', 'B'),
+        N => keys('n', 'N'),
+        M => keys('m', 'M'),
+        Comma => keys(',', '<'),
+        Period => keys('.', '>'),
+        Slash => keys('/', '?'),
+        _ => return None,
+    })
+}
+
+fn french(key: KeyCode) -> Option<Chars> {
+    use KeyCode::*;
+    Some(match key {
+        Backtick => ('²', None, None),
+        Key1 => keys('&', '1'),
+        Key2 => keys3('é', '2', '~'),
+        Key3 => keys3('"', '3', '#'),
+        Key4 => keys3('\'', '4', '{'),
+        Key5 => keys3('(', '5', '['),
+        Key6 => keys3('-', '6', '|'),
+        Key7 => keys3('è', '7', '`'),
+        Key8 => keys3('_', '8', '\\'),
+        Key9 => keys3('ç', '9', '^'),
+        Key0 => keys3('à', '0', '@'),
+        Minus => keys3(')', '°', ']'),
+        Equals => keys3('=', '+', '}'),
+        Q => keys('a', 'A'),
+        W => keys('z', 'Z'),
+        E => keys3('e', 'E', '€'),
+        R => keys('r', 'R'),
+        T => keys('t', 'T'),
+        Y => keys('y', 'Y'),
+        U => keys('u', 'U'),
+        I => keys('i', 'I'),
+        O => keys('o', 'O'),
+        P => keys('p', 'P'),
+        LeftBracket => keys('^', '¨'),
+        RightBracket => keys3('$', '£', '¤'),
+        A => keys('q', 'Q'),
+        S => keys('s', 'S'),
+        D => keys('d', 'D'),
+        F => keys('f', 'F'),
+        G => keys('g', 'G'),
+        H => keys('h', 'H'),
+        J => keys('j', 'J'),
+        K => keys('k', 'K'),
+        L => keys('l', 'L'),
+        Semicolon => keys('m', 'M'),
+        Quote => keys('ù', '%'),
+        Backslash => keys('*', 'µ'),
+        NonUsBackslash => keys('<', '>'),
+        Z => keys('w', 'W'),
+        X => keys('x', 'X'),
+        C => keys('c', 'C'),
+        V => keys('v', 'V'),
+        B => keys('b', 'B'),
+        N => keys('n', 'N'),
+        M => keys(',', '?'),
+        Comma => keys(';', '.'),
+        Period => keys(':', '/'),
+        Slash => keys('!', '§'),
+        _ => return None,
+    })
+}
+
+impl Layout {
+    /// Character typed by pressing `key` with `modifiers` held.
+    pub fn map(self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        if let Some(char) = common(key, modifiers) {
+            return Some(char);
+        }
+
+        let (normal, shifted, altgr) = match self {
+            Layout::Us => us(key),
+            Layout::French => french(key),
+        }?;
+
+        if modifiers.altgr() {
+            return altgr;
+        }
+
+        // Caps lock works like shift, but only for letters.
+        let caps = modifiers.contains(Modifiers::CAPS_LOCK) && normal.is_ascii_alphabetic();
+        if modifiers.shift() != caps {
+            shifted
+        } else {
+            Some(normal)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn french_is_azerty() {
+        let none = Modifiers::empty();
+        assert_eq!(Layout::French.map(KeyCode::Q, none), Some('a'));
+        assert_eq!(Layout::French.map(KeyCode::Key2, none), Some('é'));
+        assert_eq!(
+            Layout::French.map(KeyCode::Key2, Modifiers::LEFT_SHIFT),
+            Some('2')
+        );
+        assert_eq!(
+            Layout::French.map(KeyCode::Key0, Modifiers::RIGHT_ALT),
+            Some('@')
+        );
+    }
+
+    #[test_case]
+    fn caps_lock_only_affects_letters() {
+        let caps = Modifiers::CAPS_LOCK;
+        assert_eq!(Layout::Us.map(KeyCode::A, caps), Some('A'));
+        assert_eq!(Layout::Us.map(KeyCode::Key1, caps), Some('1'));
+        assert_eq!(
+            Layout::Us.map(KeyCode::A, caps | Modifiers::LEFT_SHIFT),
+            Some('a')
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Intel 8042 PS/2 controller.
+//! https://wiki.osdev.org/%228042%22_PS/2_Controller
+//!
+//! The controller has two ports: the first is normally wired to the
+//! keyboard (IRQ 1), the second, auxiliary one to the mouse (IRQ 12).
+//! Both share a single data register.
+
+pub mod keyboard;
+pub mod layout;
+pub mod mouse;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bitflags::bitflags;
+
+use crate::io::{inb, outb};
+
+const DATA: u16 = 0x60;
+/// Status register when read, command register when written.
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+bitflags! {
+    struct Status: u8 {
+        /// Data can be read from `DATA`.
+        const OUTPUT_FULL = 1 << 0;
+        /// The controller hasn't consumed the last byte written yet.
+        const INPUT_FULL = 1 << 1;
+        /// Time-out or parity error.
+        const ERROR = (1 << 6) | (1 << 7);
+    }
+}
+
+bitflags! {
+    /// Controller Configuration Byte
+    struct Config: u8 {
+        const FIRST_PORT_INTERRUPT = 1 << 0;
+        const SECOND_PORT_INTERRUPT = 1 << 1;
+        const FIRST_PORT_CLOCK_DISABLED = 1 << 4;
+        const SECOND_PORT_CLOCK_DISABLED = 1 << 5;
+        /// Translate scancode set 2 to set 1 on the first port.
+        const FIRST_PORT_TRANSLATION = 1 << 6;
+    }
+}
+
+// Controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const ENABLE_SECOND_PORT: u8 = 0xa8;
+const TEST_SECOND_PORT: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST_PORT: u8 = 0xab;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+/// Send the next data byte to the second port instead of the first.
+const WRITE_SECOND_PORT: u8 = 0xd4;
+/// Pulse the CPU reset line.
+const PULSE_RESET: u8 = 0xfe;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Device replies
+pub const ACK: u8 = 0xfa;
+pub const RESEND: u8 = 0xfe;
+
+/// How many status polls before giving up on the controller.
+const TIMEOUT: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    First,
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller or device didn't answer in time.
+    Timeout,
+    /// The controller self test returned this instead of 0x55.
+    SelfTest(u8),
+    /// The interface test of a port returned this error code.
+    PortTest(Port, u8),
+    /// The device answered a command with this instead of ACK.
+    NoAck(u8),
+    /// The controller only has one port.
+    NoSecondPort,
+}
+
+/// Set once `init` found a working second port.
+static DUAL_CHANNEL: AtomicBool = AtomicBool::new(false);
+
+fn status() -> Status {
+    Status::from_bits_truncate(unsafe { inb(STATUS) })
+}
+
+fn wait_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT {
+        if !status().contains(Status::INPUT_FULL) {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn wait_output_full() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT {
+        if status().contains(Status::OUTPUT_FULL) {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn command(cmd: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    unsafe { outb(COMMAND, cmd) };
+    Ok(())
+}
+
+fn command_with_reply(cmd: u8) -> Result<u8, Ps2Error> {
+    command(cmd)?;
+    read_data()
+}
+
+/// Wait for a byte from the controller or a device.
+pub fn read_data() -> Result<u8, Ps2Error> {
+    wait_output_full()?;
+    Ok(unsafe { inb(DATA) })
+}
+
+/// Read the pending byte, from an interrupt handler: the interrupt tells us
+/// there is one, so we don't wait.
+pub fn read_data_now() -> u8 {
+    unsafe { inb(DATA) }
+}
+
+/// Send a byte to the device on `port`.
+pub fn write_data(port: Port, byte: u8) -> Result<(), Ps2Error> {
+    if port == Port::Second {
+        command(WRITE_SECOND_PORT)?;
+    }
+    wait_input_empty()?;
+    unsafe { outb(DATA, byte) };
+    Ok(())
+}
+
+/// Send a command byte to the device on `port` and wait for its ACK,
+/// resending it a few times if asked to.
+/// Must be called with the port's IRQ masked, or the handler would eat the
+/// reply.
+pub fn device_command(port: Port, byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..3 {
+        write_data(port, byte)?;
+        match read_data()? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            reply => return Err(Ps2Error::NoAck(reply)),
+        }
+    }
+    Err(Ps2Error::NoAck(RESEND))
+}
+
+/// Reset the machine through the controller's reset line. Only returns if
+/// the controller didn't do it.
+pub fn reset_system() -> Result<(), Ps2Error> {
+    command(PULSE_RESET)?;
+    for _ in 0..TIMEOUT {
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn read_config() -> Result<Config, Ps2Error> {
+    Ok(Config::from_bits_truncate(command_with_reply(READ_CONFIG)?))
+}
+
+fn write_config(config: Config) -> Result<(), Ps2Error> {
+    command(WRITE_CONFIG)?;
+    wait_input_empty()?;
+    unsafe { outb(DATA, config.bits()) };
+    Ok(())
+}
+
+fn update_config(f: impl FnOnce(&mut Config)) -> Result<(), Ps2Error> {
+    let mut config = read_config()?;
+    f(&mut config);
+    write_config(config)
+}
+
+/// Turn on translation of scancode set 2 to set 1 by the controller.
+pub fn set_translation(enabled: bool) -> Result<(), Ps2Error> {
+    update_config(|config| config.set(Config::FIRST_PORT_TRANSLATION, enabled))
+}
+
+/// Let the device on `port` raise its IRQ.
+pub fn enable_interrupt(port: Port) -> Result<(), Ps2Error> {
+    update_config(|config| match port {
+        Port::First => config.insert(Config::FIRST_PORT_INTERRUPT),
+        Port::Second => config.insert(Config::SECOND_PORT_INTERRUPT),
+    })
+}
+
+pub fn has_second_port() -> bool {
+    DUAL_CHANNEL.load(Ordering::Relaxed)
+}
+
+/// Reset the controller to a known state and enable its ports.
+/// Interrupts of both ports are left disabled: the device drivers enable
+/// them once they have set their device up.
+fn init_controller() -> Result<(), Ps2Error> {
+    command(DISABLE_FIRST_PORT)?;
+    command(DISABLE_SECOND_PORT)?;
+    // Flush stale output
+    while status().contains(Status::OUTPUT_FULL) {
+        unsafe { inb(DATA) };
+    }
+
+    let mut config = read_config()?;
+    config.remove(
+        Config::FIRST_PORT_INTERRUPT
+            | Config::SECOND_PORT_INTERRUPT
+            | Config::FIRST_PORT_TRANSLATION,
+    );
+    write_config(config)?;
+
+    let result = command_with_reply(SELF_TEST)?;
+    if result != SELF_TEST_PASSED {
+        return Err(Ps2Error::SelfTest(result));
+    }
+    // The self test may reset the configuration.
+    write_config(config)?;
+
+    // If the second port exists, enabling it clears its clock-disabled bit.
+    let mut dual_channel = false;
+    if config.contains(Config::SECOND_PORT_CLOCK_DISABLED) {
+        command(ENABLE_SECOND_PORT)?;
+        dual_channel = !read_config()?.contains(Config::SECOND_PORT_CLOCK_DISABLED);
+        command(DISABLE_SECOND_PORT)?;
+    }
+
+    let result = command_with_reply(TEST_FIRST_PORT)?;
+    if result != PORT_TEST_PASSED {
+        return Err(Ps2Error::PortTest(Port::First, result));
+    }
+    if dual_channel {
+        let result = command_with_reply(TEST_SECOND_PORT)?;
+        if result != PORT_TEST_PASSED {
+            warn!("PS/2 second port test failed: {:#x}", result);
+            dual_channel = false;
+        }
+    }
+
+    command(ENABLE_FIRST_PORT)?;
+    if dual_channel {
+        command(ENABLE_SECOND_PORT)?;
+    }
+    DUAL_CHANNEL.store(dual_channel, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Initialize the controller, then the devices behind it.
+/// Requires interrupts to be initialized.
+pub fn init() {
+    if let Err(err) = init_controller() {
+        warn!("No usable PS/2 controller: {:?}", err);
+        return;
+    }
+    debug!("PS/2 controller ready, dual channel: {}", has_second_port());
+
+    if let Err(err) = keyboard::init() {
+        warn!("PS/2 keyboard init failed: {:?}", err);
+    }
+    if has_second_port() {
+        if let Err(err) = mouse::init() {
+            warn!("PS/2 mouse init failed: {:?}", err);
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! PS/2 mouse driver, on the controller's second port.
+//! https://wiki.osdev.org/PS/2_Mouse
+//!
+//! Supports the standard 3-byte protocol, and the 4-byte IntelliMouse
+//! extensions adding a wheel and buttons 4 and 5.
+
+use spin::Mutex;
+
+use crate::interrupts::{self, ExceptionStackFrame};
+use crate::ps2::{self, Port, Ps2Error};
+use crate::random;
+use crate::ring::RingBuffer;
+use crate::x86;
+
+/// Device commands
+const GET_ID: u8 = 0xf2;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const ENABLE_REPORTING: u8 = 0xf4;
+const DISABLE_REPORTING: u8 = 0xf5;
+const SET_DEFAULTS: u8 = 0xf6;
+
+/// What the mouse answered to GET_ID, after enabling extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseType {
+    /// ID 0: 3 buttons, 3-byte packets
+    Standard,
+    /// ID 3: adds a wheel, 4-byte packets
+    Wheel,
+    /// ID 4: adds a wheel and buttons 4 and 5, 4-byte packets
+    FiveButton,
+}
+
+impl MouseType {
+    fn packet_size(self) -> usize {
+        match self {
+            MouseType::Standard => 3,
+            MouseType::Wheel | MouseType::FiveButton => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Left,
+    Right,
+    Middle,
+    Fourth,
+    Fifth,
+}
+
+impl Button {
+    const ALL: [Button; 5] = [
+        Button::Left,
+        Button::Right,
+        Button::Middle,
+        Button::Fourth,
+        Button::Fifth,
+    ];
+
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent {
+    /// Relative motion. Positive `dy` is up, as reported by the mouse.
+    Motion {
+        dx: i16,
+        dy: i16,
+    },
+    /// Positive is towards the user.
+    Wheel(i8),
+    ButtonPressed(Button),
+    ButtonReleased(Button),
+}
+
+// First packet byte
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const MIDDLE: u8 = 1 << 2;
+/// Always set, used to find packet boundaries.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// Assembles packets and turns them into events.
+#[derive(Debug)]
+pub struct PacketDecoder {
+    mouse_type: MouseType,
+    packet: [u8; 4],
+    len: usize,
+    /// Buttons held after the last packet, indexed by `Button::mask`.
+    buttons: u8,
+}
+
+impl PacketDecoder {
+    pub const fn new(mouse_type: MouseType) -> Self {
+        Self {
+            mouse_type,
+            packet: [0; 4],
+            len: 0,
+            buttons: 0,
+        }
+    }
+
+    /// Feed one byte. Once a packet is complete, `emit` is called with each
+    /// event it contains.
+    pub fn feed(&mut self, byte: u8, mut emit: impl FnMut(MouseEvent)) {
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            // Out of sync: this can't be the first byte of a packet.
+            return;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < self.mouse_type.packet_size() {
+            return;
+        }
+        self.len = 0;
+
+        let flags = self.packet[0];
+        // Motion is a 9-bit two's complement value, the sign bit being in
+        // the first byte.
+        let dx = self.packet[1] as i16 - (((flags & X_SIGN) as i16) << 4);
+        let dy = self.packet[2] as i16 - (((flags & Y_SIGN) as i16) << 3);
+        // Overflowed values are meaningless.
+        if flags & (X_OVERFLOW | Y_OVERFLOW) == 0 && (dx != 0 || dy != 0) {
+            emit(MouseEvent::Motion { dx, dy });
+        }
+
+        let mut buttons = flags & (LEFT | RIGHT | MIDDLE);
+        match self.mouse_type {
+            MouseType::Standard => {}
+            MouseType::Wheel => {
+                let dz = self.packet[3] as i8;
+                if dz != 0 {
+                    emit(MouseEvent::Wheel(dz));
+                }
+            }
+            MouseType::FiveButton => {
+                // Low nibble is a 4-bit signed value.
+                let dz = ((self.packet[3] << 4) as i8) >> 4;
+                if dz != 0 {
+                    emit(MouseEvent::Wheel(dz));
+                }
+                buttons |= (self.packet[3] >> 1) & (Button::Fourth.mask() | Button::Fifth.mask());
+            }
+        }
+
+        let changed = buttons ^ self.buttons;
+        for button in Button::ALL {
+            if changed & button.mask() != 0 {
+                if buttons & button.mask() != 0 {
+                    emit(MouseEvent::ButtonPressed(button));
+                } else {
+                    emit(MouseEvent::ButtonReleased(button));
+                }
+            }
+        }
+        self.buttons = buttons;
+    }
+}
+
+const IRQ: u8 = 12;
+
+static DECODER: Mutex<PacketDecoder> = Mutex::new(PacketDecoder::new(MouseType::Standard));
+
+/// Decoded events, oldest first.
+/// Only lock with interrupts disabled, or the handler could deadlock.
+static EVENTS: Mutex<RingBuffer<MouseEvent, 256>> = Mutex::new(RingBuffer::new());
+
+static MOUSE_TYPE: Mutex<Option<MouseType>> = Mutex::new(None);
+
+extern "x86-interrupt" fn mouse_handler(_frame: ExceptionStackFrame) {
+    let byte = ps2::read_data_now();
+    random::add_interrupt_randomness(byte as u64);
+    let mut events = EVENTS.lock();
+    DECODER.lock().feed(byte, |event| {
+        // Drop events when nobody reads them.
+        let _ = events.push(event);
+    });
+    drop(events);
+    interrupts::end_of_interrupt(IRQ);
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), Ps2Error> {
+    ps2::device_command(Port::Second, SET_SAMPLE_RATE)?;
+    ps2::device_command(Port::Second, rate)
+}
+
+fn get_id() -> Result<u8, Ps2Error> {
+    ps2::device_command(Port::Second, GET_ID)?;
+    ps2::read_data()
+}
+
+/// IntelliMouse extensions are unlocked by magic sequences of sample rates,
+/// after which the mouse reports a different ID.
+fn detect_type() -> Result<MouseType, Ps2Error> {
+    for rate in [200, 100, 80] {
+        set_sample_rate(rate)?;
+    }
+    if get_id()? != 3 {
+        return Ok(MouseType::Standard);
+    }
+
+    for rate in [200, 200, 80] {
+        set_sample_rate(rate)?;
+    }
+    if get_id()? == 4 {
+        Ok(MouseType::FiveButton)
+    } else {
+        Ok(MouseType::Wheel)
+    }
+}
+
+/// Set the mouse up and start receiving events.
+pub fn init() -> Result<MouseType, Ps2Error> {
+    if !ps2::has_second_port() {
+        return Err(Ps2Error::NoSecondPort);
+    }
+
+    ps2::device_command(Port::Second, DISABLE_REPORTING)?;
+    ps2::device_command(Port::Second, SET_DEFAULTS)?;
+    let mouse_type = detect_type()?;
+    // Detection changed the sample rate, go back to the default.
+    set_sample_rate(100)?;
+    debug!("PS/2 mouse: {:?}", mouse_type);
+
+    *DECODER.lock() = PacketDecoder::new(mouse_type);
+    *MOUSE_TYPE.lock() = Some(mouse_type);
+    ps2::device_command(Port::Second, ENABLE_REPORTING)?;
+
+    interrupts::set_irq_handler(IRQ, mouse_handler);
+    ps2::enable_interrupt(Port::Second)?;
+    Ok(mouse_type)
+}
+
+/// The mouse found by `init`, if any.
+pub fn mouse_type() -> Option<MouseType> {
+    *MOUSE_TYPE.lock()
+}
+
+/// Return the next mouse event if there is one, without blocking.
+pub fn next_event() -> Option<MouseEvent> {
+    x86::without_interrupts(|| EVENTS.lock().pop())
+}
+
+/// Wait for the next mouse event.
+pub fn wait_event() -> MouseEvent {
+    loop {
+        x86::cli();
+        if let Some(event) = EVENTS.lock().pop() {
+            x86::sti();
+            return event;
+        }
+        x86::sti_hlt();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(decoder: &mut PacketDecoder, bytes: &[u8]) -> ([Option<MouseEvent>; 4], usize) {
+        let mut events = [None; 4];
+        let mut len = 0;
+        for &byte in bytes {
+            decoder.feed(byte, |event| {
+                events[len] = Some(event);
+                len += 1;
+            });
+        }
+        (events, len)
+    }
+
+    #[test_case]
+    fn negative_motion_and_button() {
+        let mut decoder = PacketDecoder::new(MouseType::Standard);
+        // Left button, dx = -1, dy = 2
+        let (events, len) = decode(&mut decoder, &[ALWAYS_ONE | X_SIGN | LEFT, 0xff, 2]);
+        assert_eq!(len, 2);
+        assert_eq!(events[0], Some(MouseEvent::Motion { dx: -1, dy: 2 }));
+        assert_eq!(events[1], Some(MouseEvent::ButtonPressed(Button::Left)));
+
+        let (events, len) = decode(&mut decoder, &[ALWAYS_ONE, 0, 0]);
+        assert_eq!(len, 1);
+        assert_eq!(events[0], Some(MouseEvent::ButtonReleased(Button::Left)));
+    }
+
+    #[test_case]
+    fn wheel_packet() {
+        let mut decoder = PacketDecoder::new(MouseType::Wheel);
+        let (events, len) = decode(&mut decoder, &[ALWAYS_ONE, 0, 0, 0xff]);
+        assert_eq!(len, 1);
+        assert_eq!(events[0], Some(MouseEvent::Wheel(-1)));
+    }
+
+    #[test_case]
+    fn resyncs_on_bad_first_byte() {
+        let mut decoder = PacketDecoder::new(MouseType::Standard);
+        let (events, len) = decode(&mut decoder, &[0x00, ALWAYS_ONE | MIDDLE, 0, 0]);
+        assert_eq!(len, 1);
+        assert_eq!(events[0], Some(MouseEvent::ButtonPressed(Button::Middle)));
+    }
+
+    #[test_case]
+    fn qemu_mouse_is_detected() {
+        // QEMU emulates an IntelliMouse Explorer.
+        assert!(mouse_type().is_some());
+    }
+}
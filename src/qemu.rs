@@ -0,0 +1,25 @@
+//! QEMU specific devices.
+
+use crate::x86::Port;
+
+/// Exit codes returned by QEMU.
+/// Note that when exiting, QEMU will shift the exit code:
+/// (exit << 1) | 1
+/// so the value returned to the shell will NOT be these constants!
+#[repr(u32)]
+pub enum ExitCode {
+    // Returned value: 33 (0x10 << 1) | 1
+    Success = 0x10,
+    // Returned value: 35 (0x11 << 1) | 1
+    Failure = 0x11,
+}
+
+pub fn exit_qemu(exit_code: ExitCode) {
+    // Write to our configured debug exit isa device:
+    // the -device isa-debug-exit,iobase=0xf4,iosize=0x04 argument to qemu,
+    // which will exit QEMU.
+    // Outside of QEMU, or without the device, nothing happens.
+    unsafe {
+        Port::<u32>::new(0xf4).write(exit_code as u32);
+    }
+}
@@ -0,0 +1,220 @@
+//! Kernel random numbers.
+//!
+//! Entropy from RDSEED/RDRAND, TSC jitter and interrupt timing is mixed into
+//! a pool, which keys a ChaCha20 generator. The key is replaced after every
+//! request ("fast key erasure"), so past output can't be recovered from the
+//! state.
+//! https://blog.cr.yp.to/20170723-random.html
+
+mod chacha;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::x86::{self, cpuid, cpuid::Features};
+
+/// Words of gathered entropy, folded into the key on reseed.
+const INPUT_WORDS: usize = 8;
+
+/// TSC jitter samples taken at boot.
+const BOOT_JITTER_SAMPLES: usize = 256;
+
+/// How many times to retry RDSEED/RDRAND when they're out of entropy.
+const HARDWARE_RETRIES: usize = 10;
+
+struct Pool {
+    key: [u32; 8],
+    input: [u32; INPUT_WORDS],
+    /// Number of words mixed into `input` since the last reseed.
+    input_len: usize,
+    /// Nonce of the next request, so requests never reuse a keystream.
+    requests: u64,
+}
+
+impl Pool {
+    const fn new() -> Self {
+        Self {
+            key: [0; 8],
+            input: [0; INPUT_WORDS],
+            input_len: 0,
+            requests: 0,
+        }
+    }
+
+    fn mix(&mut self, value: u64) {
+        for half in [value as u32, (value >> 32) as u32] {
+            let word = &mut self.input[self.input_len % INPUT_WORDS];
+            *word = word.rotate_left(7) ^ half;
+            self.input_len += 1;
+        }
+    }
+
+    /// Fold the gathered entropy into the key.
+    fn reseed(&mut self) {
+        if self.input_len == 0 {
+            return;
+        }
+        for (key, input) in self.key.iter_mut().zip(self.input) {
+            *key ^= input;
+        }
+        // One-way, so the new key doesn't reveal the inputs.
+        let block = chacha::block(&self.key, 0, &[u32::MAX; 3]);
+        self.key.copy_from_slice(&block[..8]);
+        self.input = [0; INPUT_WORDS];
+        self.input_len = 0;
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        let nonce = [self.requests as u32, (self.requests >> 32) as u32, 0];
+        self.requests += 1;
+
+        // Block 0 becomes the next key, the output starts at block 1.
+        for (counter, chunk) in buf.chunks_mut(chacha::BLOCK_BYTES).enumerate() {
+            let block = chacha::block(&self.key, counter as u32 + 1, &nonce);
+            for (bytes, word) in chunk.chunks_mut(4).zip(block) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        let block = chacha::block(&self.key, 0, &nonce);
+        self.key.copy_from_slice(&block[..8]);
+    }
+}
+
+/// Only lock with interrupts disabled, so interrupt handlers can ask for
+/// random bytes too.
+static POOL: Mutex<Pool> = Mutex::new(Pool::new());
+
+/// Interrupt timings, accumulated without locking.
+static INTERRUPT_ENTROPY: AtomicU64 = AtomicU64::new(0);
+
+static SEEDED: AtomicBool = AtomicBool::new(false);
+
+/// Mix the time of an interrupt into the pool. Cheap enough to call from
+/// any interrupt handler.
+pub fn add_interrupt_randomness(source: u64) {
+    let sample = x86::rdtsc() ^ source.rotate_left(32);
+    // A lost race just loses a sample.
+    let old = INTERRUPT_ENTROPY.load(Ordering::Relaxed);
+    INTERRUPT_ENTROPY.store(old.rotate_left(13) ^ sample, Ordering::Relaxed);
+}
+
+/// Mix arbitrary data into the pool. It isn't assumed to be secret.
+pub fn add_randomness(data: &[u8]) {
+    x86::without_interrupts(|| {
+        let mut pool = POOL.lock();
+        for chunk in data.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            pool.mix(u64::from_le_bytes(word));
+        }
+    });
+}
+
+/// A word from the CPU's random number generator, if it has one.
+fn hardware_random() -> Option<u64> {
+    if cpuid::has(Features::RDSEED) {
+        (0..HARDWARE_RETRIES).find_map(|_| x86::rdseed())
+    } else if cpuid::has(Features::RDRAND) {
+        (0..HARDWARE_RETRIES).find_map(|_| x86::rdrand())
+    } else {
+        None
+    }
+}
+
+/// Time a small, data dependent workload. The low bits vary with cache,
+/// pipeline and bus state.
+fn jitter_sample() -> u64 {
+    let start = x86::rdtsc();
+    let mut value = start;
+    for _ in 0..(start & 0x3f) {
+        value = core::hint::black_box(value.rotate_left(5) ^ 0x9e37_79b9_7f4a_7c15);
+    }
+    x86::rdtsc().wrapping_sub(start) ^ value
+}
+
+/// Seed the pool. Requires CPUID detection and the timers.
+pub fn init() {
+    let hardware = x86::without_interrupts(|| {
+        let mut pool = POOL.lock();
+        let mut hardware = 0;
+        for _ in 0..INPUT_WORDS {
+            if let Some(value) = hardware_random() {
+                pool.mix(value);
+                hardware += 1;
+            }
+        }
+        for _ in 0..BOOT_JITTER_SAMPLES {
+            pool.mix(jitter_sample());
+        }
+        pool.mix(crate::time::wall_clock().unix.as_secs());
+        pool.mix(INTERRUPT_ENTROPY.swap(0, Ordering::Relaxed));
+        pool.reseed();
+        hardware
+    });
+    SEEDED.store(true, Ordering::Relaxed);
+
+    if hardware == 0 {
+        warn!("No hardware RNG, randomness relies on timing jitter");
+    } else {
+        debug!("Random pool seeded, with {} hardware words", hardware);
+    }
+}
+
+/// Fill `buf` with random bytes.
+pub fn get_random_bytes(buf: &mut [u8]) {
+    if !SEEDED.load(Ordering::Relaxed) {
+        warn!("Random bytes requested before the pool was seeded");
+    }
+    let hardware = hardware_random();
+    x86::without_interrupts(|| {
+        let mut pool = POOL.lock();
+        pool.mix(INTERRUPT_ENTROPY.swap(0, Ordering::Relaxed));
+        pool.mix(jitter_sample());
+        if let Some(value) = hardware {
+            pool.mix(value);
+        }
+        pool.reseed();
+        pool.fill(buf);
+    });
+}
+
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    get_random_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn key_is_erased_after_fill() {
+        let mut pool = Pool::new();
+        pool.mix(42);
+        pool.reseed();
+        let key = pool.key;
+        let mut first = [0; 100];
+        pool.fill(&mut first);
+        assert_ne!(pool.key, key);
+        assert!(first.iter().any(|&b| b != 0));
+
+        // Same state, same output: only entropy makes it unpredictable.
+        let mut replay = Pool::new();
+        replay.mix(42);
+        replay.reseed();
+        let mut second = [0; 100];
+        replay.fill(&mut second);
+        assert_eq!(first, second);
+    }
+
+    #[test_case]
+    fn outputs_differ() {
+        let mut a = [0; 32];
+        let mut b = [0; 32];
+        get_random_bytes(&mut a);
+        get_random_bytes(&mut b);
+        assert_ne!(a, b);
+    }
+}
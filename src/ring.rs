@@ -0,0 +1,129 @@
+//! Fixed-capacity ring buffer.
+//! We have no heap, so every queue in the kernel (log history, UART buffers,
+//! input events...) is one of these, sized at compile time.
+
+use core::mem::MaybeUninit;
+
+pub struct RingBuffer<T: Copy, const N: usize> {
+    buf: [MaybeUninit<T>; N],
+    /// Index of the oldest element.
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            // An array of MaybeUninit does not need initialization.
+            buf: unsafe { MaybeUninit::uninit().assume_init() },
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Append an element, giving it back if the buffer is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.buf[(self.head + self.len) % N] = MaybeUninit::new(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Append an element, discarding the oldest one if the buffer is full.
+    pub fn push_overwrite(&mut self, value: T) {
+        if self.is_full() {
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+        }
+        // Can't fail, we just made room.
+        let _ = self.push(value);
+    }
+
+    /// Remove and return the oldest element.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let value = unsafe { self.buf[self.head].assume_init() };
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Return the oldest element without removing it.
+    pub fn peek(&self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(unsafe { self.buf[self.head].assume_init() })
+        }
+    }
+
+    /// Iterate from the oldest to the newest element.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.len).map(move |i| unsafe { self.buf[(self.head + i) % N].assume_init() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn push_pop_is_fifo() {
+        let mut ring = RingBuffer::<u8, 4>::new();
+        assert!(ring.is_empty());
+        ring.push(1).unwrap();
+        ring.push(2).unwrap();
+        ring.push(3).unwrap();
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), Some(3));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test_case]
+    fn push_fails_when_full() {
+        let mut ring = RingBuffer::<u8, 2>::new();
+        ring.push(1).unwrap();
+        ring.push(2).unwrap();
+        assert!(ring.is_full());
+        assert_eq!(ring.push(3), Err(3));
+    }
+
+    #[test_case]
+    fn push_overwrite_drops_oldest() {
+        let mut ring = RingBuffer::<u8, 3>::new();
+        for i in 0..5 {
+            ring.push_overwrite(i);
+        }
+        let mut contents = [0; 3];
+        for (slot, value) in contents.iter_mut().zip(ring.iter()) {
+            *slot = value;
+        }
+        assert_eq!(contents, [2, 3, 4]);
+    }
+}
@@ -1,64 +1,412 @@
 //! Serial port implementation (uart_16650).
+//! Supports the four legacy COM ports, each with its own line settings.
 
+use crate::interrupts::{self, ExceptionStackFrame};
 use crate::io::{inb, outb};
+use crate::ring::RingBuffer;
+use crate::x86;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-#[repr(transparent)]
-pub struct Serial();
+/// Number of data bits per character (LCR bits 0-1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DataBits {
+    Five = 0b00,
+    Six = 0b01,
+    Seven = 0b10,
+    Eight = 0b11,
+}
+
+/// Parity (LCR bits 3-5).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Parity {
+    None = 0b000 << 3,
+    Odd = 0b001 << 3,
+    Even = 0b011 << 3,
+    Mark = 0b101 << 3,
+    Space = 0b111 << 3,
+}
+
+/// Number of stop bits (LCR bit 2).
+/// `Two` means 1.5 stop bits with 5-bit characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StopBits {
+    One = 0 << 2,
+    Two = 1 << 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    /// The UART clock is 115200 Hz: the baud rate is `115200 / divisor`.
+    pub baud_divisor: u16,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl SerialConfig {
+    /// 115200 baud, 8N1.
+    pub const DEFAULT: Self = SerialConfig {
+        baud_divisor: 1,
+        data_bits: DataBits::Eight,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+    };
+
+    /// Divisor for the closest achievable rate at or above `baud`, or None
+    /// if `baud` is 0.
+    pub const fn divisor_for(baud: u32) -> Option<u16> {
+        if baud == 0 {
+            return None;
+        }
+        let divisor = 115200 / baud;
+        if divisor == 0 {
+            Some(1)
+        } else if divisor > u16::MAX as u32 {
+            Some(u16::MAX)
+        } else {
+            Some(divisor as u16)
+        }
+    }
+
+    fn line_control(&self) -> u8 {
+        self.data_bits as u8 | self.stop_bits as u8 | self.parity as u8
+    }
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig::DEFAULT
+    }
+}
+
+/// The four legacy PC serial ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1 = 0,
+    Com2 = 1,
+    Com3 = 2,
+    Com4 = 3,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// Conventional I/O port base, as assigned by most BIOSes.
+    pub const fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    /// COM1 and COM3 share IRQ 4, COM2 and COM4 share IRQ 3.
+    pub const fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    pub fn serial(self) -> &'static Mutex<Serial> {
+        match self {
+            ComPort::Com1 => &COM1,
+            ComPort::Com2 => &COM2,
+            ComPort::Com3 => &COM3,
+            ComPort::Com4 => &COM4,
+        }
+    }
+
+    fn rx_buffer(self) -> &'static Mutex<RxBuffer> {
+        &RX_BUFFERS[self as usize]
+    }
+
+    fn tx_buffer(self) -> &'static Mutex<TxBuffer> {
+        &TX_BUFFERS[self as usize]
+    }
+}
+
+pub struct Serial {
+    base: u16,
+    /// Whether a working UART answers at `base`.
+    /// If not, transmitting is a no-op instead of waiting forever for the
+    /// transmitter to be ready.
+    present: bool,
+    /// Bytes waiting to be sent by the transmit interrupt handler.
+    /// Until set, every byte is sent synchronously.
+    tx_buffer: Option<&'static Mutex<TxBuffer>>,
+}
 
 impl Serial {
     // https://en.wikibooks.org/wiki/Serial_Programming/8250_UART_Programming#Software_interrupts
+    // Register offsets from the base port.
 
-    /// Base port (also Transmitter Holding Buffer/Register)
-    const PORT: u16 = 0x3f8;
-    /// Interrupt Enable Register
-    const IER: u16 = Serial::PORT + 1;
+    /// Transmitter Holding Buffer/Receiver Buffer, or divisor low byte if DLAB is set
+    const DATA: u16 = 0;
+    /// Interrupt Enable Register, or divisor high byte if DLAB is set
+    const IER: u16 = 1;
     /// Fifo Control Register
-    const FCR: u16 = Serial::PORT + 2;
+    const FCR: u16 = 2;
     /// Line Control Register
-    const LCR: u16 = Serial::PORT + 3;
+    const LCR: u16 = 3;
     /// Modem Control Register
-    const MCR: u16 = Serial::PORT + 4;
+    const MCR: u16 = 4;
     /// Line Status Register
-    const LSR: u16 = Serial::PORT + 5;
+    const LSR: u16 = 5;
+    /// Scratch Register
+    const SCR: u16 = 7;
+
+    /// Received Data Available interrupt
+    const IER_RX: u8 = 1 << 0;
+    /// Transmitter Holding Register Empty interrupt
+    const IER_THRE: u8 = 1 << 1;
+    /// Divisor Latch Access Bit
+    const LCR_DLAB: u8 = 1 << 7;
+    /// Data Ready: a byte can be read.
+    const LSR_DR: u8 = 1 << 0;
+    /// Transmitter Holding Register Empty: the transmit FIFO is empty.
+    const LSR_THRE: u8 = 1 << 5;
+    /// Transmitter Empty: the last byte has been shifted out.
+    const LSR_TEMT: u8 = 1 << 6;
+    /// Size of the 16550 transmit FIFO.
+    const FIFO_SIZE: usize = 16;
+    /// Data Terminal Ready, Request To Send, and aux. output 2 which gates
+    /// the interrupt line.
+    const MCR_NORMAL: u8 = 0b1011;
+    /// Loopback mode: transmitted bytes are received back.
+    const MCR_LOOPBACK: u8 = 0b1_1110;
 
-    /// Create and initialize a serial port.
-    pub fn new() -> Self {
+    /// Create a serial port at I/O port `base` and initialize it, if it exists.
+    pub fn new(base: u16, config: SerialConfig) -> Self {
+        let mut serial = Serial {
+            base,
+            present: false,
+            tx_buffer: None,
+        };
+        serial.configure(config);
+        serial
+    }
+
+    unsafe fn read(&self, register: u16) -> u8 {
+        inb(self.base + register)
+    }
+
+    unsafe fn write(&self, register: u16, value: u8) {
+        outb(self.base + register, value)
+    }
+
+    /// (Re)initialize the port with `config`, and check that it works.
+    pub fn configure(&mut self, config: SerialConfig) {
         unsafe {
             // https://www.latticesemi.com/-/media/LatticeSemi/Documents/ReferenceDesigns/SZ/UART16550Transceiver-Documentation.ashx?document_id=48168
             // p.10, Transmit Operation
 
+            // Disable interrupts while we reprogram the port
+            let ier = self.read(Serial::IER);
+            self.write(Serial::IER, 0);
+
             // Enable Divisor Latch Access Bit, to set baud rate.
-            outb(Serial::LCR, 1 << 7);
-            // Set baud rate: 115200
-            outb(Serial::PORT, 0x1); // lo
-            outb(Serial::PORT + 1, 0); // hi
-                                       // Disable interrupts
-            outb(Serial::IER, 0);
-
-            // Unset DLAB, 8 bit word length, no parity, one stop bit
-            outb(Serial::LCR, 0x3);
+            self.write(Serial::LCR, Serial::LCR_DLAB);
+            self.write(Serial::DATA, config.baud_divisor as u8); // lo
+            self.write(Serial::IER, (config.baud_divisor >> 8) as u8); // hi
+
+            // Unset DLAB, set word length, parity, stop bits
+            self.write(Serial::LCR, config.line_control());
             // Enable fifo, clear tx/rx, 14 bytes watermark
-            outb(Serial::FCR, 0b1100_0111);
+            self.write(Serial::FCR, 0b1100_0111);
+
+            self.present = self.probe();
+
+            self.write(Serial::MCR, Serial::MCR_NORMAL);
+            if self.present {
+                self.write(Serial::IER, ier);
+            }
+        }
+    }
+
+    /// Detect a UART: the scratch register must hold a value, and a byte sent
+    /// in loopback mode must come back unchanged.
+    /// Reads from a port with nothing behind it return 0xff.
+    unsafe fn probe(&self) -> bool {
+        self.write(Serial::SCR, 0x5a);
+        if self.read(Serial::SCR) != 0x5a {
+            return false;
+        }
+
+        self.write(Serial::MCR, Serial::MCR_LOOPBACK);
+        self.write(Serial::DATA, 0xae);
+        let mut echoed = None;
+        // The byte needs a few bit times to come back.
+        for _ in 0..0x1000 {
+            if self.read(Serial::LSR) & Serial::LSR_DR != 0 {
+                echoed = Some(self.read(Serial::DATA));
+                break;
+            }
+        }
+        echoed == Some(0xae)
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// Raise an interrupt whenever a byte is received.
+    pub fn enable_rx_interrupt(&self) {
+        if !self.present {
+            return;
+        }
+        unsafe {
+            self.write(Serial::IER, self.read(Serial::IER) | Serial::IER_RX);
+        }
+    }
+
+    /// Queue transmitted bytes in `buffer`, to be sent from the transmit
+    /// interrupt handler. The port's IRQ must be routed to `handle_irq`.
+    pub fn enable_tx_buffering(&mut self, buffer: &'static Mutex<TxBuffer>) {
+        self.tx_buffer = Some(buffer);
+    }
+
+    fn set_tx_interrupt(&self, enabled: bool) {
+        unsafe {
+            let ier = self.read(Serial::IER);
+            if enabled {
+                self.write(Serial::IER, ier | Serial::IER_THRE);
+            } else {
+                self.write(Serial::IER, ier & !Serial::IER_THRE);
+            }
+        }
+    }
+
+    /// Receive a byte, if one is waiting in the FIFO.
+    pub fn rx(&self) -> Option<u8> {
+        if !self.present {
+            return None;
+        }
+        unsafe {
+            if self.read(Serial::LSR) & Serial::LSR_DR == 0 {
+                None
+            } else {
+                Some(self.read(Serial::DATA))
+            }
+        }
+    }
+
+    /// Whether bytes should skip the buffer: the interrupt handler can't run
+    /// to send them, or we are panicking and want output out right away.
+    fn synchronous(&self) -> bool {
+        self.tx_buffer.is_none()
+            || SYNCHRONOUS.load(Ordering::Relaxed)
+            || !x86::interrupts_enabled()
+    }
+
+    /// Transmit a single byte, waiting for the transmitter to be ready.
+    fn tx_sync(&self, byte: u8) {
+        unsafe {
+            while self.read(Serial::LSR) & Serial::LSR_THRE == 0 {
+                core::hint::spin_loop();
+            }
 
-            // Enable aux. output 2 for interrupts,
-            // set Request To Send, Data Terminal Ready
-            outb(Serial::MCR, 0b1011);
+            self.write(Serial::DATA, byte);
+        }
+    }
 
-            Serial {}
+    /// Synchronously send what is left in the buffer.
+    fn drain(&self) {
+        if let Some(buffer) = self.tx_buffer {
+            x86::without_interrupts(|| {
+                let mut buffer = buffer.lock();
+                while let Some(byte) = buffer.pop() {
+                    self.tx_sync(byte);
+                }
+            });
         }
     }
 
-    /// Transmit a single byte
+    /// Transmit a single byte.
+    /// The byte is queued if possible, and sent synchronously otherwise.
     pub fn tx(&self, byte: u8) {
+        if !self.present {
+            return;
+        }
+
+        match self.tx_buffer {
+            Some(buffer) if !self.synchronous() => x86::without_interrupts(|| {
+                let mut buffer = buffer.lock();
+                let was_empty = buffer.is_empty();
+                if let Err(byte) = buffer.push(byte) {
+                    // Make room by sending the oldest byte ourselves.
+                    self.tx_sync(buffer.pop().unwrap());
+                    buffer.push(byte).unwrap();
+                }
+                // The handler only disables the interrupt once the buffer
+                // is empty, so it is still enabled otherwise. If the
+                // transmitter is idle, enabling it raises it right away.
+                if was_empty {
+                    self.set_tx_interrupt(true);
+                }
+            }),
+            _ => {
+                // Keep bytes in order.
+                self.drain();
+                self.tx_sync(byte);
+            }
+        }
+    }
+
+    /// Wait until every queued byte has been sent on the wire.
+    pub fn flush(&self) {
+        if !self.present {
+            return;
+        }
+
+        match self.tx_buffer {
+            Some(buffer) if !self.synchronous() => {
+                while !x86::without_interrupts(|| buffer.lock().is_empty()) {
+                    core::hint::spin_loop();
+                }
+            }
+            _ => self.drain(),
+        }
+
         unsafe {
-            while inb(Serial::LSR) & (1 << 5) == 0 {
+            while self.read(Serial::LSR) & Serial::LSR_TEMT == 0 {
                 core::hint::spin_loop();
             }
+        }
+    }
 
-            outb(Serial::PORT, byte);
+    /// Interrupt handler work: move received bytes to `rx_buffer`, and refill
+    /// the transmit FIFO from `tx_buffer`.
+    fn handle_irq(&self, rx_buffer: &Mutex<RxBuffer>, tx_buffer: &Mutex<TxBuffer>) {
+        let mut rx_buffer = rx_buffer.lock();
+        // Drain the whole FIFO, a single interrupt may cover several bytes.
+        while let Some(byte) = self.rx() {
+            // Drop input when nobody reads it.
+            let _ = rx_buffer.push(byte);
+        }
+
+        let mut tx_buffer = tx_buffer.lock();
+        unsafe {
+            if self.read(Serial::LSR) & Serial::LSR_THRE != 0 {
+                for _ in 0..Serial::FIFO_SIZE {
+                    match tx_buffer.pop() {
+                        Some(byte) => self.write(Serial::DATA, byte),
+                        None => break,
+                    }
+                }
+            }
+        }
+        if tx_buffer.is_empty() {
+            // Otherwise the interrupt keeps firing while there's nothing
+            // to send.
+            self.set_tx_interrupt(false);
         }
     }
 }
@@ -72,15 +420,204 @@ impl Write for Serial {
     }
 }
 
+// Only lock with interrupts disabled: logging does, and a thread preempted
+// while holding one would leave it spinning forever.
 lazy_static! {
-    pub static ref COM1: Mutex<Serial> = Mutex::new(Serial::new());
+    pub static ref COM1: Mutex<Serial> =
+        Mutex::new(Serial::new(ComPort::Com1.base(), SerialConfig::DEFAULT));
+    pub static ref COM2: Mutex<Serial> =
+        Mutex::new(Serial::new(ComPort::Com2.base(), SerialConfig::DEFAULT));
+    pub static ref COM3: Mutex<Serial> =
+        Mutex::new(Serial::new(ComPort::Com3.base(), SerialConfig::DEFAULT));
+    pub static ref COM4: Mutex<Serial> =
+        Mutex::new(Serial::new(ComPort::Com4.base(), SerialConfig::DEFAULT));
+}
+
+type RxBuffer = RingBuffer<u8, 1024>;
+pub type TxBuffer = RingBuffer<u8, 4096>;
+
+// Only lock these with interrupts disabled, or the handlers could deadlock.
+
+/// Bytes received by the interrupt handlers, waiting to be read.
+static RX_BUFFERS: [Mutex<RxBuffer>; 4] = [
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+];
+
+/// Bytes waiting to be sent by the interrupt handlers.
+static TX_BUFFERS: [Mutex<TxBuffer>; 4] = [
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+    Mutex::new(RingBuffer::new()),
+];
+
+/// Bypass transmit buffers, see `force_synchronous`.
+static SYNCHRONOUS: AtomicBool = AtomicBool::new(false);
+
+/// Send everything synchronously from now on, starting with what is still
+/// buffered. Used when panicking: the interrupt handler may never run again.
+pub fn force_synchronous() {
+    SYNCHRONOUS.store(true, Ordering::Relaxed);
+}
+
+/// Release COM1 and its transmit buffer, whoever holds them.
+///
+/// # Safety
+/// Only for the panic path, once nothing else runs: the holder was
+/// interrupted for good.
+pub unsafe fn force_unlock() {
+    COM1.force_unlock();
+    ComPort::Com1.tx_buffer().force_unlock();
+}
+
+/// Whether each port was found by `init`, readable without taking its lock.
+static PRESENT: [AtomicBool; 4] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+
+/// Service every port on `irq`.
+fn handle_irq(irq: u8) {
+    for port in ComPort::ALL {
+        if port.irq() != irq || !PRESENT[port as usize].load(Ordering::Relaxed) {
+            continue;
+        }
+        // We don't lock the port: the interrupted code may hold the lock,
+        // and all the state we share with it is in the buffers.
+        let serial = Serial {
+            base: port.base(),
+            present: true,
+            tx_buffer: Some(port.tx_buffer()),
+        };
+        serial.handle_irq(port.rx_buffer(), port.tx_buffer());
+    }
+    interrupts::end_of_interrupt(irq);
+}
+
+extern "x86-interrupt" fn irq3_handler(_frame: ExceptionStackFrame) {
+    handle_irq(3);
+}
+
+extern "x86-interrupt" fn irq4_handler(_frame: ExceptionStackFrame) {
+    handle_irq(4);
+}
+
+/// Probe every port, start receiving and buffering transmitted bytes on the
+/// ones present. Requires interrupts to be initialized.
+pub fn init() {
+    for port in ComPort::ALL {
+        // Don't hold the lock while logging, COM1 is a log sink.
+        let present = x86::without_interrupts(|| port.serial().lock().is_present());
+        PRESENT[port as usize].store(present, Ordering::Relaxed);
+        if present {
+            debug!("{:?} found at {:#x}", port, port.base());
+        }
+    }
+
+    interrupts::set_irq_handler(3, irq3_handler);
+    interrupts::set_irq_handler(4, irq4_handler);
+
+    for port in ComPort::ALL {
+        x86::without_interrupts(|| {
+            let mut serial = port.serial().lock();
+            serial.enable_rx_interrupt();
+            serial.enable_tx_buffering(port.tx_buffer());
+        });
+    }
+}
+
+/// Return a byte received on `port` if there is one, without blocking.
+pub fn try_read_byte(port: ComPort) -> Option<u8> {
+    x86::without_interrupts(|| port.rx_buffer().lock().pop())
+}
+
+/// Wait for a byte to be received on `port`.
+pub fn read_byte(port: ComPort) -> u8 {
+    loop {
+        x86::cli();
+        if let Some(byte) = port.rx_buffer().lock().pop() {
+            x86::sti();
+            return byte;
+        }
+        // Atomically re-enable interrupts and wait for the next one,
+        // so we can't miss the one bringing our byte.
+        x86::sti_hlt();
+    }
+}
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// Store one input byte of a line being edited in `buf`, echoing it back.
+/// Returns true once the line is complete.
+fn line_push(port: ComPort, buf: &mut [u8], len: &mut usize, byte: u8) -> bool {
+    x86::without_interrupts(|| {
+        let mut serial = port.serial().lock();
+        match byte {
+            b'\r' | b'\n' => {
+                serial.write_str("\r\n").unwrap();
+                true
+            }
+            BACKSPACE | DELETE => {
+                if *len > 0 {
+                    *len -= 1;
+                    serial.write_str("\x08 \x08").unwrap();
+                }
+                false
+            }
+            byte => {
+                if *len < buf.len() {
+                    buf[*len] = byte;
+                    *len += 1;
+                    serial.tx(byte);
+                }
+                // Keep reading until the end of line, ignoring what doesn't fit.
+                false
+            }
+        }
+    })
+}
+
+/// Wait for a whole line, echoing it as it is typed.
+/// The line is stored in `buf` without its terminator, and its length is
+/// returned. Input that doesn't fit in `buf` is discarded.
+pub fn read_line(port: ComPort, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    while !line_push(port, buf, &mut len, read_byte(port)) {}
+    len
+}
+
+/// Read a whole line if one was already received, without blocking.
+/// Returns None, and consumes nothing, if no complete line is available.
+pub fn try_read_line(port: ComPort, buf: &mut [u8]) -> Option<usize> {
+    let has_line = x86::without_interrupts(|| {
+        port.rx_buffer()
+            .lock()
+            .iter()
+            .any(|byte| byte == b'\r' || byte == b'\n')
+    });
+    if !has_line {
+        return None;
+    }
+
+    let mut len = 0;
+    // The line terminator is in the buffer, so this won't block.
+    while !line_push(port, buf, &mut len, read_byte(port)) {}
+    Some(len)
 }
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    COM1.lock()
-        .write_fmt(args)
-        .expect("Printing to serial failed");
+    x86::without_interrupts(|| {
+        COM1.lock()
+            .write_fmt(args)
+            .expect("Printing to serial failed")
+    });
 }
 
 #[macro_export]
@@ -97,3 +634,23 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn default_config_is_8n1() {
+        assert_eq!(SerialConfig::DEFAULT.line_control(), 0x3);
+    }
+
+    #[test_case]
+    fn divisor_for_common_rates() {
+        assert_eq!(SerialConfig::divisor_for(115200), Some(1));
+        assert_eq!(SerialConfig::divisor_for(38400), Some(3));
+        assert_eq!(SerialConfig::divisor_for(9600), Some(12));
+        assert_eq!(SerialConfig::divisor_for(230400), Some(1));
+        assert_eq!(SerialConfig::divisor_for(1), Some(u16::MAX));
+        assert_eq!(SerialConfig::divisor_for(0), None);
+    }
+}
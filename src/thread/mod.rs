@@ -0,0 +1,744 @@
+//! Kernel threads.
+//!
+//! Every thread has its own stack, where `switch_context` saves its
+//! callee-saved registers when it stops running. Threads give up the CPU by
+//! yielding, sleeping, joining or exiting, and are preempted when the timer
+//! tick finds that another thread should run, see `scheduler`: the switch
+//! then happens from the interrupt handler, which returns to the thread once
+//! it's picked again.
+//!
+//! Every CPU has its own run queue, but the thread table and the queues are
+//! one lock, taken with interrupts disabled. Each thread keeps its own
+//! address space across switches, but only one thread may run user code at
+//! a time, see `userspace`.
+
+pub mod scheduler;
+
+use core::arch::global_asm;
+use core::fmt::{self, Write};
+use core::mem::{self, ManuallyDrop};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use spin::{Mutex, MutexGuard};
+
+use crate::interrupts::apic;
+use crate::memory::address_space;
+use crate::memory::stack::Stack;
+use crate::serial::COM1;
+use crate::thread::scheduler::{fair, Entity, Policy, RunQueue, MAX_CPUS};
+use crate::time::{self, lapic};
+use crate::x86;
+#[cfg(feature = "simd")]
+use crate::x86::fpu::{self, FpuState};
+use crate::x86::registers::Cr3;
+
+pub const MAX_THREADS: usize = 64;
+const STACK_PAGES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// In the run queue.
+    Ready,
+    /// Until the `time::uptime` deadline.
+    Sleeping(Duration),
+    /// Waiting for another thread to exit.
+    Joining,
+    /// Returned this value, not joined yet.
+    Exited(u64),
+    /// Exited and detached: the slot can be reused.
+    Dead,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The thread table is full.
+    TooManyThreads,
+    /// No memory for the stack.
+    OutOfMemory,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    sched: Entity,
+    /// Saved stack pointer, while not running.
+    rsp: u64,
+    /// Saved CR3, while not running.
+    cr3: u64,
+    /// Slot of the thread joining this one.
+    joiner: Option<usize>,
+    /// Nobody will join: the thread is dead as soon as it exits.
+    detached: bool,
+    #[cfg(feature = "simd")]
+    fpu: FpuState,
+}
+
+struct Threads {
+    threads: [Option<Thread>; MAX_THREADS],
+    /// Stacks stay with their slot when a thread is gone, for the next
+    /// thread there: we can't free stack slots.
+    stacks: [Option<Stack>; MAX_THREADS],
+    queues: [RunQueue; MAX_CPUS],
+    next_id: u64,
+}
+
+impl Threads {
+    fn thread(&mut self, slot: usize) -> &mut Thread {
+        self.threads[slot].as_mut().expect("No thread in slot")
+    }
+
+    /// Slot of the thread running on this CPU.
+    fn current(&self) -> usize {
+        self.queues[this_cpu()].current
+    }
+
+    fn wake(&mut self, slot: usize, now: Duration) {
+        let thread = self.threads[slot].as_mut().expect("No thread in slot");
+        thread.state = State::Ready;
+        self.queues[thread.sched.cpu].enqueue(slot, &mut thread.sched, now);
+    }
+
+    fn wake_sleepers(&mut self, now: Duration) {
+        for slot in 0..MAX_THREADS {
+            if let Some(Thread {
+                state: State::Sleeping(deadline),
+                ..
+            }) = self.threads[slot]
+            {
+                if deadline <= now {
+                    self.wake(slot, now);
+                }
+            }
+        }
+    }
+
+    /// Account the running time of this CPU's current thread up to `now`.
+    /// Returns the CPU's run queue and the thread.
+    fn update_current(&mut self, now: Duration) -> (&mut RunQueue, &mut Thread) {
+        let queue = &mut self.queues[this_cpu()];
+        let thread = self.threads[queue.current]
+            .as_mut()
+            .expect("No current thread");
+        queue.update_current(&mut thread.sched, now);
+        (queue, thread)
+    }
+}
+
+const NO_THREAD: Option<Thread> = None;
+const NO_STACK: Option<Stack> = None;
+const NO_QUEUE: RunQueue = RunQueue::new();
+
+/// Only lock with interrupts disabled: the timer tick takes it too.
+static THREADS: Mutex<Threads> = Mutex::new(Threads {
+    threads: [NO_THREAD; MAX_THREADS],
+    stacks: [NO_STACK; MAX_THREADS],
+    queues: [NO_QUEUE; MAX_CPUS],
+    next_id: 0,
+});
+
+/// LAPIC ID of the CPU behind each run queue, `NO_CPU` if unused. LAPIC IDs
+/// aren't dense, so they can't index the queues directly.
+static CPU_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(NO_CPU) }; MAX_CPUS];
+const NO_CPU: u32 = u32::MAX;
+
+/// Index of the current CPU, for its run queue.
+fn this_cpu() -> usize {
+    let id = apic::id() as u32;
+    CPU_IDS
+        .iter()
+        .position(|cpu| cpu.load(Ordering::Relaxed) == id)
+        .unwrap_or_else(|| panic!("No run queue for CPU {}", id))
+}
+
+/// Give the current CPU the first free run queue.
+fn add_cpu() -> usize {
+    let id = apic::id() as u32;
+    CPU_IDS
+        .iter()
+        .position(|cpu| {
+            cpu.compare_exchange(NO_CPU, id, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        })
+        .expect("More CPUs than run queues")
+}
+
+global_asm!(
+    // switch_context(old_rsp: *mut u64, new_rsp: u64)
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    // First return of a new thread, with its entry point in r12 and
+    // argument in r13. rbp is 0, which ends backtraces.
+    ".global thread_start",
+    "thread_start:",
+    "mov rdi, r12",
+    "mov rsi, r13",
+    "sti",
+    "call {thread_main}",
+    "ud2",
+    thread_main = sym thread_main,
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_start();
+}
+
+extern "C" fn thread_main(entry: u64, arg: u64) -> ! {
+    let entry: fn(u64) -> u64 = unsafe { mem::transmute(entry) };
+    exit(entry(arg))
+}
+
+/// Switch to the next thread to run on this CPU. The current thread is
+/// queued again if it's still running, otherwise it must have set why it
+/// stops. Interrupts must be disabled, and stay so until the current thread
+/// runs again.
+fn schedule(mut guard: MutexGuard<Threads>) {
+    let now = time::uptime();
+    let threads = &mut *guard;
+    threads.wake_sleepers(now);
+    let (queue, thread) = threads.update_current(now);
+    let current = queue.current;
+    if thread.state == State::Running {
+        thread.state = State::Ready;
+        if Some(current) != queue.idle {
+            queue.enqueue(current, &mut thread.sched, now);
+        }
+    }
+
+    let next = queue.pick_next().expect("No idle thread");
+    let thread = threads.threads[next].as_mut().expect("No thread in slot");
+    let queue = &mut threads.queues[thread.sched.cpu];
+    thread.state = State::Running;
+    queue.switch_to(next, &mut thread.sched, now);
+    if next == current {
+        return;
+    }
+    let new_rsp = thread.rsp;
+    let new_cr3 = thread.cr3;
+    #[cfg(feature = "simd")]
+    fpu::switch_to(&mut thread.fpu);
+    let old = threads.thread(current);
+    old.cr3 = Cr3::read_raw();
+    if new_cr3 != old.cr3 {
+        // Safe: the kernel half is the same in every address space.
+        unsafe { Cr3::write_raw(new_cr3) };
+    }
+    let old_rsp = &mut old.rsp as *mut u64;
+    // The table is static, so `old_rsp` stays valid. Nothing can reuse a
+    // dead thread's slot before we are off its stack: interrupts are
+    // disabled.
+    drop(guard);
+    unsafe { switch_context(old_rsp, new_rsp) };
+}
+
+/// Called on every timer interrupt, with interrupts disabled.
+fn tick() {
+    let mut threads = THREADS.lock();
+    let now = time::uptime();
+    threads.wake_sleepers(now);
+    let (queue, thread) = threads.update_current(now);
+    if queue.should_preempt(&thread.sched, now) {
+        schedule(threads);
+    }
+}
+
+fn idle_main(_: u64) -> u64 {
+    loop {
+        x86::hlt();
+        // The tick switches away from us, unless the timer was stopped:
+        // sleepers are also woken when scheduling.
+        yield_now();
+    }
+}
+
+/// Owns the right to join a thread. Dropping it detaches the thread.
+pub struct JoinHandle {
+    slot: usize,
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Wait for the thread to exit, and return the value it returned.
+    pub fn join(self) -> u64 {
+        let this = ManuallyDrop::new(self);
+        loop {
+            let value = x86::without_interrupts(|| {
+                let mut threads = THREADS.lock();
+                let current = threads.current();
+                assert_ne!(current, this.slot, "Thread joining itself");
+                let thread = threads.thread(this.slot);
+                if let State::Exited(value) = thread.state {
+                    threads.threads[this.slot] = None;
+                    return Some(value);
+                }
+                thread.joiner = Some(current);
+                threads.thread(current).state = State::Joining;
+                schedule(threads);
+                None
+            });
+            if let Some(value) = value {
+                return value;
+            }
+        }
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        x86::without_interrupts(|| {
+            let mut threads = THREADS.lock();
+            let thread = threads.thread(self.slot);
+            thread.detached = true;
+            if let State::Exited(_) = thread.state {
+                thread.state = State::Dead;
+            }
+        });
+    }
+}
+
+/// Set up a thread running `entry(arg)` on this CPU, without queuing it.
+/// Returns its slot.
+fn create(
+    threads: &mut Threads,
+    name: &'static str,
+    entry: fn(u64) -> u64,
+    arg: u64,
+    policy: Policy,
+) -> Result<usize, SpawnError> {
+    let slot = (0..MAX_THREADS)
+        .find(|&slot| match &threads.threads[slot] {
+            None => true,
+            Some(thread) => thread.state == State::Dead,
+        })
+        .ok_or(SpawnError::TooManyThreads)?;
+    if threads.stacks[slot].is_none() {
+        let stack = Stack::allocate(STACK_PAGES).map_err(|_| SpawnError::OutOfMemory)?;
+        threads.stacks[slot] = Some(stack);
+    }
+
+    // What `switch_context` pops: r15, r14, r13, r12, rbx, rbp, then
+    // the return address.
+    let top = threads.stacks[slot].as_ref().unwrap().top();
+    let frame = [
+        0,
+        0,
+        arg,
+        entry as usize as u64,
+        0,
+        0,
+        thread_start as *const () as u64,
+    ];
+    let rsp = top - mem::size_of_val(&frame) as u64;
+    unsafe { (rsp as *mut [u64; 7]).write(frame) };
+
+    let id = ThreadId(threads.next_id);
+    threads.next_id += 1;
+    threads.threads[slot] = Some(Thread {
+        id,
+        name,
+        state: State::Ready,
+        sched: Entity::new(policy, this_cpu()),
+        rsp,
+        cr3: address_space::kernel_pml4(),
+        joiner: None,
+        detached: false,
+        #[cfg(feature = "simd")]
+        fpu: FpuState::new(),
+    });
+    Ok(slot)
+}
+
+/// Run `entry(arg)` in a new thread of the fair class, called `name`.
+pub fn spawn(
+    name: &'static str,
+    entry: fn(u64) -> u64,
+    arg: u64,
+) -> Result<JoinHandle, SpawnError> {
+    spawn_with_policy(name, entry, arg, Policy::default())
+}
+
+/// Run `entry(arg)` in a new thread scheduled with `policy`, called `name`.
+/// Out of range priorities and nice values are clamped.
+pub fn spawn_with_policy(
+    name: &'static str,
+    entry: fn(u64) -> u64,
+    arg: u64,
+    policy: Policy,
+) -> Result<JoinHandle, SpawnError> {
+    x86::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let slot = create(&mut threads, name, entry, arg, policy)?;
+        threads.wake(slot, time::uptime());
+        let id = threads.thread(slot).id;
+        Ok(JoinHandle { slot, id })
+    })
+}
+
+/// Let the other ready threads of the same class run first.
+pub fn yield_now() {
+    x86::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let (queue, thread) = threads.update_current(time::uptime());
+        queue.yield_current(&mut thread.sched);
+        schedule(threads);
+    });
+}
+
+/// Let other threads run for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let deadline = time::uptime() + duration;
+    x86::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let current = threads.current();
+        threads.thread(current).state = State::Sleeping(deadline);
+        schedule(threads);
+    });
+}
+
+/// End the current thread, returning `value` to its joiner.
+pub fn exit(value: u64) -> ! {
+    x86::cli();
+    let mut threads = THREADS.lock();
+    let current = threads.current();
+    let thread = threads.thread(current);
+    thread.state = if thread.detached {
+        State::Dead
+    } else {
+        State::Exited(value)
+    };
+    // The next thread in this slot must not take over our registers.
+    #[cfg(feature = "simd")]
+    fpu::forget(&mut thread.fpu);
+    if let Some(joiner) = thread.joiner {
+        threads.wake(joiner, time::uptime());
+    }
+    schedule(threads);
+    unreachable!("Exited thread scheduled");
+}
+
+pub fn current() -> ThreadId {
+    x86::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let current = threads.current();
+        threads.thread(current).id
+    })
+}
+
+/// Schedule the current thread with `policy` from now on. Out of range
+/// priorities and nice values are clamped.
+pub fn set_policy(policy: Policy) {
+    x86::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let (queue, thread) = threads.update_current(time::uptime());
+        queue.set_policy(&mut thread.sched, policy);
+        // Another thread may come first now.
+        schedule(threads);
+    });
+}
+
+/// A thread, as copied for `dump_stats`.
+#[derive(Clone, Copy)]
+struct ThreadInfo {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    sched: Entity,
+}
+
+/// Write the statistics of every run queue, then of every thread, to `w`.
+pub fn dump_stats(w: &mut impl Write) -> fmt::Result {
+    // Copied first: `w` may be slow, or take locks of its own.
+    let mut cpus = [None; MAX_CPUS];
+    let mut infos = [None; MAX_THREADS];
+    x86::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        threads.update_current(time::uptime());
+        for (cpu, queue) in cpus.iter_mut().zip(threads.queues.iter()) {
+            if queue.idle.is_some() {
+                *cpu = Some((queue.stats, queue.len()));
+            }
+        }
+        for (info, thread) in infos.iter_mut().zip(threads.threads.iter()) {
+            *info = thread.as_ref().map(|thread| ThreadInfo {
+                id: thread.id,
+                name: thread.name,
+                state: thread.state,
+                sched: thread.sched,
+            });
+        }
+    });
+
+    for (cpu, entry) in cpus.iter().enumerate() {
+        if let Some((stats, queued)) = entry {
+            writeln!(
+                w,
+                "CPU {}: {} queued, busy {:?}, waited {:?}, {} switches",
+                cpu, queued, stats.runtime, stats.wait_time, stats.switches
+            )?;
+        }
+    }
+    for info in infos.iter().flatten() {
+        let stats = info.sched.stats;
+        writeln!(
+            w,
+            "  #{} {}: {:?}, {:?} on CPU {}, ran {:?}, waited {:?}, {} switches, vruntime {}",
+            info.id.0,
+            info.name,
+            info.state,
+            info.sched.policy,
+            info.sched.cpu,
+            stats.runtime,
+            stats.wait_time,
+            stats.switches,
+            info.sched.vruntime
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the scheduler statistics to serial.
+pub fn print_stats() {
+    x86::without_interrupts(|| dump_stats(&mut *COM1.lock()).expect("Printing to serial failed"));
+}
+
+/// Make the running code the "main" thread, start the idle thread of this
+/// CPU and preemption. Requires the LAPIC timer.
+pub fn init() {
+    x86::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let cpu = add_cpu();
+        threads.threads[0] = Some(Thread {
+            id: ThreadId(0),
+            name: "main",
+            state: State::Running,
+            sched: Entity::new(Policy::default(), cpu),
+            rsp: 0,
+            cr3: Cr3::read_raw(),
+            joiner: None,
+            detached: true,
+            #[cfg(feature = "simd")]
+            fpu: FpuState::new(),
+        });
+        threads.next_id = 1;
+        threads.queues[cpu].current = 0;
+        #[cfg(feature = "simd")]
+        fpu::switch_to(&mut threads.thread(0).fpu);
+        let idle = create(
+            &mut threads,
+            "idle",
+            idle_main,
+            0,
+            Policy::Fair(fair::MAX_NICE),
+        )
+        .expect("Can't create the idle thread");
+        threads.queues[cpu].idle = Some(idle);
+    });
+
+    lapic::set_event_handler(Some(tick));
+    lapic::start_periodic(time::TICK_HZ);
+    debug!("Threads: scheduling on CPU {}", this_cpu());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serial_print;
+    use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    #[test_case]
+    fn spawn_and_join() {
+        let handle = spawn("double", |n| n * 2, 21).unwrap();
+        assert_ne!(handle.id(), current());
+        assert_eq!(handle.join(), 42);
+    }
+
+    #[test_case]
+    fn yield_lets_others_run() {
+        static FLAG: AtomicBool = AtomicBool::new(false);
+        let waiter = spawn(
+            "waiter",
+            |_| {
+                while !FLAG.load(Ordering::Relaxed) {
+                    yield_now();
+                }
+                1
+            },
+            0,
+        )
+        .unwrap();
+        let setter = spawn(
+            "setter",
+            |_| {
+                FLAG.store(true, Ordering::Relaxed);
+                2
+            },
+            0,
+        )
+        .unwrap();
+        assert_eq!(waiter.join() + setter.join(), 3);
+    }
+
+    #[test_case]
+    fn sleep_waits() {
+        let start = time::uptime();
+        sleep(Duration::from_millis(5));
+        assert!(time::uptime() >= start + Duration::from_millis(5));
+    }
+
+    #[test_case]
+    fn spinning_threads_are_preempted() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        // The LAPIC timer tests may have stopped the tick.
+        lapic::start_periodic(time::TICK_HZ);
+        let spinner = spawn(
+            "spinner",
+            |_| {
+                while !STOP.load(Ordering::Relaxed) {
+                    core::hint::spin_loop();
+                }
+                0
+            },
+            0,
+        )
+        .unwrap();
+        // Only comes back once the spinner is preempted.
+        yield_now();
+        STOP.store(true, Ordering::Relaxed);
+        spinner.join();
+    }
+
+    #[test_case]
+    fn preempted_printers_dont_block_logging() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+        lapic::start_periodic(time::TICK_HZ);
+        let printer = spawn(
+            "printer",
+            |_| {
+                while !STOP.load(Ordering::Relaxed) {
+                    serial_print!("");
+                }
+                0
+            },
+            0,
+        )
+        .unwrap();
+        // Each time we wake up, the printer was preempted, maybe mid-print.
+        for i in 0..10 {
+            sleep(Duration::from_millis(1));
+            trace!("Logging against a printer, {}", i);
+        }
+        STOP.store(true, Ordering::Relaxed);
+        printer.join();
+    }
+
+    #[test_case]
+    fn threads_keep_their_address_space() {
+        let cr3 = Cr3::read_raw();
+        let other = spawn(
+            "other space",
+            |_| {
+                let space = address_space::AddressSpace::new().unwrap();
+                space.activate();
+                yield_now();
+                let active = space.is_active();
+                address_space::activate_kernel();
+                active as u64
+            },
+            0,
+        )
+        .unwrap();
+        yield_now();
+        assert_eq!(Cr3::read_raw(), cr3);
+        assert_eq!(other.join(), 1);
+    }
+
+    #[test_case]
+    fn detached_slots_are_reused() {
+        for _ in 0..2 * MAX_THREADS {
+            drop(spawn("detached", |_| 0, 0).unwrap());
+            yield_now();
+        }
+    }
+
+    #[test_case]
+    fn fifo_threads_run_first() {
+        static ORDER: AtomicU64 = AtomicU64::new(0);
+        // How many ran before, then `n`.
+        let record = |n: u64| ORDER.fetch_add(1, Ordering::Relaxed) * 10 + n;
+        // Nothing else runs until we block.
+        set_policy(Policy::Fifo(50));
+        let fair = spawn("fair", record, 1).unwrap();
+        let low = spawn_with_policy("low", record, 2, Policy::Fifo(10)).unwrap();
+        let high = spawn_with_policy("high", record, 3, Policy::Fifo(20)).unwrap();
+        assert_eq!(fair.join(), 21);
+        assert_eq!(high.join(), 3);
+        assert_eq!(low.join(), 12);
+        set_policy(Policy::default());
+    }
+
+    #[test_case]
+    fn stats_are_dumped() {
+        /// Looks for a line starting with `prefix`, once trimmed.
+        struct Find {
+            prefix: &'static str,
+            line: [u8; 128],
+            len: usize,
+            found: bool,
+        }
+
+        impl Write for Find {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                for byte in s.bytes() {
+                    if byte == b'\n' {
+                        let line = core::str::from_utf8(&self.line[..self.len]).unwrap_or("");
+                        self.found |= line.trim_start().starts_with(self.prefix);
+                        self.len = 0;
+                    } else if self.len < self.line.len() {
+                        self.line[self.len] = byte;
+                        self.len += 1;
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        let mut find = Find {
+            prefix: "#1 idle: Ready, Fair(19)",
+            line: [0; 128],
+            len: 0,
+            found: false,
+        };
+        dump_stats(&mut find).unwrap();
+        assert!(find.found);
+        find.prefix = "CPU 0:";
+        find.found = false;
+        dump_stats(&mut find).unwrap();
+        assert!(find.found);
+    }
+}
@@ -0,0 +1,144 @@
+//! Fair class, after Linux's CFS.
+//! https://docs.kernel.org/scheduler/sched-design-CFS.html
+//!
+//! Every thread accumulates virtual runtime: its runtime, scaled down by the
+//! weight of its nice value. The thread with the least virtual runtime runs
+//! next, so over time every thread gets CPU time in proportion to its
+//! weight. A nice level is worth about 10% of CPU time.
+
+use core::time::Duration;
+
+use crate::thread::MAX_THREADS;
+
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// Weight of nice 0.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weight of every nice value, from -20 to 19. Each level is 1.25 times
+/// the next.
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// Period in which every ready thread should run once.
+const LATENCY: Duration = Duration::from_millis(6);
+/// Shortest time slice, however many threads share the period.
+const MIN_GRANULARITY: Duration = Duration::from_micros(750);
+
+pub fn weight(nice: i8) -> u64 {
+    WEIGHTS[(nice.clamp(MIN_NICE, MAX_NICE) - MIN_NICE) as usize]
+}
+
+/// Virtual runtime for running `delta` with `nice`, in nanoseconds.
+pub fn virtual_runtime(delta: Duration, nice: i8) -> u64 {
+    (delta.as_nanos() as u64).saturating_mul(NICE_0_WEIGHT) / weight(nice)
+}
+
+/// Ready threads, by slot, with their virtual runtime and weight.
+pub struct FairQueue {
+    entries: [(u64, usize, u64); MAX_THREADS],
+    len: usize,
+    total_weight: u64,
+    /// Never decreases, so threads that slept for long don't get to run
+    /// for as long.
+    min_vruntime: u64,
+}
+
+impl FairQueue {
+    pub const fn new() -> Self {
+        Self {
+            entries: [(0, 0, 0); MAX_THREADS],
+            len: 0,
+            total_weight: 0,
+            min_vruntime: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Virtual runtime to queue a thread with: a thread that slept keeps at
+    /// most half a period of credit over the others.
+    pub fn place(&self, vruntime: u64) -> u64 {
+        let credit = LATENCY.as_nanos() as u64 / 2;
+        vruntime.max(self.min_vruntime.saturating_sub(credit))
+    }
+
+    pub fn push(&mut self, vruntime: u64, nice: i8, slot: usize) {
+        // Every thread is queued at most once, so there is always room.
+        assert!(self.len < MAX_THREADS, "Run queue full");
+        self.entries[self.len] = (vruntime, slot, weight(nice));
+        self.len += 1;
+        self.total_weight += weight(nice);
+    }
+
+    /// The thread with the least virtual runtime. The first queued wins ties.
+    pub fn pop(&mut self) -> Option<usize> {
+        let entries = &self.entries[..self.len];
+        let index = (0..entries.len()).min_by_key(|&i| entries[i].0)?;
+        let (_, slot, weight) = self.entries[index];
+        self.entries.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        self.total_weight -= weight;
+        Some(slot)
+    }
+
+    /// Largest virtual runtime of the queued threads.
+    pub fn max_vruntime(&self) -> Option<u64> {
+        self.entries[..self.len].iter().map(|entry| entry.0).max()
+    }
+
+    /// Move `min_vruntime` forward, as the running thread with `vruntime`
+    /// and the queued ones progress.
+    pub fn update_min_vruntime(&mut self, vruntime: u64) {
+        let queued = self.entries[..self.len].iter().map(|entry| entry.0).min();
+        let min = queued.map_or(vruntime, |queued| queued.min(vruntime));
+        self.min_vruntime = self.min_vruntime.max(min);
+    }
+
+    /// How long a running thread with `nice` may run before making way: its
+    /// share of the period, next to the queued threads.
+    pub fn slice(&self, nice: i8) -> Duration {
+        let weight = weight(nice);
+        let share = LATENCY.as_nanos() as u64 * weight / (self.total_weight + weight);
+        Duration::from_nanos(share).max(MIN_GRANULARITY)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn nice_scales_virtual_runtime() {
+        let ms = Duration::from_millis(1);
+        assert_eq!(virtual_runtime(ms, 0), 1_000_000);
+        assert!(virtual_runtime(ms, 5) > 3 * virtual_runtime(ms, 0));
+        assert!(virtual_runtime(ms, -5) < virtual_runtime(ms, 0) / 3);
+        assert_eq!(weight(-100), weight(MIN_NICE));
+    }
+
+    #[test_case]
+    fn least_virtual_runtime_first() {
+        let mut queue = FairQueue::new();
+        queue.push(300, 0, 1);
+        queue.push(100, 0, 2);
+        queue.push(100, 0, 3);
+        assert!(queue.slice(0) < LATENCY);
+        assert!(queue.slice(-20) > queue.slice(0));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.slice(0), LATENCY);
+    }
+}
@@ -0,0 +1,79 @@
+//! Real-time FIFO class: the ready thread of highest priority runs until it
+//! blocks or yields. Threads of equal priority run in the order they got
+//! ready.
+
+use crate::thread::MAX_THREADS;
+
+pub const MIN_PRIORITY: u8 = 1;
+pub const MAX_PRIORITY: u8 = 99;
+
+/// Ready threads, by slot, sorted by decreasing priority.
+pub struct FifoQueue {
+    entries: [(u8, usize); MAX_THREADS],
+    len: usize,
+}
+
+impl FifoQueue {
+    pub const fn new() -> Self {
+        Self {
+            entries: [(0, 0); MAX_THREADS],
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Queue `slot` after every thread of the same or higher priority.
+    pub fn push(&mut self, priority: u8, slot: usize) {
+        // Every thread is queued at most once, so there is always room.
+        assert!(self.len < MAX_THREADS, "Run queue full");
+        let position = self.entries[..self.len]
+            .iter()
+            .position(|&(queued, _)| queued < priority)
+            .unwrap_or(self.len);
+        self.entries.copy_within(position..self.len, position + 1);
+        self.entries[position] = (priority, slot);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let (_, slot) = self.entries[0];
+        self.entries.copy_within(1..self.len, 0);
+        self.len -= 1;
+        Some(slot)
+    }
+
+    /// Priority of the next thread to run.
+    pub fn highest(&self) -> Option<u8> {
+        (!self.is_empty()).then(|| self.entries[0].0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn higher_priorities_first_then_fifo() {
+        let mut queue = FifoQueue::new();
+        queue.push(10, 1);
+        queue.push(20, 2);
+        queue.push(10, 3);
+        queue.push(5, 4);
+        assert_eq!(queue.highest(), Some(20));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), Some(4));
+        assert_eq!(queue.pop(), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Scheduling classes, and the run queue of every CPU.
+//!
+//! Each thread belongs to a class, through its policy. Ready threads are
+//! picked from the classes in order: real-time FIFO threads first, then
+//! fair threads. The idle thread runs when both are empty.
+
+pub mod fair;
+pub mod fifo;
+
+use core::time::Duration;
+
+use fair::FairQueue;
+use fifo::FifoQueue;
+
+pub const MAX_CPUS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Real-time, with a priority from 1 to 99: runs until it blocks or
+    /// yields, unless a thread of higher priority gets ready.
+    Fifo(u8),
+    /// Shares the CPU with the other fair threads, according to its nice
+    /// value: from -20, the largest share, to 19.
+    Fair(i8),
+}
+
+impl Policy {
+    /// The policy, with its priority or nice value brought in range.
+    pub fn clamped(self) -> Self {
+        match self {
+            Policy::Fifo(priority) => {
+                Policy::Fifo(priority.clamp(fifo::MIN_PRIORITY, fifo::MAX_PRIORITY))
+            }
+            Policy::Fair(nice) => Policy::Fair(nice.clamp(fair::MIN_NICE, fair::MAX_NICE)),
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::Fair(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    /// Time spent running. For a CPU, not counting the idle thread.
+    pub runtime: Duration,
+    /// Time spent ready, waiting for the CPU.
+    pub wait_time: Duration,
+    /// Times switched to. For a CPU, all context switches.
+    pub switches: u64,
+}
+
+/// Scheduling state of a thread.
+#[derive(Debug, Clone, Copy)]
+pub struct Entity {
+    pub policy: Policy,
+    /// Whose run queue the thread goes to.
+    pub cpu: usize,
+    /// For the fair class, in nanoseconds.
+    pub vruntime: u64,
+    pub stats: Stats,
+    /// When the thread started running, or got queued.
+    since: Duration,
+}
+
+impl Entity {
+    pub fn new(policy: Policy, cpu: usize) -> Self {
+        Self {
+            policy: policy.clamped(),
+            cpu,
+            vruntime: 0,
+            stats: Stats::default(),
+            since: Duration::ZERO,
+        }
+    }
+}
+
+/// Threads of a CPU, by slot in the thread table.
+pub struct RunQueue {
+    fifo: FifoQueue,
+    fair: FairQueue,
+    /// Running thread.
+    pub current: usize,
+    /// Runs when nothing else is ready. Never queued. None until the CPU
+    /// runs threads.
+    pub idle: Option<usize>,
+    /// When the running thread got the CPU.
+    slice_start: Duration,
+    pub stats: Stats,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            fifo: FifoQueue::new(),
+            fair: FairQueue::new(),
+            current: 0,
+            idle: None,
+            slice_start: Duration::ZERO,
+            stats: Stats {
+                runtime: Duration::ZERO,
+                wait_time: Duration::ZERO,
+                switches: 0,
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fifo.is_empty() && self.fair.is_empty()
+    }
+
+    /// Number of queued threads.
+    pub fn len(&self) -> usize {
+        self.fifo.len() + self.fair.len()
+    }
+
+    pub fn enqueue(&mut self, slot: usize, entity: &mut Entity, now: Duration) {
+        entity.since = now;
+        match entity.policy {
+            Policy::Fifo(priority) => self.fifo.push(priority, slot),
+            Policy::Fair(nice) => {
+                entity.vruntime = self.fair.place(entity.vruntime);
+                self.fair.push(entity.vruntime, nice, slot);
+            }
+        }
+    }
+
+    /// The next thread to run, by class. The idle thread if none is ready.
+    pub fn pick_next(&mut self) -> Option<usize> {
+        self.fifo.pop().or_else(|| self.fair.pop()).or(self.idle)
+    }
+
+    /// Account the time since the last update to `entity`, the running
+    /// thread.
+    pub fn update_current(&mut self, entity: &mut Entity, now: Duration) {
+        let delta = now.saturating_sub(entity.since);
+        entity.since = now;
+        entity.stats.runtime += delta;
+        if Some(self.current) != self.idle {
+            self.stats.runtime += delta;
+        }
+        if let Policy::Fair(nice) = entity.policy {
+            entity.vruntime += fair::virtual_runtime(delta, nice);
+            self.fair.update_min_vruntime(entity.vruntime);
+        }
+    }
+
+    /// Give the CPU to `slot`, just picked, whose state is `entity`.
+    pub fn switch_to(&mut self, slot: usize, entity: &mut Entity, now: Duration) {
+        // The idle thread only runs when nothing else is queued: the time
+        // since it last ran was spent busy, not waiting.
+        if Some(slot) != self.idle {
+            let wait = now.saturating_sub(entity.since);
+            entity.stats.wait_time += wait;
+            self.stats.wait_time += wait;
+        }
+        entity.since = now;
+        if slot != self.current {
+            entity.stats.switches += 1;
+            self.stats.switches += 1;
+        }
+        self.current = slot;
+        self.slice_start = now;
+    }
+
+    /// Whether `entity`, the running thread, should make way for a queued
+    /// one.
+    pub fn should_preempt(&self, entity: &Entity, now: Duration) -> bool {
+        if Some(self.current) == self.idle {
+            return !self.is_empty();
+        }
+        match entity.policy {
+            Policy::Fifo(priority) => self.fifo.highest().is_some_and(|next| next > priority),
+            Policy::Fair(nice) => {
+                let ran = now.saturating_sub(self.slice_start);
+                !self.fifo.is_empty() || (!self.fair.is_empty() && ran >= self.fair.slice(nice))
+            }
+        }
+    }
+
+    /// Make `entity`, the running thread, go after the queued threads of
+    /// its class when it's queued again.
+    pub fn yield_current(&mut self, entity: &mut Entity) {
+        // FIFO threads go after those of the same priority anyway.
+        if let Policy::Fair(_) = entity.policy {
+            entity.vruntime = entity.vruntime.max(self.fair.max_vruntime().unwrap_or(0));
+        }
+    }
+
+    /// Switch the running thread's class: its virtual runtime starts over
+    /// from the fair threads' if it joins them.
+    pub fn set_policy(&mut self, entity: &mut Entity, policy: Policy) {
+        if let (Policy::Fifo(_), Policy::Fair(_)) = (entity.policy, policy) {
+            entity.vruntime = self.fair.place(0);
+        }
+        entity.policy = policy.clamped();
+    }
+}
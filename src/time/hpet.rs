@@ -0,0 +1,382 @@
+//! High Precision Event Timer.
+//! https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/software-developers-hpet-spec-1-0a.pdf
+//!
+//! A free running main counter, plus comparators ("timers") that raise an
+//! interrupt when the counter reaches their value, once or periodically.
+
+use core::ptr::{addr_of, read_unaligned, read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bitflags::bitflags;
+use spin::Once;
+
+use crate::acpi::{self, GenericAddress};
+use crate::memory::phys_to_virt;
+
+// Register offsets
+const CAPABILITIES: usize = 0x000;
+const CONFIG: usize = 0x010;
+const INTERRUPT_STATUS: usize = 0x020;
+const MAIN_COUNTER: usize = 0x0f0;
+const fn timer_config(n: usize) -> usize {
+    0x100 + 0x20 * n
+}
+const fn timer_comparator(n: usize) -> usize {
+    0x108 + 0x20 * n
+}
+
+bitflags! {
+    struct Capabilities: u64 {
+        /// The main counter is 64 bits wide.
+        const COUNT_SIZE_64 = 1 << 13;
+        const LEGACY_REPLACEMENT = 1 << 15;
+    }
+}
+
+bitflags! {
+    struct Config: u64 {
+        const ENABLE = 1 << 0;
+        /// Timer 0 raises IRQ 0 and timer 1 raises IRQ 8, in place of the
+        /// PIT and RTC.
+        const LEGACY_REPLACEMENT = 1 << 1;
+    }
+}
+
+bitflags! {
+    struct TimerConfig: u64 {
+        /// Level triggered interrupt, edge triggered if unset.
+        const LEVEL_TRIGGERED = 1 << 1;
+        const INTERRUPT_ENABLE = 1 << 2;
+        const PERIODIC = 1 << 3;
+        const PERIODIC_CAPABLE = 1 << 4;
+        const SIZE_64 = 1 << 5;
+        /// Next comparator write sets the accumulator in periodic mode.
+        const VALUE_SET = 1 << 6;
+        const FORCE_32 = 1 << 8;
+        const ROUTE = 0b11111 << 9;
+        const FSB_ENABLE = 1 << 14;
+    }
+}
+
+/// Where a comparator's interrupt goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// IRQ 0 for timer 0, IRQ 8 for timer 1, edge triggered.
+    /// Legacy replacement must be enabled.
+    Legacy,
+    /// This I/O APIC input, level triggered.
+    /// Must be allowed by `Timer::routes`.
+    IoApic(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timer {
+    pub periodic_capable: bool,
+    pub size_64: bool,
+    /// Bitmap of the I/O APIC inputs this timer can be routed to.
+    pub routes: u32,
+}
+
+pub struct Hpet {
+    base: usize,
+    /// Main counter tick, in femtoseconds.
+    period_fs: u64,
+    timers: usize,
+    counter_64: bool,
+    legacy_capable: bool,
+    /// Last value of `extended_counter`.
+    last_counter: AtomicU64,
+}
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+impl Hpet {
+    unsafe fn read(&self, register: usize) -> u64 {
+        read_volatile((self.base + register) as *const u64)
+    }
+
+    unsafe fn write(&self, register: usize, value: u64) {
+        write_volatile((self.base + register) as *mut u64, value)
+    }
+
+    /// # Safety
+    /// `base` must be the virtual address of the HPET registers.
+    unsafe fn new(base: usize) -> Self {
+        let caps = read_volatile((base + CAPABILITIES) as *const u64);
+        let flags = Capabilities::from_bits_truncate(caps);
+        Self {
+            base,
+            period_fs: caps >> 32,
+            timers: ((caps >> 8) & 0b11111) as usize + 1,
+            counter_64: flags.contains(Capabilities::COUNT_SIZE_64),
+            legacy_capable: flags.contains(Capabilities::LEGACY_REPLACEMENT),
+            last_counter: AtomicU64::new(0),
+        }
+    }
+
+    fn config(&self) -> Config {
+        Config::from_bits_truncate(unsafe { self.read(CONFIG) })
+    }
+
+    fn set_config(&self, config: Config) {
+        unsafe {
+            let reserved = self.read(CONFIG) & !Config::all().bits();
+            self.write(CONFIG, reserved | config.bits());
+        }
+    }
+
+    pub fn enable(&self) {
+        self.set_config(self.config() | Config::ENABLE);
+    }
+
+    pub fn disable(&self) {
+        self.set_config(self.config() - Config::ENABLE);
+    }
+
+    /// Route timers 0 and 1 to IRQ 0 and 8, see `Route::Legacy`.
+    /// Returns false if the HPET doesn't support it.
+    pub fn set_legacy_replacement(&self, enabled: bool) -> bool {
+        if !self.legacy_capable {
+            return false;
+        }
+        let mut config = self.config();
+        config.set(Config::LEGACY_REPLACEMENT, enabled);
+        self.set_config(config);
+        true
+    }
+
+    /// Main counter value, as the comparators see it: it wraps around
+    /// after 2^32 ticks on a 32-bit HPET.
+    pub fn counter(&self) -> u64 {
+        unsafe {
+            if self.counter_64 {
+                self.read(MAIN_COUNTER)
+            } else {
+                self.read(MAIN_COUNTER) & 0xffff_ffff
+            }
+        }
+    }
+
+    /// Main counter frequency, in Hz.
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / FEMTOS_PER_NANO as u128) as u64
+    }
+
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns as u128 * FEMTOS_PER_NANO as u128 / self.period_fs as u128) as u64
+    }
+
+    /// Whether the main counter is 32 bits wide, and so wraps around.
+    pub fn wraps_around(&self) -> bool {
+        !self.counter_64
+    }
+
+    /// Main counter value, extended to 64 bits in software on a 32-bit
+    /// HPET. That only works if it is read at least once per wraparound
+    /// (about 5 minutes at 14.318 MHz): the PIT tick does.
+    pub fn extended_counter(&self) -> u64 {
+        if self.counter_64 {
+            return self.counter();
+        }
+        let last = self.last_counter.load(Ordering::Relaxed);
+        let now = extend(last, self.counter());
+        // Another CPU may have seen a later value meanwhile.
+        self.last_counter.fetch_max(now, Ordering::Relaxed).max(now)
+    }
+
+    /// Nanoseconds since the counter was last reset.
+    pub fn now_ns(&self) -> u64 {
+        self.ticks_to_ns(self.extended_counter())
+    }
+
+    pub fn timer_count(&self) -> usize {
+        self.timers
+    }
+
+    pub fn timer(&self, n: usize) -> Timer {
+        let config = unsafe { self.read(timer_config(n)) };
+        let flags = TimerConfig::from_bits_truncate(config);
+        Timer {
+            periodic_capable: flags.contains(TimerConfig::PERIODIC_CAPABLE),
+            size_64: flags.contains(TimerConfig::SIZE_64),
+            routes: (config >> 32) as u32,
+        }
+    }
+
+    fn timer_flags(&self, n: usize) -> TimerConfig {
+        TimerConfig::from_bits_truncate(unsafe { self.read(timer_config(n)) })
+    }
+
+    fn set_timer_flags(&self, n: usize, flags: TimerConfig) {
+        unsafe {
+            let reserved = self.read(timer_config(n)) & !TimerConfig::all().bits();
+            self.write(timer_config(n), reserved | flags.bits());
+        }
+    }
+
+    fn route_flags(route: Route) -> TimerConfig {
+        match route {
+            Route::Legacy => TimerConfig::empty(),
+            Route::IoApic(input) => {
+                TimerConfig::from_bits_truncate((input as u64) << 9) | TimerConfig::LEVEL_TRIGGERED
+            }
+        }
+    }
+
+    /// Raise an interrupt once the main counter reaches `deadline`.
+    pub fn set_one_shot(&self, n: usize, deadline: u64, route: Route) {
+        let mut flags = self.timer_flags(n)
+            - (TimerConfig::PERIODIC
+                | TimerConfig::ROUTE
+                | TimerConfig::LEVEL_TRIGGERED
+                | TimerConfig::FSB_ENABLE);
+        flags |= Hpet::route_flags(route) | TimerConfig::INTERRUPT_ENABLE;
+        // The old comparator could fire as soon as the interrupt is enabled.
+        unsafe { self.write(timer_comparator(n), deadline) };
+        self.set_timer_flags(n, flags);
+    }
+
+    /// Raise an interrupt every `period` ticks, starting `period` ticks from
+    /// now. Returns false if the timer can't do periodic interrupts.
+    pub fn set_periodic(&self, n: usize, period: u64, route: Route) -> bool {
+        if !self.timer(n).periodic_capable {
+            return false;
+        }
+        let mut flags = self.timer_flags(n)
+            - (TimerConfig::ROUTE | TimerConfig::LEVEL_TRIGGERED | TimerConfig::FSB_ENABLE);
+        flags |= Hpet::route_flags(route)
+            | TimerConfig::INTERRUPT_ENABLE
+            | TimerConfig::PERIODIC
+            | TimerConfig::VALUE_SET;
+        self.set_timer_flags(n, flags);
+        unsafe {
+            // With VALUE_SET, the first write sets the comparator, the
+            // second the period added to it on every interrupt.
+            self.write(timer_comparator(n), self.counter() + period);
+            self.write(timer_comparator(n), period);
+        }
+        true
+    }
+
+    pub fn stop(&self, n: usize) {
+        let flags = self.timer_flags(n) - (TimerConfig::INTERRUPT_ENABLE | TimerConfig::PERIODIC);
+        self.set_timer_flags(n, flags);
+    }
+
+    /// Whether a level triggered interrupt of timer `n` is active.
+    pub fn is_pending(&self, n: usize) -> bool {
+        unsafe { self.read(INTERRUPT_STATUS) & (1 << n) != 0 }
+    }
+
+    /// Acknowledge a level triggered interrupt of timer `n`.
+    pub fn acknowledge(&self, n: usize) {
+        unsafe { self.write(INTERRUPT_STATUS, 1 << n) };
+    }
+}
+
+/// The first 64-bit counter value at or after `last` whose low half is
+/// `counter`, a 32-bit counter value.
+fn extend(last: u64, counter: u64) -> u64 {
+    let extended = (last & !0xffff_ffff) | counter;
+    if extended < last {
+        extended + (1 << 32)
+    } else {
+        extended
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// The HPET, if `init` found one.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.r#try()
+}
+
+/// Find the HPET through ACPI and start its main counter.
+/// Requires ACPI to be initialized.
+pub fn init() -> Option<&'static Hpet> {
+    let table = acpi::hpet_table()?;
+    let address: GenericAddress = unsafe { read_unaligned(addr_of!(table.base_address)) };
+    if address.address_space != GenericAddress::SYSTEM_MEMORY {
+        warn!("HPET isn't memory mapped");
+        return None;
+    }
+
+    let hpet = unsafe { Hpet::new(phys_to_virt(address.address) as usize) };
+    if hpet.period_fs == 0 || hpet.period_fs > 100_000_000 {
+        // The spec caps the period at 100ns
+        warn!("HPET has an invalid period: {}fs", hpet.period_fs);
+        return None;
+    }
+
+    // Stop and reset the counter, and start with every timer disabled.
+    hpet.disable();
+    unsafe { hpet.write(MAIN_COUNTER, 0) };
+    for n in 0..hpet.timer_count() {
+        hpet.stop(n);
+    }
+    hpet.enable();
+
+    debug!(
+        "HPET at {:#x}: {} Hz, {} timers",
+        { address.address },
+        hpet.frequency(),
+        hpet.timer_count()
+    );
+    Some(HPET.call_once(|| hpet))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn counter_advances() {
+        let hpet = hpet().expect("QEMU has an HPET");
+        let start = hpet.counter();
+        crate::time::busy_wait(core::time::Duration::from_micros(100));
+        assert!(hpet.counter() > start);
+    }
+
+    #[test_case]
+    fn counter_extension_handles_wraparound() {
+        assert_eq!(extend(0x10, 0x20), 0x20);
+        assert_eq!(extend(0x1_0000_0010, 0x20), 0x1_0000_0020);
+        assert_eq!(extend(0xffff_fff0, 0x10), 0x1_0000_0010);
+        assert_eq!(extend(0x1_ffff_fff0, 0xffff_fff0), 0x1_ffff_fff0);
+    }
+
+    #[test_case]
+    fn tick_conversion() {
+        let hpet = hpet().unwrap();
+        let ticks = hpet.frequency();
+        let ns = hpet.ticks_to_ns(ticks);
+        // One second, give or take rounding
+        assert!((999_999_000..=1_000_001_000).contains(&ns));
+        assert!((hpet.ns_to_ticks(ns) as i64 - ticks as i64).abs() <= 1);
+    }
+
+    #[test_case]
+    fn one_shot_comparator_fires() {
+        let hpet = hpet().unwrap();
+        let n = hpet.timer_count() - 1;
+        let input = hpet.timer(n).routes.trailing_zeros() as u8;
+        hpet.acknowledge(n);
+        hpet.set_one_shot(
+            n,
+            hpet.counter() + hpet.ns_to_ticks(100_000),
+            Route::IoApic(input),
+        );
+        // Level triggered interrupts are visible in the status register,
+        // even though the I/O APIC itself masks them.
+        while !hpet.is_pending(n) {
+            core::hint::spin_loop();
+        }
+        hpet.stop(n);
+        hpet.acknowledge(n);
+    }
+}
@@ -0,0 +1,227 @@
+//! Local APIC timer.
+//! https://wiki.osdev.org/APIC_timer
+//!
+//! Every CPU has its own. Its frequency isn't architectural, so it's
+//! calibrated once at boot against the HPET, or the PIT if there is none,
+//! along with the TSC. When the CPU supports it, one-shot events use
+//! TSC-deadline mode, which doesn't drift with the APIC timer divider.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::interrupts::apic::{
+    self, LVT_MASKED, LVT_TIMER, TIMER_CURRENT_COUNT, TIMER_DIVIDE, TIMER_INITIAL_COUNT,
+};
+use crate::interrupts::{self, ExceptionStackFrame};
+use crate::time::{self, hpet, pit};
+use crate::x86::{self, cpuid, cpuid::Features, Msr};
+
+/// Interrupt vector of the timer, right after the legacy IRQs.
+pub const VECTOR: u8 = 0x30;
+
+/// Divide Configuration Register value for a divider of 16.
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// How long calibration measures for.
+const CALIBRATION_MS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Interrupt once the count reaches 0.
+    OneShot = 0b00,
+    /// Interrupt every time the count reaches 0, then reload it.
+    Periodic = 0b01,
+    /// Interrupt once the TSC reaches IA32_TSC_DEADLINE.
+    TscDeadline = 0b10,
+}
+
+impl Mode {
+    fn lvt(self) -> u32 {
+        (self as u32) << 17 | VECTOR as u32
+    }
+}
+
+/// APIC timer ticks per millisecond, with `DIVIDE_BY_16`. 0 until
+/// calibrated.
+static TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+/// TSC frequency in Hz. 0 until calibrated.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+/// Number of timer interrupts received, on all CPUs.
+static EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Called on every timer interrupt, e.g. by the scheduler.
+/// Only lock with interrupts disabled, or the handler could deadlock.
+static EVENT_HANDLER: Mutex<Option<fn()>> = Mutex::new(None);
+
+extern "x86-interrupt" fn timer_handler(_frame: ExceptionStackFrame) {
+    EVENTS.fetch_add(1, Ordering::Relaxed);
+    let handler = *EVENT_HANDLER.lock();
+    apic::end_of_interrupt();
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+/// Wait for `ms` milliseconds without interrupts, using the best reference
+/// we have.
+fn reference_wait_ms(ms: u64) {
+    match hpet::hpet() {
+        Some(hpet) => {
+            let target = hpet.now_ns() + ms * 1_000_000;
+            while hpet.now_ns() < target {
+                core::hint::spin_loop();
+            }
+        }
+        None => pit::busy_wait_us(ms * 1000),
+    }
+}
+
+/// Measure the APIC timer and TSC frequencies.
+/// Only needed once, as all CPUs share them.
+fn calibrate() {
+    apic::write(TIMER_DIVIDE, DIVIDE_BY_16);
+    apic::write(LVT_TIMER, LVT_MASKED | Mode::OneShot.lvt());
+
+    let (apic_ticks, tsc_ticks) = x86::without_interrupts(|| {
+        apic::write(TIMER_INITIAL_COUNT, u32::MAX);
+        let tsc_start = x86::rdtsc();
+        reference_wait_ms(CALIBRATION_MS);
+        let remaining = apic::read(TIMER_CURRENT_COUNT);
+        let tsc_end = x86::rdtsc();
+        apic::write(TIMER_INITIAL_COUNT, 0);
+        ((u32::MAX - remaining) as u64, tsc_end - tsc_start)
+    });
+
+    TICKS_PER_MS.store(apic_ticks / CALIBRATION_MS, Ordering::Relaxed);
+    TSC_HZ.store(tsc_ticks * 1000 / CALIBRATION_MS, Ordering::Relaxed);
+}
+
+/// APIC timer frequency in Hz, after the divider. 0 before `init`.
+pub fn frequency() -> u64 {
+    TICKS_PER_MS.load(Ordering::Relaxed) * 1000
+}
+
+/// TSC frequency in Hz. 0 before `init`.
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Whether one-shot events use TSC-deadline mode.
+pub fn has_tsc_deadline() -> bool {
+    TSC_DEADLINE.load(Ordering::Relaxed)
+}
+
+/// Number of timer interrupts received so far.
+pub fn events() -> u64 {
+    EVENTS.load(Ordering::Relaxed)
+}
+
+/// Call `handler` on every timer interrupt, after the EOI.
+pub fn set_event_handler(handler: Option<fn()>) {
+    x86::without_interrupts(|| *EVENT_HANDLER.lock() = handler);
+}
+
+/// APIC timer count for `duration`, at least 1 since 0 stops the timer.
+fn count_for(duration: Duration) -> u32 {
+    let ticks = duration.as_nanos() * TICKS_PER_MS.load(Ordering::Relaxed) as u128 / 1_000_000;
+    ticks.clamp(1, u32::MAX as u128) as u32
+}
+
+/// Interrupt `hz` times per second, until `stop` or `set_next_event`.
+pub fn start_periodic(hz: u32) {
+    apic::write(TIMER_INITIAL_COUNT, 0);
+    apic::write(LVT_TIMER, Mode::Periodic.lvt());
+    apic::write(TIMER_DIVIDE, DIVIDE_BY_16);
+    apic::write(
+        TIMER_INITIAL_COUNT,
+        count_for(Duration::from_secs(1) / hz.max(1)),
+    );
+}
+
+/// Interrupt once, at `deadline` on the `time::uptime` clock.
+/// This replaces any pending event, and stops periodic interrupts: with
+/// nothing else ticking, the CPU stays idle until the next event.
+/// Deadlines in the past fire right away.
+pub fn set_next_event(deadline: Duration) {
+    let delay = deadline.saturating_sub(time::uptime());
+    if has_tsc_deadline() {
+        let tsc_ticks = delay.as_nanos() * tsc_frequency() as u128 / 1_000_000_000;
+        apic::write(LVT_TIMER, Mode::TscDeadline.lvt());
+        // The deadline can only be written once the LVT is in TSC-deadline
+        // mode, see SDM 10.5.4.1.
+        unsafe { Msr::TSC_DEADLINE.write(x86::rdtsc() + tsc_ticks.max(1) as u64) };
+    } else {
+        apic::write(TIMER_INITIAL_COUNT, 0);
+        apic::write(LVT_TIMER, Mode::OneShot.lvt());
+        apic::write(TIMER_DIVIDE, DIVIDE_BY_16);
+        apic::write(TIMER_INITIAL_COUNT, count_for(delay));
+    }
+}
+
+/// Cancel pending events and periodic interrupts.
+pub fn stop() {
+    apic::write(LVT_TIMER, LVT_MASKED | Mode::OneShot.lvt());
+    apic::write(TIMER_INITIAL_COUNT, 0);
+    if has_tsc_deadline() {
+        unsafe { Msr::TSC_DEADLINE.write(0) };
+    }
+}
+
+/// Enable the local APIC and its timer on the current CPU, calibrating it
+/// first if needed. The timer is left stopped.
+/// Requires the HPET or PIT to be initialized.
+pub fn init() {
+    apic::init();
+    interrupts::set_handler(VECTOR, timer_handler);
+
+    if TICKS_PER_MS.load(Ordering::Relaxed) == 0 {
+        calibrate();
+        let supported = cpuid::has(Features::TSC_DEADLINE);
+        TSC_DEADLINE.store(supported, Ordering::Relaxed);
+        debug!(
+            "APIC timer: {} Hz, TSC: {} Hz, TSC-deadline: {}",
+            frequency(),
+            tsc_frequency(),
+            supported
+        );
+    }
+    stop();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn wait_events(count: u64) {
+        let target = events() + count;
+        while events() < target {
+            x86::hlt();
+        }
+    }
+
+    #[test_case]
+    fn is_calibrated() {
+        assert!(frequency() > 0);
+        assert!(tsc_frequency() > 0);
+    }
+
+    #[test_case]
+    fn periodic_interrupts_fire() {
+        start_periodic(1000);
+        wait_events(3);
+        stop();
+    }
+
+    #[test_case]
+    fn next_event_fires() {
+        let start = time::uptime();
+        set_next_event(start + Duration::from_millis(2));
+        wait_events(1);
+        assert!(time::uptime() >= start + Duration::from_millis(1));
+        stop();
+    }
+}
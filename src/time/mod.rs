@@ -0,0 +1,118 @@
+//! Timekeeping: timers, the monotonic clock and delays.
+
+pub mod hpet;
+pub mod lapic;
+pub mod pit;
+pub mod rtc;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::boot::StivaleStruct;
+use crate::x86;
+
+pub use rtc::DateTime;
+
+/// Frequency of the periodic tick.
+pub const TICK_HZ: u32 = 1000;
+
+/// UNIX time in seconds when `uptime` was 0.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// How far apart the RTC and the bootloader's epoch may be before we
+/// complain.
+const EPOCH_TOLERANCE_SECS: u64 = 5;
+
+/// Start the timers and read the wall clock. Requires ACPI to be
+/// initialized.
+pub fn init(boot_info: &StivaleStruct) {
+    if hpet::init().is_none() {
+        debug!("No HPET, timekeeping relies on the PIT");
+    }
+    pit::init(TICK_HZ);
+    lapic::init();
+    init_wall_clock(boot_info);
+}
+
+fn init_wall_clock(boot_info: &StivaleStruct) {
+    let rtc = rtc::read().map(|date| date.to_unix());
+    let now = match (rtc, boot_info.epoch()) {
+        (Some(rtc), Some(epoch)) => {
+            // The epoch was read at boot, some time ago.
+            let epoch = epoch + uptime().as_secs();
+            let drift = rtc.abs_diff(epoch);
+            if drift > EPOCH_TOLERANCE_SECS {
+                warn!("RTC and boot epoch disagree by {}s, using the RTC", drift);
+            }
+            rtc
+        }
+        (Some(rtc), None) => rtc,
+        (None, Some(epoch)) => {
+            warn!("Invalid RTC date, using the boot epoch");
+            epoch + uptime().as_secs()
+        }
+        (None, None) => {
+            warn!("No wall clock available");
+            return;
+        }
+    };
+    BOOT_TIME.store(now - uptime().as_secs(), Ordering::Relaxed);
+    log!("Wall clock: {}", DateTime::from_unix(now));
+}
+
+/// The current calendar time.
+#[derive(Debug, Clone, Copy)]
+pub struct WallClock {
+    /// Time since 1970-01-01 00:00:00 UTC.
+    pub unix: Duration,
+    pub utc: DateTime,
+}
+
+/// The current calendar time: the RTC time at boot, advanced by `uptime`.
+/// Starts at the UNIX epoch if no clock could be read.
+pub fn wall_clock() -> WallClock {
+    let unix = Duration::from_secs(BOOT_TIME.load(Ordering::Relaxed)) + uptime();
+    WallClock {
+        unix,
+        utc: DateTime::from_unix(unix.as_secs()),
+    }
+}
+
+/// Time elapsed since the timers were started.
+/// Uses the HPET if there is one, the PIT tick count otherwise.
+pub fn uptime() -> Duration {
+    if let Some(hpet) = hpet::hpet() {
+        return Duration::from_nanos(hpet.now_ns());
+    }
+
+    let frequency = pit::frequency() as u64;
+    if frequency == 0 {
+        return Duration::ZERO;
+    }
+    let ticks = pit::ticks();
+    Duration::from_secs(ticks / frequency)
+        + Duration::from_nanos((ticks % frequency) * 1_000_000_000 / frequency)
+}
+
+/// Sleep for at least `ms` milliseconds, halting the CPU in the meantime.
+/// Falls back to busy waiting when interrupts are disabled, since the tick
+/// would never come.
+pub fn sleep_ms(ms: u64) {
+    if !x86::interrupts_enabled() || pit::frequency() == 0 {
+        busy_wait(Duration::from_millis(ms));
+        return;
+    }
+
+    // One more tick for rounding, another since we may be right before
+    // the next tick.
+    let ticks = ms * pit::frequency() as u64 / 1000 + 2;
+    let target = pit::ticks() + ticks;
+    while pit::ticks() < target {
+        x86::hlt();
+    }
+}
+
+/// Spin for at least `duration`, without relying on interrupts.
+pub fn busy_wait(duration: Duration) {
+    pit::busy_wait_us(duration.as_micros() as u64);
+}
@@ -0,0 +1,157 @@
+//! 8253/8254 Programmable Interval Timer.
+//! https://wiki.osdev.org/Programmable_Interval_Timer
+//!
+//! Channel 0 drives IRQ 0 and is our periodic tick. Channel 2, normally
+//! wired to the PC speaker, can be polled without interrupts and is used for
+//! busy waiting.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::interrupts::{self, ExceptionStackFrame};
+use crate::io::{inb, outb};
+use crate::random;
+use crate::time::hpet;
+
+/// Frequency of the PIT oscillator, in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port B: bit 0 gates channel 2, bit 1 connects it to
+/// the speaker, bit 5 reads its output.
+const PORT_B: u16 = 0x61;
+
+// Mode/Command register fields
+const SELECT_CHANNEL0: u8 = 0b00 << 6;
+const SELECT_CHANNEL2: u8 = 0b10 << 6;
+const ACCESS_LOHI: u8 = 0b11 << 4;
+/// Interrupt on terminal count: output goes high once the count reaches 0.
+const MODE_ONE_SHOT: u8 = 0b000 << 1;
+/// Rate generator: a pulse every `divisor` oscillations.
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+const IRQ: u8 = 0;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+extern "x86-interrupt" fn timer_handler(_frame: ExceptionStackFrame) {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed);
+    random::add_interrupt_randomness(ticks);
+    match hpet::hpet() {
+        // Don't let the HPET wrap around unnoticed.
+        Some(hpet) if hpet.wraps_around() => {
+            hpet.extended_counter();
+        }
+        _ => {}
+    }
+    interrupts::end_of_interrupt(IRQ);
+}
+
+/// Divisor giving the closest frequency to `hz`. A divisor of 0 means 65536.
+fn divisor_for(hz: u32) -> u16 {
+    let divisor = (BASE_FREQUENCY + hz / 2) / hz.max(1);
+    match divisor {
+        0 | 1 => 2, // 1 is not allowed in mode 2
+        0x10000.. => 0,
+        divisor => divisor as u16,
+    }
+}
+
+fn frequency_of(divisor: u16) -> u32 {
+    let divisor = if divisor == 0 {
+        0x10000
+    } else {
+        divisor as u32
+    };
+    BASE_FREQUENCY / divisor
+}
+
+/// Make channel 0 interrupt at about `hz` times per second.
+/// Returns the frequency actually programmed.
+pub fn set_frequency(hz: u32) -> u32 {
+    let divisor = divisor_for(hz);
+    crate::x86::without_interrupts(|| unsafe {
+        outb(COMMAND, SELECT_CHANNEL0 | ACCESS_LOHI | MODE_RATE_GENERATOR);
+        outb(CHANNEL0, divisor as u8);
+        outb(CHANNEL0, (divisor >> 8) as u8);
+    });
+    let frequency = frequency_of(divisor);
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+/// Start the periodic tick.
+pub fn init(hz: u32) {
+    let frequency = set_frequency(hz);
+    interrupts::set_irq_handler(IRQ, timer_handler);
+    debug!("PIT ticking at {} Hz", frequency);
+}
+
+/// Stop delivering ticks, e.g. once another timer took over.
+pub fn disable() {
+    crate::interrupts::pic::mask(IRQ);
+}
+
+/// Number of ticks since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Tick frequency in Hz, 0 before `init`.
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Wait for `count` oscillations of the PIT (at most 65535), polling
+/// channel 2. Works with interrupts disabled.
+pub fn wait_oscillations(count: u16) {
+    unsafe {
+        // Gate channel 2 off and disconnect the speaker.
+        let port_b = inb(PORT_B) & !0b11;
+        outb(PORT_B, port_b);
+
+        outb(COMMAND, SELECT_CHANNEL2 | ACCESS_LOHI | MODE_ONE_SHOT);
+        outb(CHANNEL2, count as u8);
+        outb(CHANNEL2, (count >> 8) as u8);
+
+        // Raising the gate starts counting.
+        outb(PORT_B, port_b | 1);
+        while inb(PORT_B) & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+        outb(PORT_B, port_b);
+    }
+}
+
+/// Busy wait for `us` microseconds, without relying on interrupts.
+pub fn busy_wait_us(us: u64) {
+    let mut remaining = us * BASE_FREQUENCY as u64 / 1_000_000;
+    while remaining > 0 {
+        let count = remaining.min(u16::MAX as u64);
+        wait_oscillations(count as u16);
+        remaining -= count;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn divisor_is_clamped() {
+        assert_eq!(divisor_for(1000), 1193);
+        assert_eq!(divisor_for(1), 0);
+        assert_eq!(divisor_for(BASE_FREQUENCY), 2);
+        assert_eq!(frequency_of(0), 18);
+    }
+
+    #[test_case]
+    fn ticks_advance() {
+        let start = ticks();
+        while ticks() == start {
+            crate::x86::hlt();
+        }
+    }
+}
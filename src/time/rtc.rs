@@ -0,0 +1,223 @@
+//! CMOS Real-Time Clock.
+//! https://wiki.osdev.org/CMOS#The_Real-Time_Clock
+//!
+//! Only read once at boot: afterwards, the wall clock is kept by adding the
+//! monotonic clock to the boot time.
+
+use core::fmt;
+
+use crate::io::{inb, outb};
+use crate::x86;
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+// CMOS registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+/// Status A: the RTC is updating its registers, which may be inconsistent.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: hours are 0-23 instead of 1-12 with a PM bit.
+const HOURS_24: u8 = 1 << 1;
+/// Status B: values are binary instead of BCD.
+const BINARY: u8 = 1 << 2;
+/// Hours register in 12-hour mode.
+const PM: u8 = 1 << 7;
+
+/// A point in time, broken down in the UTC calendar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC.
+    pub fn to_unix(self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        days as u64 * 86_400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix(timestamp: u64) -> Self {
+        let (year, month, day) = civil_from_days((timestamp / 86_400) as i64);
+        let seconds = timestamp % 86_400;
+        Self {
+            year: year as u16,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+}
+
+/// ISO 8601
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        outb(INDEX, register);
+        inb(DATA)
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Raw date registers: seconds, minutes, hours, day, month, year.
+fn read_raw() -> [u8; 6] {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(read_register)
+}
+
+/// Decode raw registers according to the Status B format flags.
+fn decode(raw: [u8; 6], status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year] = raw;
+    let convert = |value: u8| {
+        if status_b & BINARY != 0 {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+
+    let mut hour_value = convert(hour & !PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 AM is midnight, 12 PM is noon.
+        hour_value %= 12;
+        if hour & PM != 0 {
+            hour_value += 12;
+        }
+    }
+
+    DateTime {
+        // The century register isn't reliably there, assume the 21st.
+        year: 2000 + convert(year) as u16,
+        month: convert(month),
+        day: convert(day),
+        hour: hour_value,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+/// Read the current date from the RTC, or None if it makes no sense.
+pub fn read() -> Option<DateTime> {
+    x86::without_interrupts(|| {
+        // An update may start right after we checked for one: read until
+        // two reads agree.
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let date = decode(raw, read_register(STATUS_B));
+        Some(date).filter(DateTime::is_valid)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn unix_round_trip() {
+        let date = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 37,
+            second: 42,
+        };
+        assert_eq!(date.to_unix(), 1_709_213_862);
+        assert_eq!(DateTime::from_unix(date.to_unix()), date);
+        assert_eq!(DateTime::from_unix(0).year, 1970);
+    }
+
+    #[test_case]
+    fn decode_bcd_12_hour() {
+        // 12:05:09 AM, 31/12/99
+        let date = decode([0x09, 0x05, 0x12, 0x31, 0x12, 0x99], 0);
+        assert_eq!(date.hour, 0);
+        assert_eq!(date.minute, 5);
+        assert_eq!(date.year, 2099);
+        // 1 PM
+        assert_eq!(decode([0, 0, PM | 0x01, 1, 1, 0], 0).hour, 13);
+        // 12 PM, binary
+        assert_eq!(decode([0, 0, PM | 12, 1, 1, 0], BINARY).hour, 12);
+    }
+
+    #[test_case]
+    fn rtc_is_readable() {
+        let date = read().expect("QEMU has an RTC");
+        assert!(date.year >= 2021);
+    }
+}
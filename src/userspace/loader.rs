@@ -0,0 +1,311 @@
+//! Loading statically linked ELF programs into their own address space.
+//!
+//! Every `PT_LOAD` segment is copied into fresh frames, mapped with the
+//! permissions of its flags. The initial stack follows the System V ABI:
+//! from the stack pointer up, argc, the argv pointers, the envp pointers and
+//! the auxiliary vector, with the strings they point to at the top.
+//! https://refspecs.linuxfoundation.org/elf/x86_64-abi-0.99.pdf, 3.4.1
+
+use core::mem::size_of;
+
+use crate::elf::{Elf, ElfError, ProgramHeader, ET_EXEC, PF_W, PF_X, PT_LOAD};
+use crate::memory::address_space::AddressSpace;
+use crate::memory::paging::{MapError, PageFlags, PAGE_SIZE};
+use crate::memory::user::USER_END;
+use crate::random;
+use crate::userspace::enter_user_in;
+
+/// Top of the initial stack. The last page of user space stays unmapped.
+const STACK_TOP: u64 = USER_END - PAGE_SIZE;
+const STACK_PAGES: u64 = 16;
+const STACK_BOTTOM: u64 = STACK_TOP - STACK_PAGES * PAGE_SIZE;
+
+/// Auxiliary vector entry types.
+const AT_NULL: u64 = 0;
+const AT_IGNORE: u64 = 1;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+const AUX_ENTRIES: u64 = 7;
+
+/// Bytes of randomness for AT_RANDOM, seeding the program's stack canaries.
+const RANDOM_BYTES: u64 = 16;
+
+/// A program loaded in its own address space, ready to run. Dropping it
+/// frees its memory.
+pub struct Program {
+    space: AddressSpace,
+    entry: u64,
+    stack_pointer: u64,
+}
+
+impl Program {
+    /// Load the ELF executable `data`, with arguments `argv` and environment
+    /// `envp`. Those must fit in a page along with the auxiliary vector.
+    pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Self, ElfError> {
+        let elf = Elf::parse(data)?;
+        if elf.header.elf_type != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if elf.header.phentsize as usize != size_of::<ProgramHeader>() {
+            return Err(ElfError::BadSegment);
+        }
+        let mut entry_is_executable = false;
+        for segment in elf.program_headers() {
+            let segment = segment?;
+            if segment.segment_type != PT_LOAD {
+                continue;
+            }
+            check_segment(&segment)?;
+            let end = segment.vaddr + segment.memsz;
+            if segment.flags & PF_X != 0 && (segment.vaddr..end).contains(&elf.header.entry) {
+                entry_is_executable = true;
+            }
+        }
+        if !entry_is_executable {
+            return Err(ElfError::BadEntry);
+        }
+
+        let mut program = Self {
+            space: AddressSpace::new().map_err(map_error)?,
+            entry: elf.header.entry,
+            stack_pointer: 0,
+        };
+        for segment in elf.program_headers() {
+            let segment = segment?;
+            if segment.segment_type == PT_LOAD {
+                program.load_segment(&segment, elf.segment_data(&segment)?)?;
+            }
+        }
+        program.stack_pointer = program.build_stack(&elf, argv, envp)?;
+        Ok(program)
+    }
+
+    /// Run the program until it exits, and return its exit code.
+    pub fn run(&mut self) -> u64 {
+        unsafe { enter_user_in(&mut self.space, self.entry, self.stack_pointer) }
+    }
+
+    /// Copy `segment`, whose bytes in the file are `data`, into its pages.
+    /// The rest of the segment, past the file bytes, stays zeroed.
+    fn load_segment(&mut self, segment: &ProgramHeader, data: &[u8]) -> Result<(), ElfError> {
+        let mut flags = PageFlags::empty();
+        if segment.flags & PF_W != 0 {
+            flags |= PageFlags::WRITABLE;
+        }
+        if segment.flags & PF_X == 0 {
+            flags |= PageFlags::NO_EXECUTE;
+        }
+
+        let start = segment.vaddr & !(PAGE_SIZE - 1);
+        let end = (segment.vaddr + segment.memsz + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        self.space
+            .map(start, end - start, flags)
+            .map_err(map_error)?;
+        self.space.write(segment.vaddr, data).map_err(map_error)
+    }
+
+    /// Map the stack and lay out the arguments on it. Returns the initial
+    /// stack pointer.
+    fn build_stack(&mut self, elf: &Elf, argv: &[&str], envp: &[&str]) -> Result<u64, ElfError> {
+        let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+        self.space
+            .map(STACK_BOTTOM, STACK_PAGES * PAGE_SIZE, flags)
+            .map_err(map_error)?;
+        let top_page = STACK_TOP - PAGE_SIZE;
+        let space = &self.space;
+        // Everything written is checked to be in the top page.
+        let write = |address: u64, bytes: &[u8]| space.write(address, bytes).unwrap();
+
+        let random = STACK_TOP - RANDOM_BYTES;
+        let strings_size: u64 = argv.iter().chain(envp).map(|s| s.len() as u64 + 1).sum();
+        let strings = random
+            .checked_sub(strings_size)
+            .filter(|&strings| strings >= top_page)
+            .ok_or(ElfError::ArgumentsTooLarge)?;
+        let words = 1 + argv.len() as u64 + 1 + envp.len() as u64 + 1 + 2 * AUX_ENTRIES;
+        // The ABI wants a 16 byte aligned stack pointer at entry.
+        let stack_pointer = (strings & !15)
+            .checked_sub(words * 8)
+            .filter(|&sp| sp >= top_page)
+            .ok_or(ElfError::ArgumentsTooLarge)?
+            & !15;
+
+        let mut bytes = [0u8; RANDOM_BYTES as usize];
+        random::get_random_bytes(&mut bytes);
+        write(random, &bytes);
+
+        let mut string = strings;
+        let mut word = stack_pointer;
+        let mut push = |value: u64| {
+            write(word, &value.to_ne_bytes());
+            word += 8;
+        };
+        push(argv.len() as u64);
+        for list in [argv, envp] {
+            for s in list {
+                write(string, s.as_bytes());
+                write(string + s.len() as u64, &[0]);
+                push(string);
+                string += s.len() as u64 + 1;
+            }
+            push(0);
+        }
+
+        let (phdr_type, phdr) = match program_headers_address(elf) {
+            Some(address) => (AT_PHDR, address),
+            None => (AT_IGNORE, 0),
+        };
+        let aux = [
+            (phdr_type, phdr),
+            (AT_PHENT, size_of::<ProgramHeader>() as u64),
+            (AT_PHNUM, elf.header.phnum as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, elf.header.entry),
+            (AT_RANDOM, random),
+            (AT_NULL, 0),
+        ];
+        for (key, value) in aux {
+            push(key);
+            push(value);
+        }
+        Ok(stack_pointer)
+    }
+}
+
+fn map_error(err: MapError) -> ElfError {
+    match err {
+        MapError::OutOfMemory | MapError::TooManyAreas => ElfError::OutOfMemory,
+        // Overlapping segments.
+        MapError::AlreadyMapped | MapError::HugePage | MapError::BadRange => ElfError::BadSegment,
+    }
+}
+
+/// Check that `segment` is consistent, and in user space below the stack.
+fn check_segment(segment: &ProgramHeader) -> Result<(), ElfError> {
+    let end = segment.vaddr.checked_add(segment.memsz);
+    match end {
+        Some(end) if segment.filesz <= segment.memsz && end <= STACK_BOTTOM => Ok(()),
+        _ => Err(ElfError::BadSegment),
+    }
+}
+
+/// Where the program headers end up in the program's memory, if a segment
+/// loads them.
+fn program_headers_address(elf: &Elf) -> Option<u64> {
+    let phoff = elf.header.phoff;
+    elf.program_headers()
+        .filter_map(Result::ok)
+        .find(|segment| {
+            segment.segment_type == PT_LOAD
+                && segment.offset <= phoff
+                && phoff < segment.offset + segment.filesz
+        })
+        .map(|segment| segment.vaddr + phoff - segment.offset)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::{Header, PF_R};
+    use crate::memory::frame;
+    use crate::userspace::syscall::EXIT;
+    use core::ptr::write_unaligned;
+
+    const BASE: u64 = 0x0000_6000_0010_0000;
+    const CODE_OFFSET: usize = size_of::<Header>() + size_of::<ProgramHeader>();
+
+    /// An executable loading itself at `BASE`, running `code`.
+    fn image(code: &[u8], flags: u32) -> [u8; 256] {
+        let mut data = [0u8; 256];
+        let header = Header {
+            ident: *b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0",
+            elf_type: ET_EXEC,
+            machine: 62,
+            version: 1,
+            entry: BASE + CODE_OFFSET as u64,
+            phoff: size_of::<Header>() as u64,
+            shoff: 0,
+            flags: 0,
+            ehsize: size_of::<Header>() as u16,
+            phentsize: size_of::<ProgramHeader>() as u16,
+            phnum: 1,
+            shentsize: 0,
+            shnum: 0,
+            shstrndx: 0,
+        };
+        let segment = ProgramHeader {
+            segment_type: PT_LOAD,
+            flags,
+            offset: 0,
+            vaddr: BASE,
+            paddr: BASE,
+            filesz: (CODE_OFFSET + code.len()) as u64,
+            memsz: 0x2000,
+            align: PAGE_SIZE,
+        };
+        unsafe {
+            write_unaligned(data.as_mut_ptr() as *mut Header, header);
+            write_unaligned(
+                data[size_of::<Header>()..].as_mut_ptr() as *mut ProgramHeader,
+                segment,
+            );
+        }
+        data[CODE_OFFSET..CODE_OFFSET + code.len()].copy_from_slice(code);
+        data
+    }
+
+    #[test_case]
+    fn runs_with_arguments() {
+        let code = [
+            0x48, 0x8b, 0x3c, 0x24, // mov rdi, [rsp] (argc)
+            0x48, 0x8b, 0x74, 0x24, 0x08, // mov rsi, [rsp + 8] (argv[0])
+            0x0f, 0xb6, 0x06, // movzx eax, byte [rsi]
+            0x48, 0x01, 0xc7, // add rdi, rax
+            0xb8, EXIT as u8, 0, 0, 0, // mov eax, EXIT
+            0x0f, 0x05, // syscall
+        ];
+        let data = image(&code, PF_R | PF_X);
+        let available = frame::available();
+        let mut program = Program::load(&data, &["a", "b"], &["HOME=/"]).unwrap();
+        assert_eq!(program.run(), 2 + b'a' as u64);
+        drop(program);
+        assert_eq!(frame::available(), available);
+    }
+
+    #[test_case]
+    fn rejects_bad_programs() {
+        let data = image(&[0xf4], PF_R);
+        assert_eq!(
+            Program::load(&data, &[], &[]).err(),
+            Some(ElfError::BadEntry)
+        );
+
+        let mut data = image(&[0xf4], PF_R | PF_X);
+        data[16] = 3; // ET_DYN
+        assert_eq!(
+            Program::load(&data, &[], &[]).err(),
+            Some(ElfError::NotExecutable)
+        );
+
+        // vaddr in the kernel half.
+        let mut data = image(&[0xf4], PF_R | PF_X);
+        data[size_of::<Header>() + 16..][..8]
+            .copy_from_slice(&0xffff_8000_0000_0000u64.to_le_bytes());
+        assert_eq!(
+            Program::load(&data, &[], &[]).err(),
+            Some(ElfError::BadSegment)
+        );
+
+        let data = image(&[0xf4], PF_R | PF_X);
+        let argument = [b'x'; 4096];
+        let argument = core::str::from_utf8(&argument).unwrap();
+        assert_eq!(
+            Program::load(&data, &[argument], &[]).err(),
+            Some(ElfError::ArgumentsTooLarge)
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Running code in ring 3.
+//!
+//! `enter_user` saves the kernel's callee-saved registers and stack, then
+//! `iretq`s to user code. User code comes back through a trap: its handler
+//! calls `return_to_kernel`, which drops the handler's frame, restores the
+//! saved state and makes `enter_user` return.
+//!
+//! Only one thread may run user code for now, so the saved state is global,
+//! and so are the kernel stacks of the TSS and `syscall`: `enter_user_in`
+//! checks it.
+
+pub mod loader;
+pub mod syscall;
+
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::boot::StivaleStruct;
+use crate::interrupts::{self, KERNEL_DS, USER_CS, USER_DS};
+use crate::memory::address_space::{self, AddressSpace};
+use loader::Program;
+
+/// Interrupts enabled, and the always set bit 1.
+const USER_RFLAGS: u64 = 0x202;
+
+/// Kernel stack pointer while user code runs.
+static mut KERNEL_RSP: u64 = 0;
+
+/// Address space of the user code running, see `enter_user_in`. Null when
+/// none runs.
+static CURRENT_SPACE: AtomicPtr<AddressSpace> = AtomicPtr::new(ptr::null_mut());
+
+global_asm!(
+    ".global enter_user_asm",
+    "enter_user_asm:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov [rip + {kernel_rsp}], rsp",
+    // iretq frame
+    "push {user_ds}",
+    "push rsi",
+    "push {rflags}",
+    "push {user_cs}",
+    "push rdi",
+    // Don't leak kernel values.
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+    "",
+    ".global return_to_kernel_asm",
+    "return_to_kernel_asm:",
+    "mov rsp, [rip + {kernel_rsp}]",
+    // Leaving ring 3 nulled SS, and iretq nulled DS and ES.
+    "mov ax, {kernel_ds}",
+    "mov ss, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov rax, rdi",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    kernel_rsp = sym KERNEL_RSP,
+    kernel_ds = const KERNEL_DS,
+    user_ds = const USER_DS,
+    user_cs = const USER_CS,
+    rflags = const USER_RFLAGS,
+);
+
+extern "C" {
+    fn enter_user_asm(entry: u64, stack: u64) -> u64;
+    fn return_to_kernel_asm(value: u64) -> !;
+}
+
+/// Set the stack the CPU switches to when user code enters the kernel,
+/// through an interrupt or a system call.
+pub fn set_kernel_stack(top: u64) {
+    interrupts::set_kernel_stack(top);
+    syscall::set_kernel_stack(top);
+}
+
+/// Requires the interrupt tables.
+pub fn init() {
+    syscall::init();
+}
+
+/// Run every boot module as a program, one after the other, with its name
+/// as its only argument.
+pub fn run_modules(boot_info: &StivaleStruct) {
+    for module in boot_info.modules() {
+        let name = module.name();
+        match Program::load(module.data(), &[name], &[]) {
+            Ok(mut program) => {
+                let code = program.run();
+                log!("{} exited with code {}", name, code);
+            }
+            Err(err) => error!("Can't load {}: {:?}", name, err),
+        }
+    }
+}
+
+/// Run user code at `entry`, with stack pointer `stack`, in ring 3. Returns
+/// the value given to `return_to_kernel`.
+///
+/// # Safety
+/// `entry` and `stack` must be mapped user pages, and the kernel stack of
+/// the TSS must be set.
+pub unsafe fn enter_user(entry: u64, stack: u64) -> u64 {
+    enter_user_asm(entry, stack)
+}
+
+/// Run user code like `enter_user`, in `space`. System calls act on
+/// `space` meanwhile.
+///
+/// # Safety
+/// See `enter_user`, in `space`.
+pub unsafe fn enter_user_in(space: &mut AddressSpace, entry: u64, stack: u64) -> u64 {
+    let free = CURRENT_SPACE.compare_exchange(
+        ptr::null_mut(),
+        space,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+    assert!(free.is_ok(), "Another thread is running user code");
+    space.activate();
+    let value = enter_user(entry, stack);
+    address_space::activate_kernel();
+    CURRENT_SPACE.store(ptr::null_mut(), Ordering::Relaxed);
+    value
+}
+
+/// Call `f` with the address space of the running user code, if any.
+/// For system calls.
+pub fn with_current_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    let space = CURRENT_SPACE.load(Ordering::Relaxed);
+    // Only the thread running user code makes system calls, and
+    // `enter_user_in` lends us the space until it returns.
+    unsafe { space.as_mut() }.map(f)
+}
+
+/// What `enter_user` returns when user code is killed by exception
+/// `vector`, like a shell reports a process killed by a signal.
+pub const fn fault_exit_code(vector: u8) -> u64 {
+    128 + vector as u64
+}
+
+/// Leave user code for good, making `enter_user` return `value`.
+///
+/// # Safety
+/// Only from a handler of a trap from ring 3, with the user code entered by
+/// `enter_user`. Whatever is on the current stack is dropped.
+pub unsafe fn return_to_kernel(value: u64) -> ! {
+    return_to_kernel_asm(value)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::interrupts::{self, ExceptionStackFrame};
+    use crate::memory::frame;
+    use crate::memory::paging::{self, PageFlags};
+
+    const TRAP_VECTOR: u8 = 0x81;
+
+    /// Run `f` as if user code ran in `space`, for system calls.
+    pub fn with_space<R>(space: &mut AddressSpace, f: impl FnOnce() -> R) -> R {
+        CURRENT_SPACE.store(space, Ordering::Relaxed);
+        let value = f();
+        CURRENT_SPACE.store(ptr::null_mut(), Ordering::Relaxed);
+        value
+    }
+    const CODE: u64 = 0x0000_6000_0000_0000;
+    const STACK: u64 = CODE + 0x1000;
+
+    /// Run `code` in ring 3, with a page of stack, until it traps back.
+    pub fn run_code(code: &[u8]) -> u64 {
+        let code_frame = frame::allocate_zeroed().unwrap();
+        let stack_frame = frame::allocate_zeroed().unwrap();
+        unsafe {
+            // Written through the direct map, SMAP keeps us out of user pages.
+            code_frame
+                .virt()
+                .copy_from_nonoverlapping(code.as_ptr(), code.len());
+            paging::map(CODE, code_frame, PageFlags::USER).unwrap();
+            paging::map(
+                STACK,
+                stack_frame,
+                PageFlags::USER | PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+            )
+            .unwrap();
+        }
+
+        let value = unsafe { enter_user(CODE, STACK + 0x1000) };
+
+        unsafe {
+            frame::free(paging::unmap(CODE).unwrap());
+            frame::free(paging::unmap(STACK).unwrap());
+        }
+        value
+    }
+
+    extern "x86-interrupt" fn trap_handler(frame: ExceptionStackFrame) {
+        // The selector of the interrupted code gives its ring.
+        let ring = frame.cs & 3;
+        assert_eq!(frame.ip, CODE + 7);
+        unsafe { return_to_kernel(ring) }
+    }
+
+    #[test_case]
+    fn user_code_traps_back() {
+        interrupts::set_user_handler(TRAP_VECTOR, trap_handler);
+        // mov edi, 42; int 0x81; jmp $
+        let code = [0xbf, 42, 0, 0, 0, 0xcd, TRAP_VECTOR, 0xeb, 0xfe];
+        assert_eq!(run_code(&code), 3);
+    }
+
+    #[test_case]
+    fn user_faults_end_the_program() {
+        // ud2
+        assert_eq!(run_code(&[0x0f, 0x0b]), fault_exit_code(6));
+        // cli, not allowed in ring 3
+        assert_eq!(run_code(&[0xfa]), fault_exit_code(13));
+        // mov rax, [0]
+        let code = [0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0];
+        assert_eq!(run_code(&code), fault_exit_code(14));
+        // xor ecx, ecx; div ecx
+        assert_eq!(run_code(&[0x31, 0xc9, 0xf7, 0xf1]), fault_exit_code(0));
+    }
+}
@@ -0,0 +1,381 @@
+//! System calls.
+//!
+//! User code calls the kernel with `syscall`, or with `int 0x80` when
+//! debugging: the number goes in RAX, arguments in RDI, RSI, RDX, R10, R8
+//! and R9, and the result comes back in RAX. Errors are negative.
+//!
+//! `syscall` doesn't switch stacks: the entry stub uses `swapgs` to reach
+//! per-CPU data, where it saves the user stack pointer and finds the kernel
+//! stack. RCX and R11 hold the user RIP and RFLAGS, and are clobbered.
+
+use core::arch::global_asm;
+use core::ptr;
+use core::str;
+use core::time::Duration;
+
+use crate::interrupts::{self, KERNEL_CS, USER_DS};
+use crate::memory::paging::{MapError, PageFlags, PAGE_SIZE};
+use crate::memory::user::{self, UserCopyError};
+use crate::thread;
+use crate::userspace::{self, return_to_kernel};
+use crate::x86::registers::{Efer, EferFlags};
+use crate::x86::{self, Msr};
+
+pub const WRITE: u64 = 0;
+pub const EXIT: u64 = 1;
+pub const YIELD: u64 = 2;
+pub const SLEEP: u64 = 3;
+pub const MMAP: u64 = 4;
+
+// mmap protections
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// Vector of the `int 0x80` gate.
+pub const INT_VECTOR: u8 = 0x80;
+
+/// Longest message `write` logs at once.
+const MAX_WRITE: usize = 512;
+
+/// Where `mmap` places mappings without an address.
+const MMAP_BASE: u64 = 0x0000_2000_0000_0000;
+
+/// Flags cleared on entry: interrupts, trap, direction and alignment check.
+const SFMASK: u64 = (1 << 9) | (1 << 8) | (1 << 10) | (1 << 18);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    NoSuchCall = 1,
+    BadAddress = 2,
+    InvalidArgument = 3,
+    OutOfMemory = 4,
+}
+
+impl SyscallError {
+    /// Value returned to user code.
+    pub fn to_return(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+}
+
+impl From<UserCopyError> for SyscallError {
+    fn from(_: UserCopyError) -> Self {
+        SyscallError::BadAddress
+    }
+}
+
+impl From<MapError> for SyscallError {
+    fn from(err: MapError) -> Self {
+        match err {
+            MapError::OutOfMemory => SyscallError::OutOfMemory,
+            MapError::TooManyAreas => SyscallError::OutOfMemory,
+            MapError::AlreadyMapped | MapError::HugePage | MapError::BadRange => {
+                SyscallError::InvalidArgument
+            }
+        }
+    }
+}
+
+/// Registers of a system call, as pushed by the entry stubs.
+#[repr(C)]
+pub struct SyscallFrame {
+    pub number: u64,
+    pub args: [u64; 6],
+}
+
+/// Per-CPU data, reached through GS after `swapgs`.
+#[repr(C)]
+struct CpuLocal {
+    /// Offset 0
+    kernel_rsp: u64,
+    /// Offset 8
+    user_rsp: u64,
+}
+
+static mut CPU_LOCAL: CpuLocal = CpuLocal {
+    kernel_rsp: 0,
+    user_rsp: 0,
+};
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[8], rsp",
+    "mov rsp, gs:[0]",
+    "push qword ptr gs:[8]",
+    "push r11",
+    "push rcx",
+    "sti",
+    // SyscallFrame
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "add rsp, 8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "cli",
+    "pop rcx",
+    "pop r11",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    "",
+    ".global int80_entry",
+    "int80_entry:",
+    // The CPU already switched to the kernel stack. Unlike `syscall`,
+    // `int` preserves RCX and R11.
+    "push r11",
+    "push rcx",
+    // Nor does it clear the direction and alignment check flags, which user
+    // code controls. AC would let us access user pages despite SMAP. Not
+    // `clac`, which faults on CPUs without SMAP.
+    "cld",
+    "pushfq",
+    "btr qword ptr [rsp], 18",
+    "popfq",
+    "sti",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "add rsp, 8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop rcx",
+    "pop r11",
+    "iretq",
+    dispatch = sym dispatch_entry,
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn int80_entry();
+}
+
+type Handler = fn(&[u64; 6]) -> Result<u64, SyscallError>;
+
+/// Indexed by system call number.
+static TABLE: [Handler; 5] = [sys_write, sys_exit, sys_yield, sys_sleep, sys_mmap];
+
+extern "C" fn dispatch_entry(frame: &mut SyscallFrame) -> u64 {
+    match dispatch(frame) {
+        Ok(value) => value,
+        Err(err) => err.to_return(),
+    }
+}
+
+pub fn dispatch(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    let handler = TABLE
+        .get(frame.number as usize)
+        .ok_or(SyscallError::NoSuchCall)?;
+    handler(&frame.args)
+}
+
+/// write(buf, len): log `len` bytes of text, returns how many were written.
+fn sys_write(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    let mut buf = [0; MAX_WRITE];
+    let len = (args[1] as usize).min(MAX_WRITE);
+    user::check_mapped(args[0], len, false)?;
+    unsafe { user::copy_from_user(&mut buf[..len], args[0])? };
+    let text = str::from_utf8(&buf[..len]).map_err(|_| SyscallError::InvalidArgument)?;
+    log!("[user] {}", text);
+    Ok(len as u64)
+}
+
+/// exit(code): leave user code, `enter_user` returns `code`.
+fn sys_exit(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    unsafe {
+        // Coming from `syscall`, GS is still swapped.
+        if Msr::GS_BASE.read() == ptr::addr_of!(CPU_LOCAL) as u64 {
+            x86::swapgs();
+        }
+        return_to_kernel(args[0])
+    }
+}
+
+/// yield(): let other threads run.
+fn sys_yield(_args: &[u64; 6]) -> Result<u64, SyscallError> {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// sleep(ms)
+fn sys_sleep(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    thread::sleep(Duration::from_millis(args[0]));
+    Ok(0)
+}
+
+/// mmap(address, len, prot): map zeroed memory at `address`, or anywhere if
+/// it is 0. Returns the address.
+fn sys_mmap(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    let (address, len, prot) = (args[0], args[1], args[2]);
+    if len == 0 || address % PAGE_SIZE != 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let len = len
+        .checked_add(PAGE_SIZE - 1)
+        .ok_or(SyscallError::InvalidArgument)?
+        & !(PAGE_SIZE - 1);
+    if address != 0 {
+        user::check_range(address, len as usize)?;
+    }
+
+    let mut flags = PageFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageFlags::NO_EXECUTE;
+    }
+    userspace::with_current_space(|space| {
+        let address = match address {
+            0 => space
+                .find_free(MMAP_BASE, len)
+                .ok_or(SyscallError::OutOfMemory)?,
+            address => address,
+        };
+        space.map(address, len, flags)?;
+        Ok(address)
+    })
+    .ok_or(SyscallError::InvalidArgument)?
+}
+
+/// Set the stack `syscall` switches to.
+pub fn set_kernel_stack(top: u64) {
+    unsafe { CPU_LOCAL.kernel_rsp = top };
+}
+
+/// Enable `syscall` and the `int 0x80` gate. Requires the TSS kernel stack.
+pub fn init() {
+    set_kernel_stack(interrupts::kernel_stack());
+    unsafe {
+        // The kernel doesn't use GS otherwise: it holds the user's value
+        // while in the kernel, and the entry stub swaps this one in.
+        Msr::KERNEL_GS_BASE.write(ptr::addr_of!(CPU_LOCAL) as u64);
+
+        // SYSCALL loads CS from STAR[47:32] and SS from the next selector.
+        // SYSRET loads SS from STAR[63:48] + 8, and CS from STAR[63:48] + 16.
+        let sysret_base = (USER_DS - 8) as u64;
+        Msr::STAR.write((sysret_base << 48) | ((KERNEL_CS as u64) << 32));
+        Msr::LSTAR.write(syscall_entry as *const () as u64);
+        Msr::SFMASK.write(SFMASK);
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+
+        interrupts::set_user_entry(INT_VECTOR, int80_entry);
+    }
+    debug!("System calls enabled");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::address_space::AddressSpace;
+    use crate::memory::paging;
+    use crate::memory::phys_to_virt;
+    use crate::memory::user::USER_END;
+    use crate::userspace::test::{run_code, with_space};
+
+    fn call(number: u64, args: [u64; 6]) -> Result<u64, SyscallError> {
+        dispatch(&SyscallFrame { number, args })
+    }
+
+    #[test_case]
+    fn dispatch_checks_arguments() {
+        assert_eq!(call(42, [0; 6]), Err(SyscallError::NoSuchCall));
+        let kernel = ptr::addr_of!(CPU_LOCAL) as u64;
+        assert_eq!(
+            call(WRITE, [kernel, 8, 0, 0, 0, 0]),
+            Err(SyscallError::BadAddress)
+        );
+        assert_eq!(
+            call(MMAP, [0x1234, 0x1000, PROT_READ, 0, 0, 0]),
+            Err(SyscallError::InvalidArgument)
+        );
+        assert_eq!(
+            call(MMAP, [USER_END, 0x1000, PROT_READ, 0, 0, 0]),
+            Err(SyscallError::BadAddress)
+        );
+        assert_eq!(SyscallError::NoSuchCall.to_return() as i64, -1);
+    }
+
+    #[test_case]
+    fn mmap_maps_zeroed_user_memory() {
+        let mut space = AddressSpace::new().unwrap();
+        let address = with_space(&mut space, || {
+            assert_eq!(
+                call(MMAP, [0, 1 << 63, PROT_READ, 0, 0, 0]),
+                Err(SyscallError::OutOfMemory)
+            );
+            call(MMAP, [0, 0x1800, PROT_READ | PROT_WRITE, 0, 0, 0]).unwrap()
+        });
+        let area = *space.area(address).unwrap();
+        assert_eq!(area.end - area.start, 2 * PAGE_SIZE);
+        for page in area.pages() {
+            let (phys, flags) = paging::lookup_in(space.pml4(), page).unwrap();
+            assert!(flags.contains(PageFlags::USER | PageFlags::WRITABLE | PageFlags::NO_EXECUTE));
+            assert_eq!(unsafe { *(phys_to_virt(phys) as *const u64) }, 0);
+        }
+        // Not in the kernel's address space, and kept by clones.
+        assert_eq!(paging::translate(address), None);
+        assert!(space.try_clone().unwrap().area(address).is_some());
+        // Without user code, there is nothing to map into.
+        assert_eq!(
+            call(MMAP, [0, 0x1000, PROT_READ, 0, 0, 0]),
+            Err(SyscallError::InvalidArgument)
+        );
+    }
+
+    #[test_case]
+    fn syscall_from_user() {
+        #[rustfmt::skip]
+        let code = [
+            0x48, 0x8d, 0x3d, 0x13, 0, 0, 0, // lea rdi, [rip + message]
+            0xbe, 2, 0, 0, 0,                // mov esi, 2
+            0x31, 0xc0,                      // xor eax, eax (write)
+            0x0f, 0x05,                      // syscall
+            0x48, 0x89, 0xc7,                // mov rdi, rax
+            0xb8, 1, 0, 0, 0,                // mov eax, 1 (exit)
+            0x0f, 0x05,                      // syscall
+            b'h', b'i',                      // message
+        ];
+        assert_eq!(run_code(&code), 2);
+        // The kernel still doesn't use GS.
+        assert_ne!(
+            unsafe { Msr::GS_BASE.read() },
+            ptr::addr_of!(CPU_LOCAL) as u64
+        );
+    }
+
+    #[test_case]
+    fn int80_from_user() {
+        #[rustfmt::skip]
+        let code = [
+            0xbf, 5, 0, 0, 0,  // mov edi, 5
+            0xb8, 1, 0, 0, 0,  // mov eax, 1 (exit)
+            0xcd, INT_VECTOR,  // int 0x80
+        ];
+        assert_eq!(run_code(&code), 5);
+    }
+}
@@ -6,6 +6,13 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
 
+use crate::memory::phys_to_virt;
+use crate::x86;
+
+/// Physical address of the text buffer. It's reached through the direct map,
+/// user address spaces don't map the bottom of memory.
+const BUFFER: u64 = 0xb8000;
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -117,10 +124,12 @@ impl Write for Writer {
     }
 }
 
+// Only lock with interrupts disabled, like the serial ports.
 lazy_static! {
     static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         buf: unsafe {
-            &mut *(0xb8000 as *mut [[Volatile<VgaChar>; Writer::WIDTH]; Writer::HEIGHT])
+            &mut *(phys_to_virt(BUFFER)
+                as *mut [[Volatile<VgaChar>; Writer::WIDTH]; Writer::HEIGHT])
         },
         color_code: ColorCode::new(Color::White, Color::Black),
         col: 0,
@@ -138,10 +147,18 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Release the screen, whoever holds it.
+///
+/// # Safety
+/// See `serial::force_unlock`.
+pub unsafe fn force_unlock() {
+    WRITER.force_unlock();
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     // unwrap will never panic since we always return Ok
-    WRITER.lock().write_fmt(args).unwrap();
+    x86::without_interrupts(|| WRITER.lock().write_fmt(args).unwrap());
 }
 
 #[cfg(test)]
@@ -153,7 +170,8 @@ mod test {
         let s = "Test string to test things";
         println!("{}", s);
         for (i, c) in s.chars().enumerate() {
-            let screen_char = WRITER.lock().buf[Writer::HEIGHT - 2][i].read();
+            let screen_char =
+                x86::without_interrupts(|| WRITER.lock().buf[Writer::HEIGHT - 2][i].read());
             assert_eq!(char::from(screen_char.char), c);
         }
     }
@@ -0,0 +1,413 @@
+//! CPU identification and feature detection.
+//! https://www.felixcloutier.com/x86/cpuid
+//! https://wiki.osdev.org/CPUID
+
+use core::{fmt, str};
+
+use bitflags::bitflags;
+use spin::Once;
+
+pub use core::arch::x86_64::CpuidResult;
+
+/// Query CPU information for `leaf` and `subleaf`.
+#[inline]
+#[allow(unused_unsafe)] // Safe since Rust 1.87
+pub fn query(leaf: u32, subleaf: u32) -> CpuidResult {
+    unsafe { core::arch::x86_64::__cpuid_count(leaf, subleaf) }
+}
+
+bitflags! {
+    /// Features we may care about, gathered from several leaves.
+    pub struct Features: u64 {
+        // Leaf 1, EDX
+        const FPU = 1 << 0;
+        const TSC = 1 << 1;
+        const MSR = 1 << 2;
+        const APIC = 1 << 3;
+        const PGE = 1 << 4;
+        const FXSR = 1 << 5;
+        const SSE = 1 << 6;
+        const SSE2 = 1 << 7;
+        // Leaf 1, ECX
+        const SSE3 = 1 << 8;
+        const SSSE3 = 1 << 9;
+        const PCID = 1 << 10;
+        const SSE4_1 = 1 << 11;
+        const SSE4_2 = 1 << 12;
+        const X2APIC = 1 << 13;
+        const TSC_DEADLINE = 1 << 14;
+        const XSAVE = 1 << 15;
+        const AVX = 1 << 16;
+        const RDRAND = 1 << 17;
+        const HYPERVISOR = 1 << 18;
+        // Leaf 7, EBX and ECX
+        const FSGSBASE = 1 << 19;
+        const AVX2 = 1 << 20;
+        const SMEP = 1 << 21;
+        const INVPCID = 1 << 22;
+        const RDSEED = 1 << 23;
+        const SMAP = 1 << 24;
+        const UMIP = 1 << 25;
+        // Leaf 0x8000_0001, EDX
+        const SYSCALL = 1 << 26;
+        const NX = 1 << 27;
+        const PAGE_1G = 1 << 28;
+        const RDTSCP = 1 << 29;
+        // Leaf 0x8000_0007, EDX
+        const INVARIANT_TSC = 1 << 30;
+    }
+}
+
+/// `(register bit, feature)` pairs for each register we decode.
+const LEAF1_EDX: [(u32, Features); 8] = [
+    (0, Features::FPU),
+    (4, Features::TSC),
+    (5, Features::MSR),
+    (9, Features::APIC),
+    (13, Features::PGE),
+    (24, Features::FXSR),
+    (25, Features::SSE),
+    (26, Features::SSE2),
+];
+const LEAF1_ECX: [(u32, Features); 11] = [
+    (0, Features::SSE3),
+    (9, Features::SSSE3),
+    (17, Features::PCID),
+    (19, Features::SSE4_1),
+    (20, Features::SSE4_2),
+    (21, Features::X2APIC),
+    (24, Features::TSC_DEADLINE),
+    (26, Features::XSAVE),
+    (28, Features::AVX),
+    (30, Features::RDRAND),
+    (31, Features::HYPERVISOR),
+];
+const LEAF7_EBX: [(u32, Features); 6] = [
+    (0, Features::FSGSBASE),
+    (5, Features::AVX2),
+    (7, Features::SMEP),
+    (10, Features::INVPCID),
+    (18, Features::RDSEED),
+    (20, Features::SMAP),
+];
+const LEAF7_ECX: [(u32, Features); 1] = [(2, Features::UMIP)];
+const EXTENDED1_EDX: [(u32, Features); 4] = [
+    (11, Features::SYSCALL),
+    (20, Features::NX),
+    (26, Features::PAGE_1G),
+    (27, Features::RDTSCP),
+];
+const EXTENDED7_EDX: [(u32, Features); 1] = [(8, Features::INVARIANT_TSC)];
+
+fn decode_features(register: u32, bits: &[(u32, Features)]) -> Features {
+    bits.iter()
+        .filter(|(bit, _)| register & (1 << bit) != 0)
+        .fold(Features::empty(), |features, (_, feature)| {
+            features | *feature
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Other([u8; 12]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cache {
+    pub level: u8,
+    pub cache_type: CacheType,
+    pub line_size: u32,
+    pub ways: u32,
+    pub sets: u32,
+    /// Logical CPUs sharing this cache.
+    pub shared_by: u32,
+}
+
+impl Cache {
+    /// Size in bytes.
+    pub fn size(&self) -> u32 {
+        self.line_size * self.ways * self.sets
+    }
+
+    /// Decode a leaf 4 (Intel) or 0x8000_001D (AMD) subleaf, which share
+    /// their layout. None once there are no more caches.
+    fn decode(result: CpuidResult) -> Option<Self> {
+        let cache_type = match result.eax & 0x1f {
+            1 => CacheType::Data,
+            2 => CacheType::Instruction,
+            3 => CacheType::Unified,
+            _ => return None,
+        };
+        Some(Self {
+            level: ((result.eax >> 5) & 0b111) as u8,
+            cache_type,
+            line_size: (result.ebx & 0xfff) + 1,
+            ways: (result.ebx >> 22) + 1,
+            sets: result.ecx + 1,
+            shared_by: ((result.eax >> 14) & 0xfff) + 1,
+        })
+    }
+}
+
+const MAX_CACHES: usize = 8;
+
+/// Everything we know about the CPU.
+pub struct CpuInfo {
+    pub vendor: Vendor,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    brand: [u8; 48],
+    pub features: Features,
+    /// Highest basic and extended leaves.
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    caches: [Option<Cache>; MAX_CACHES],
+    /// Initial APIC ID of the CPU that ran the detection.
+    pub apic_id: u32,
+    pub threads_per_core: u32,
+    pub logical_per_package: u32,
+    pub physical_address_bits: u8,
+    pub linear_address_bits: u8,
+}
+
+/// Family, model and stepping from leaf 1 EAX, accounting for the
+/// extended family and model fields.
+fn family_model_stepping(eax: u32) -> (u32, u32, u32) {
+    let base_family = (eax >> 8) & 0xf;
+    let base_model = (eax >> 4) & 0xf;
+    let family = if base_family == 0xf {
+        base_family + ((eax >> 20) & 0xff)
+    } else {
+        base_family
+    };
+    let model = if base_family == 0x6 || base_family == 0xf {
+        base_model + (((eax >> 16) & 0xf) << 4)
+    } else {
+        base_model
+    };
+    (family, model, eax & 0xf)
+}
+
+impl CpuInfo {
+    fn detect() -> Self {
+        let leaf0 = query(0, 0);
+        let max_leaf = leaf0.eax;
+        let mut vendor_id = [0; 12];
+        vendor_id[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor_id[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor_id[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+        let vendor = match &vendor_id {
+            b"GenuineIntel" => Vendor::Intel,
+            b"AuthenticAMD" => Vendor::Amd,
+            _ => Vendor::Other(vendor_id),
+        };
+        let max_extended_leaf = query(0x8000_0000, 0).eax;
+
+        let leaf1 = query(1, 0);
+        let (family, model, stepping) = family_model_stepping(leaf1.eax);
+        let mut features =
+            decode_features(leaf1.edx, &LEAF1_EDX) | decode_features(leaf1.ecx, &LEAF1_ECX);
+        if max_leaf >= 7 {
+            let leaf7 = query(7, 0);
+            features |=
+                decode_features(leaf7.ebx, &LEAF7_EBX) | decode_features(leaf7.ecx, &LEAF7_ECX);
+        }
+        if max_extended_leaf >= 0x8000_0001 {
+            features |= decode_features(query(0x8000_0001, 0).edx, &EXTENDED1_EDX);
+        }
+        if max_extended_leaf >= 0x8000_0007 {
+            features |= decode_features(query(0x8000_0007, 0).edx, &EXTENDED7_EDX);
+        }
+
+        let mut brand = [0; 48];
+        if max_extended_leaf >= 0x8000_0004 {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let result = query(leaf, 0);
+                for (j, register) in [result.eax, result.ebx, result.ecx, result.edx]
+                    .iter()
+                    .enumerate()
+                {
+                    let start = i * 16 + j * 4;
+                    brand[start..start + 4].copy_from_slice(&register.to_le_bytes());
+                }
+            }
+        }
+
+        let (physical_address_bits, linear_address_bits) = if max_extended_leaf >= 0x8000_0008 {
+            let result = query(0x8000_0008, 0);
+            (result.eax as u8, (result.eax >> 8) as u8)
+        } else {
+            (36, 48)
+        };
+
+        let mut info = Self {
+            vendor,
+            family,
+            model,
+            stepping,
+            brand,
+            features,
+            max_leaf,
+            max_extended_leaf,
+            caches: [None; MAX_CACHES],
+            apic_id: leaf1.ebx >> 24,
+            threads_per_core: 1,
+            logical_per_package: (leaf1.ebx >> 16) & 0xff,
+            physical_address_bits,
+            linear_address_bits,
+        };
+        info.detect_caches();
+        info.detect_topology();
+        info
+    }
+
+    fn detect_caches(&mut self) {
+        let leaf = match self.vendor {
+            Vendor::Intel if self.max_leaf >= 4 => 4,
+            // Requires the TOPOEXT feature, CPUID 0x8000_0001 ECX bit 22.
+            Vendor::Amd
+                if self.max_extended_leaf >= 0x8000_001d
+                    && query(0x8000_0001, 0).ecx & (1 << 22) != 0 =>
+            {
+                0x8000_001d
+            }
+            _ => return,
+        };
+        for (subleaf, slot) in self.caches.iter_mut().enumerate() {
+            *slot = Cache::decode(query(leaf, subleaf as u32));
+            if slot.is_none() {
+                break;
+            }
+        }
+    }
+
+    /// Use the extended topology leaf when there is one, it's the only
+    /// reliable source.
+    fn detect_topology(&mut self) {
+        if self.max_leaf < 0xb || query(0xb, 0).ebx == 0 {
+            return;
+        }
+        for subleaf in 0.. {
+            let result = query(0xb, subleaf);
+            let logical = result.ebx & 0xffff;
+            match (result.ecx >> 8) & 0xff {
+                // SMT
+                1 => self.threads_per_core = logical,
+                // Core
+                2 => self.logical_per_package = logical,
+                _ => break,
+            }
+        }
+        self.apic_id = query(0xb, 0).edx;
+    }
+
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&b| b == 0).unwrap_or(48);
+        str::from_utf8(&self.brand[..len]).unwrap_or("").trim()
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = &Cache> {
+        self.caches.iter().map_while(Option::as_ref)
+    }
+
+    pub fn has(&self, features: Features) -> bool {
+        self.features.contains(features)
+    }
+}
+
+impl fmt::Display for Vendor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Vendor::Intel => f.write_str("Intel"),
+            Vendor::Amd => f.write_str("AMD"),
+            Vendor::Other(id) => f.write_str(str::from_utf8(id).unwrap_or("unknown")),
+        }
+    }
+}
+
+static INFO: Once<CpuInfo> = Once::new();
+
+/// Information about the CPU, detected on first use.
+/// All CPUs are assumed to be the same.
+pub fn info() -> &'static CpuInfo {
+    INFO.call_once(CpuInfo::detect)
+}
+
+/// Whether the CPU has all of `features`.
+pub fn has(features: Features) -> bool {
+    info().has(features)
+}
+
+/// Detect and log the CPU.
+pub fn init() {
+    let info = info();
+    log!(
+        "CPU: {} {} (family {:#x}, model {:#x}, stepping {})",
+        info.vendor,
+        info.brand(),
+        info.family,
+        info.model,
+        info.stepping
+    );
+    debug!("CPU features: {:?}", info.features);
+    debug!(
+        "CPU topology: APIC ID {}, {} threads per core, {} per package, {}/{} address bits",
+        info.apic_id,
+        info.threads_per_core,
+        info.logical_per_package,
+        info.physical_address_bits,
+        info.linear_address_bits
+    );
+    for cache in info.caches() {
+        debug!(
+            "L{} {:?} cache: {} KiB, {}-way, {} B lines, shared by {}",
+            cache.level,
+            cache.cache_type,
+            cache.size() / 1024,
+            cache.ways,
+            cache.line_size,
+            cache.shared_by
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn extended_family_and_model() {
+        // Skylake
+        assert_eq!(family_model_stepping(0x0005_06e3), (0x6, 0x5e, 3));
+        // Zen
+        assert_eq!(family_model_stepping(0x0080_0f11), (0x17, 0x1, 1));
+        // Pentium, no extended fields
+        assert_eq!(family_model_stepping(0x0000_0543), (0x5, 0x4, 3));
+    }
+
+    #[test_case]
+    fn feature_bits() {
+        let features = decode_features((1 << 25) | (1 << 26), &LEAF1_EDX);
+        assert_eq!(features, Features::SSE | Features::SSE2);
+    }
+
+    #[test_case]
+    fn long_mode_baseline() {
+        // Every x86_64 CPU has these.
+        assert!(has(Features::FPU
+            | Features::TSC
+            | Features::MSR
+            | Features::SSE2));
+        assert!(info().physical_address_bits >= 32);
+    }
+}
@@ -0,0 +1,312 @@
+//! x87, SSE and AVX state.
+//! https://wiki.osdev.org/SSE
+//!
+//! The kernel itself is built with soft-float and never touches these
+//! registers, so interrupt handlers don't need to save them. Enabling them
+//! is opt-in, with the `simd` feature: threads, and later user space, then
+//! get their own `FpuState`, switched eagerly or lazily on first use.
+
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use bitflags::bitflags;
+
+use crate::interrupts::{self, ExceptionStackFrame};
+use crate::x86::cpuid::{self, Features};
+use crate::x86::registers::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+bitflags! {
+    /// XCR0: state components managed by XSAVE.
+    struct Xcr0: u64 {
+        const X87 = 1 << 0;
+        const SSE = 1 << 1;
+        const AVX = 1 << 2;
+    }
+}
+
+/// Device Not Available, raised when using the FPU with CR0.TS set.
+const DEVICE_NOT_AVAILABLE: u8 = 7;
+
+/// Enough for x87, SSE and AVX, the only components we enable.
+const STATE_SIZE: usize = 1024;
+
+/// Offsets of the x87 control word and MXCSR in the legacy area.
+const FCW: usize = 0;
+const MXCSR: usize = 24;
+/// Their values after FNINIT and reset.
+const DEFAULT_FCW: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// A saved FPU, SSE and AVX context.
+#[repr(C, align(64))]
+#[derive(Clone)]
+pub struct FpuState([u8; STATE_SIZE]);
+
+impl FpuState {
+    /// The state right after initialization.
+    pub fn new() -> Self {
+        let mut state = Self([0; STATE_SIZE]);
+        let default = DEFAULT_STATE.load(Ordering::Relaxed);
+        if !default.is_null() {
+            state.0.copy_from_slice(unsafe { &(*default).0 });
+        } else {
+            // Not recorded yet: the architectural defaults, with every
+            // exception masked. A zero MXCSR would unmask them all.
+            state.0[FCW..FCW + 2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+            state.0[MXCSR..MXCSR + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        }
+        state
+    }
+
+    /// Save the current registers here.
+    ///
+    /// # Safety
+    /// The FPU must be enabled, with CR0.TS clear.
+    pub unsafe fn save(&mut self) {
+        let ptr = self.0.as_mut_ptr();
+        if XSAVE.load(Ordering::Relaxed) {
+            asm!("xsave64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags));
+        } else {
+            asm!("fxsave64 [{}]", in(reg) ptr, options(nostack, preserves_flags));
+        }
+    }
+
+    /// Load the registers from here.
+    ///
+    /// # Safety
+    /// The FPU must be enabled, with CR0.TS clear, and the state must come
+    /// from `new` or `save`.
+    pub unsafe fn restore(&self) {
+        let ptr = self.0.as_ptr();
+        if XSAVE.load(Ordering::Relaxed) {
+            asm!("xrstor64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags));
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) ptr, options(nostack, preserves_flags));
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// When to switch the registers between threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchMode {
+    /// Save and restore on every switch.
+    Eager,
+    /// Set CR0.TS on switch, and only swap states in the #NM handler if the
+    /// new thread actually uses the FPU.
+    Lazy,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static XSAVE: AtomicBool = AtomicBool::new(false);
+static MODE: AtomicUsize = AtomicUsize::new(SwitchMode::Eager as usize);
+
+/// Initial state, copied by `FpuState::new`.
+static DEFAULT_STATE: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+/// Whose state is in the registers.
+static OWNER: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+/// Whose state should be, in lazy mode.
+static CURRENT: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn mode() -> SwitchMode {
+    if MODE.load(Ordering::Relaxed) == SwitchMode::Lazy as usize {
+        SwitchMode::Lazy
+    } else {
+        SwitchMode::Eager
+    }
+}
+
+pub fn set_mode(mode: SwitchMode) {
+    MODE.store(mode as usize, Ordering::Relaxed);
+}
+
+#[inline]
+fn clts() {
+    unsafe { asm!("clts", options(nomem, nostack, preserves_flags)) };
+}
+
+#[inline]
+unsafe fn xsetbv(register: u32, value: u64) {
+    asm!("xsetbv", in("ecx") register, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nomem, nostack, preserves_flags));
+}
+
+/// Swap the registers to `CURRENT`'s state, saving `OWNER`'s.
+unsafe fn swap_to_current() {
+    let owner = OWNER.load(Ordering::Relaxed);
+    let current = CURRENT.load(Ordering::Relaxed);
+    if owner == current {
+        return;
+    }
+    if !owner.is_null() {
+        (*owner).save();
+    }
+    if !current.is_null() {
+        (*current).restore();
+    }
+    OWNER.store(current, Ordering::Relaxed);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(_frame: ExceptionStackFrame) {
+    clts();
+    unsafe { swap_to_current() };
+}
+
+/// Make `state` the registers' state, e.g. when switching threads.
+/// `state` must stay valid until the next switch.
+pub fn switch_to(state: *mut FpuState) {
+    if !is_enabled() {
+        return;
+    }
+    CURRENT.store(state, Ordering::Relaxed);
+    match mode() {
+        SwitchMode::Eager => unsafe {
+            clts();
+            swap_to_current();
+        },
+        SwitchMode::Lazy => unsafe {
+            if OWNER.load(Ordering::Relaxed) == state {
+                clts();
+            } else {
+                Cr0::update(|cr0| cr0.insert(Cr0Flags::TASK_SWITCHED));
+            }
+        },
+    }
+}
+
+/// Forget `state`, which is going away: the registers may still hold it,
+/// but they won't be saved there, nor taken as the state of whatever comes
+/// next at that address.
+pub fn forget(state: *mut FpuState) {
+    let _ = OWNER.compare_exchange(state, ptr::null_mut(), Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Enable the FPU, SSE and AVX if available, on the current CPU.
+pub fn init() {
+    if !cpuid::has(Features::FPU | Features::FXSR | Features::SSE | Features::SSE2) {
+        warn!("No SSE2, SIMD stays disabled");
+        return;
+    }
+
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATION | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT));
+
+        let mut xcr0 = Xcr0::X87 | Xcr0::SSE;
+        if cpuid::has(Features::XSAVE) {
+            if cpuid::has(Features::AVX) {
+                xcr0 |= Xcr0::AVX;
+            }
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            xsetbv(0, xcr0.bits());
+            // EBX is the size needed for the components enabled in XCR0.
+            let size = cpuid::query(0xd, 0).ebx as usize;
+            if size <= STATE_SIZE {
+                XSAVE.store(true, Ordering::Relaxed);
+            } else {
+                warn!("XSAVE area too large ({} bytes), using FXSAVE", size);
+            }
+        }
+        asm!("fninit", options(nomem, nostack));
+    }
+
+    interrupts::set_handler(DEVICE_NOT_AVAILABLE, device_not_available_handler);
+    ENABLED.store(true, Ordering::Relaxed);
+
+    // Record the clean state, for `FpuState::new`.
+    static mut DEFAULT: FpuState = FpuState([0; STATE_SIZE]);
+    unsafe {
+        (*ptr::addr_of_mut!(DEFAULT)).save();
+        DEFAULT_STATE.store(ptr::addr_of_mut!(DEFAULT), Ordering::Relaxed);
+    }
+
+    log!(
+        "SIMD enabled: {}, {} switching",
+        if XSAVE.load(Ordering::Relaxed) {
+            "XSAVE"
+        } else {
+            "FXSAVE"
+        },
+        if mode() == SwitchMode::Lazy {
+            "lazy"
+        } else {
+            "eager"
+        }
+    );
+}
+
+#[cfg(all(test, feature = "simd"))]
+mod test {
+    use super::*;
+
+    fn mxcsr() -> u32 {
+        let mut value = 0u32;
+        unsafe { asm!("stmxcsr [{}]", in(reg) &mut value, options(nostack)) };
+        value
+    }
+
+    fn set_mxcsr(value: u32) {
+        unsafe { asm!("ldmxcsr [{}]", in(reg) &value, options(nostack)) };
+    }
+
+    #[test_case]
+    fn state_round_trip() {
+        assert!(is_enabled());
+        let original = mxcsr();
+        let mut state = FpuState::new();
+        // Round towards zero
+        set_mxcsr(original | (0b11 << 13));
+        unsafe { state.save() };
+        set_mxcsr(original);
+        unsafe { state.restore() };
+        assert_eq!(mxcsr(), original | (0b11 << 13));
+        set_mxcsr(original);
+    }
+
+    #[test_case]
+    fn lazy_switch_traps_once() {
+        let mut first = FpuState::new();
+        let mut second = FpuState::new();
+        set_mode(SwitchMode::Lazy);
+        switch_to(&mut first);
+        set_mxcsr(mxcsr() | (0b11 << 13));
+        switch_to(&mut second);
+        assert!(Cr0::read().contains(Cr0Flags::TASK_SWITCHED));
+        // Traps, and loads the default state of `second`.
+        assert_eq!(mxcsr() & (0b11 << 13), 0);
+        assert!(!Cr0::read().contains(Cr0Flags::TASK_SWITCHED));
+        switch_to(&mut first);
+        assert_ne!(mxcsr() & (0b11 << 13), 0);
+        set_mode(SwitchMode::Eager);
+        // Don't leave dangling pointers to our states.
+        switch_to(ptr::null_mut());
+    }
+
+    #[test_case]
+    fn forgotten_state_is_not_reused() {
+        let mut state = FpuState::new();
+        set_mode(SwitchMode::Lazy);
+        switch_to(&mut state);
+        set_mxcsr(mxcsr() | (0b11 << 13));
+        // A new state at the same address, as when a thread slot is reused.
+        forget(&mut state);
+        state = FpuState::new();
+        switch_to(&mut state);
+        // The fresh state is loaded, not the registers left behind.
+        assert_eq!(mxcsr() & (0b11 << 13), 0);
+        set_mode(SwitchMode::Eager);
+        switch_to(ptr::null_mut());
+    }
+}
@@ -0,0 +1,274 @@
+//! Wrappers around x86 instructions and registers.
+//! Inline assembly belongs here, so unsafe register accesses stay in one
+//! place.
+
+pub mod cpuid;
+pub mod fpu;
+pub mod msr;
+pub mod port;
+pub mod protection;
+pub mod registers;
+pub mod tables;
+
+use core::arch::asm;
+
+pub use msr::Msr;
+pub use port::Port;
+
+#[inline]
+pub fn hlt() {
+    unsafe {
+        asm!("hlt", options(nostack));
+    }
+}
+
+/// Disable maskable interrupts.
+/// Not `nomem`: memory accesses must not move across it, since it starts
+/// critical sections.
+#[inline]
+pub fn cli() {
+    unsafe {
+        asm!("cli", options(nostack));
+    }
+}
+
+/// Enable maskable interrupts.
+/// Not `nomem` either, see `cli`.
+#[inline]
+pub fn sti() {
+    unsafe {
+        asm!("sti", options(nostack));
+    }
+}
+
+/// Enable interrupts and halt until the next one.
+/// `sti` only takes effect after the following instruction, so no
+/// interrupt can sneak in between the two and leave us halted forever.
+#[inline]
+pub fn sti_hlt() {
+    unsafe {
+        asm!("sti; hlt", options(nostack));
+    }
+}
+
+/// The RFLAGS register.
+#[inline]
+pub fn rflags() -> u64 {
+    let flags: u64;
+    unsafe {
+        asm!("pushfq; pop {}", out(reg) flags, options(nomem, preserves_flags));
+    }
+    flags
+}
+
+/// Whether maskable interrupts are enabled (RFLAGS.IF).
+#[inline]
+pub fn interrupts_enabled() -> bool {
+    rflags() & (1 << 9) != 0
+}
+
+/// Disables interrupts until dropped, then restores the previous state.
+#[must_use]
+pub struct InterruptGuard {
+    enabled: bool,
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.enabled {
+            sti();
+        }
+    }
+}
+
+/// Disable interrupts until the returned guard is dropped.
+#[inline]
+pub fn disable_interrupts() -> InterruptGuard {
+    let enabled = interrupts_enabled();
+    if enabled {
+        cli();
+    }
+    InterruptGuard { enabled }
+}
+
+/// Run `f` with interrupts disabled, restoring the previous state afterwards.
+/// Needed whenever we take a lock that an interrupt handler also takes.
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = disable_interrupts();
+    f()
+}
+
+/// Allow ring 0 to access user pages despite SMAP (set RFLAGS.AC).
+/// Raises #UD if the CPU doesn't support SMAP.
+/// Not `nomem`, so user accesses can't be moved before it.
+#[inline]
+pub fn stac() {
+    unsafe {
+        asm!("stac", options(nostack));
+    }
+}
+
+/// Forbid ring 0 to access user pages again (clear RFLAGS.AC).
+/// Raises #UD if the CPU doesn't support SMAP.
+/// Not `nomem`, so user accesses can't be moved after it.
+#[inline]
+pub fn clac() {
+    unsafe {
+        asm!("clac", options(nostack));
+    }
+}
+
+/// The current frame pointer, RBP.
+/// Always inlined, so this is the caller's frame.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// The current stack pointer, RSP.
+#[inline(always)]
+pub fn stack_pointer() -> u64 {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    rsp
+}
+
+/// Switch to the stack at `top` and call `f(arg)` there, never to come
+/// back. RBP is cleared, so backtraces stop at `f`.
+///
+/// # Safety
+/// `top` must be the top of a mapped stack, and nothing may use the
+/// current stack anymore.
+pub unsafe fn switch_stack(top: u64, f: extern "C" fn(u64) -> !, arg: u64) -> ! {
+    asm!(
+        "mov rsp, {top}",
+        "xor ebp, ebp",
+        "call {f}",
+        top = in(reg) top,
+        f = in(reg) f,
+        in("rdi") arg,
+        options(noreturn)
+    );
+}
+
+/// Raise a breakpoint exception.
+#[inline]
+pub fn int3() {
+    unsafe {
+        asm!("int3", options(nomem, nostack));
+    }
+}
+
+/// Exchange GS base with IA32_KERNEL_GS_BASE.
+///
+/// # Safety
+/// Code relying on either value must expect the swap.
+#[inline]
+pub unsafe fn swapgs() {
+    asm!("swapgs", options(nomem, nostack, preserves_flags));
+}
+
+/// Invalidate the TLB entry of the page containing `address`.
+#[inline]
+pub fn invlpg(address: u64) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) address, options(nostack, preserves_flags));
+    }
+}
+
+/// Invalidate the TLB entries of every PCID, global ones included.
+/// Raises #UD without `Features::INVPCID`.
+#[inline]
+pub fn invpcid_all() {
+    // The descriptor's PCID and address are ignored for this type.
+    let descriptor = [0u64; 2];
+    unsafe {
+        asm!(
+            "invpcid {}, [{}]",
+            in(reg) 2u64,
+            in(reg) &descriptor,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Read the Time Stamp Counter.
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Read the Time Stamp Counter once all previous instructions executed,
+/// along with IA32_TSC_AUX.
+#[inline]
+pub fn rdtscp() -> (u64, u32) {
+    let (high, low, aux): (u32, u32, u32);
+    unsafe {
+        asm!("rdtscp", out("eax") low, out("edx") high, out("ecx") aux, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32 | low as u64, aux)
+}
+
+/// A random number from the CPU's DRBG, or None if it's temporarily
+/// exhausted. Raises #UD without `Features::RDRAND`.
+#[inline]
+pub fn rdrand() -> Option<u64> {
+    let (value, ok): (u64, u8);
+    unsafe {
+        asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+    }
+    if ok != 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// A random number straight from the CPU's entropy source, or None if it's
+/// temporarily exhausted. Raises #UD without `Features::RDSEED`.
+#[inline]
+pub fn rdseed() -> Option<u64> {
+    let (value, ok): (u64, u8);
+    unsafe {
+        asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+    }
+    if ok != 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn interrupt_guard_restores_state() {
+        assert!(interrupts_enabled());
+        {
+            let _outer = disable_interrupts();
+            assert!(!interrupts_enabled());
+            drop(disable_interrupts());
+            // The inner guard found interrupts disabled, and left them so.
+            assert!(!interrupts_enabled());
+        }
+        assert!(interrupts_enabled());
+    }
+
+    #[test_case]
+    fn tsc_advances() {
+        let start = rdtsc();
+        assert!(rdtscp().0 > start);
+    }
+}
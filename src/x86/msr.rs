@@ -0,0 +1,41 @@
+//! Model specific registers.
+//! See the Intel SDM Volume 4, or the AMD64 manual Volume 2, Appendix A.
+
+use core::arch::asm;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msr(pub u32);
+
+impl Msr {
+    pub const APIC_BASE: Msr = Msr(0x1b);
+    pub const TSC_DEADLINE: Msr = Msr(0x6e0);
+    pub const EFER: Msr = Msr(0xc000_0080);
+    /// Segments of SYSCALL and SYSRET.
+    pub const STAR: Msr = Msr(0xc000_0081);
+    /// 64-bit SYSCALL target.
+    pub const LSTAR: Msr = Msr(0xc000_0082);
+    /// RFLAGS bits cleared by SYSCALL.
+    pub const SFMASK: Msr = Msr(0xc000_0084);
+    pub const FS_BASE: Msr = Msr(0xc000_0100);
+    pub const GS_BASE: Msr = Msr(0xc000_0101);
+    /// Swapped with GS_BASE by SWAPGS.
+    pub const KERNEL_GS_BASE: Msr = Msr(0xc000_0102);
+    /// Read by RDTSCP, usually the CPU number.
+    pub const TSC_AUX: Msr = Msr(0xc000_0103);
+
+    /// # Safety
+    /// Reading a MSR that doesn't exist raises #GP.
+    #[inline]
+    pub unsafe fn read(self) -> u64 {
+        let (high, low): (u32, u32);
+        asm!("rdmsr", in("ecx") self.0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+        (high as u64) << 32 | low as u64
+    }
+
+    /// # Safety
+    /// MSRs control the CPU, and writing an invalid value raises #GP.
+    #[inline]
+    pub unsafe fn write(self, value: u64) {
+        asm!("wrmsr", in("ecx") self.0, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+    }
+}
@@ -0,0 +1,90 @@
+//! I/O ports.
+
+use core::arch::asm;
+use core::marker::PhantomData;
+
+/// A value that can be read from or written to an I/O port.
+pub trait PortValue: Copy {
+    /// # Safety
+    /// Reading a port can have side effects on the device behind it.
+    unsafe fn read_from(port: u16) -> Self;
+    /// # Safety
+    /// Writing a port can have side effects on the device behind it.
+    unsafe fn write_to(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    #[inline]
+    unsafe fn read_from(port: u16) -> Self {
+        let value: u8;
+        asm!("in al, dx", in("dx") port, out("al") value, options(preserves_flags, nomem, nostack));
+        value
+    }
+
+    #[inline]
+    unsafe fn write_to(port: u16, value: Self) {
+        asm!("out dx, al", in("dx") port, in("al") value, options(preserves_flags, nomem, nostack));
+    }
+}
+
+impl PortValue for u16 {
+    #[inline]
+    unsafe fn read_from(port: u16) -> Self {
+        let value: u16;
+        asm!("in ax, dx", in("dx") port, out("ax") value, options(preserves_flags, nomem, nostack));
+        value
+    }
+
+    #[inline]
+    unsafe fn write_to(port: u16, value: Self) {
+        asm!("out dx, ax", in("dx") port, in("ax") value, options(preserves_flags, nomem, nostack));
+    }
+}
+
+impl PortValue for u32 {
+    #[inline]
+    unsafe fn read_from(port: u16) -> Self {
+        let value: u32;
+        asm!("in eax, dx", in("dx") port, out("eax") value, options(preserves_flags, nomem, nostack));
+        value
+    }
+
+    #[inline]
+    unsafe fn write_to(port: u16, value: Self) {
+        asm!("out dx, eax", in("dx") port, in("eax") value, options(preserves_flags, nomem, nostack));
+    }
+}
+
+/// An I/O port accessed `T` at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port<T> {
+    port: u16,
+    _value: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    pub const fn number(&self) -> u16 {
+        self.port
+    }
+
+    /// # Safety
+    /// Reading a port can have side effects on the device behind it.
+    #[inline]
+    pub unsafe fn read(&self) -> T {
+        T::read_from(self.port)
+    }
+
+    /// # Safety
+    /// Writing a port can have side effects on the device behind it.
+    #[inline]
+    pub unsafe fn write(&self, value: T) {
+        T::write_to(self.port, value)
+    }
+}
@@ -0,0 +1,112 @@
+//! Hardening features of the CPU, enabled at boot when supported.
+//! https://wiki.osdev.org/Supervisor_Memory_Protection
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use bitflags::bitflags;
+
+use crate::x86::cpuid::{self, Features};
+use crate::x86::registers::{Cr0, Cr0Flags, Cr4, Cr4Flags, Efer, EferFlags};
+use crate::x86::{clac, stac};
+
+bitflags! {
+    pub struct Protections: u8 {
+        /// Supervisor Mode Execution Prevention: ring 0 can't execute user
+        /// pages.
+        const SMEP = 1 << 0;
+        /// Supervisor Mode Access Prevention: ring 0 can't access user
+        /// pages, outside of `UserAccess`.
+        const SMAP = 1 << 1;
+        /// User Mode Instruction Prevention: SGDT, SIDT and friends fault
+        /// in ring 3.
+        const UMIP = 1 << 2;
+        /// Pages can be marked no-execute.
+        const NX = 1 << 3;
+        /// Ring 0 honors read-only pages.
+        const WRITE_PROTECT = 1 << 4;
+    }
+}
+
+static ACTIVE: AtomicU8 = AtomicU8::new(0);
+
+/// Protections enabled by `init`.
+pub fn active() -> Protections {
+    Protections::from_bits_truncate(ACTIVE.load(Ordering::Relaxed))
+}
+
+/// Enable every protection the CPU supports, on the current CPU.
+pub fn init() {
+    let mut active = Protections::WRITE_PROTECT;
+    let mut cr4 = Cr4::read();
+    if cpuid::has(Features::SMEP) {
+        cr4.insert(Cr4Flags::SMEP);
+        active.insert(Protections::SMEP);
+    }
+    if cpuid::has(Features::SMAP) {
+        cr4.insert(Cr4Flags::SMAP);
+        active.insert(Protections::SMAP);
+    }
+    if cpuid::has(Features::UMIP) {
+        cr4.insert(Cr4Flags::UMIP);
+        active.insert(Protections::UMIP);
+    }
+
+    unsafe {
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+        Cr4::write(cr4);
+        if cpuid::has(Features::NX) {
+            Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE));
+            active.insert(Protections::NX);
+        }
+    }
+
+    ACTIVE.store(active.bits(), Ordering::Relaxed);
+    log!("CPU protections: {:?}", active);
+    if active != Protections::all() {
+        warn!(
+            "Unsupported CPU protections: {:?}",
+            Protections::all() - active
+        );
+    }
+}
+
+/// Lets ring 0 access user pages while it lives, when SMAP is active.
+/// Keep it as short-lived as possible.
+#[must_use]
+pub struct UserAccess {
+    smap: bool,
+}
+
+impl UserAccess {
+    pub fn new() -> Self {
+        let smap = active().contains(Protections::SMAP);
+        if smap {
+            stac();
+        }
+        Self { smap }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if self.smap {
+            clac();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn supported_protections_are_on() {
+        let active = active();
+        assert!(active.contains(Protections::WRITE_PROTECT));
+        assert_eq!(active.contains(Protections::NX), cpuid::has(Features::NX));
+        assert_eq!(
+            Cr4::read().contains(Cr4Flags::SMEP),
+            active.contains(Protections::SMEP)
+        );
+    }
+}
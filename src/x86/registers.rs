@@ -0,0 +1,229 @@
+//! Control registers and EFER.
+//! https://wiki.osdev.org/CPU_Registers_x86-64
+
+use core::arch::asm;
+
+use bitflags::bitflags;
+
+use crate::x86::Msr;
+
+bitflags! {
+    pub struct Cr0Flags: u64 {
+        const PROTECTED_MODE = 1 << 0;
+        const MONITOR_COPROCESSOR = 1 << 1;
+        /// x87 instructions raise #NM.
+        const EMULATION = 1 << 2;
+        /// The next x87/SSE instruction raises #NM, for lazy FPU switching.
+        const TASK_SWITCHED = 1 << 3;
+        const EXTENSION_TYPE = 1 << 4;
+        const NUMERIC_ERROR = 1 << 5;
+        /// Read-only pages are read-only in ring 0 as well.
+        const WRITE_PROTECT = 1 << 16;
+        const ALIGNMENT_MASK = 1 << 18;
+        const NOT_WRITE_THROUGH = 1 << 29;
+        const CACHE_DISABLE = 1 << 30;
+        const PAGING = 1 << 31;
+    }
+}
+
+bitflags! {
+    pub struct Cr4Flags: u64 {
+        const VIRTUAL_8086_EXTENSIONS = 1 << 0;
+        const PROTECTED_VIRTUAL_INTERRUPTS = 1 << 1;
+        /// RDTSC is only allowed in ring 0.
+        const TIMESTAMP_DISABLE = 1 << 2;
+        const DEBUGGING_EXTENSIONS = 1 << 3;
+        const PAGE_SIZE_EXTENSION = 1 << 4;
+        const PHYSICAL_ADDRESS_EXTENSION = 1 << 5;
+        const MACHINE_CHECK = 1 << 6;
+        const PAGE_GLOBAL = 1 << 7;
+        const PERFORMANCE_COUNTER = 1 << 8;
+        /// FXSAVE, FXRSTOR and SSE are available.
+        const OSFXSR = 1 << 9;
+        const OSXMMEXCPT = 1 << 10;
+        /// SGDT, SIDT, SLDT, SMSW and STR are only allowed in ring 0.
+        const UMIP = 1 << 11;
+        const LA57 = 1 << 12;
+        const FSGSBASE = 1 << 16;
+        const PCID = 1 << 17;
+        /// XSAVE and extended states are available.
+        const OSXSAVE = 1 << 18;
+        /// Ring 0 can't execute user pages.
+        const SMEP = 1 << 20;
+        /// Ring 0 can't access user pages, unless RFLAGS.AC is set.
+        const SMAP = 1 << 21;
+    }
+}
+
+bitflags! {
+    pub struct EferFlags: u64 {
+        /// SYSCALL and SYSRET are available.
+        const SYSTEM_CALL_EXTENSIONS = 1 << 0;
+        const LONG_MODE_ENABLE = 1 << 8;
+        const LONG_MODE_ACTIVE = 1 << 10;
+        /// The no-execute page table bit is available.
+        const NO_EXECUTE_ENABLE = 1 << 11;
+        const SECURE_VIRTUAL_MACHINE_ENABLE = 1 << 12;
+        const FAST_FXSAVE_FXRSTOR = 1 << 14;
+    }
+}
+
+pub struct Cr0;
+
+impl Cr0 {
+    #[inline]
+    pub fn read() -> Cr0Flags {
+        let value: u64;
+        unsafe { asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        Cr0Flags::from_bits_truncate(value)
+    }
+
+    /// # Safety
+    /// Clearing protection or paging bits breaks everything.
+    #[inline]
+    pub unsafe fn write(flags: Cr0Flags) {
+        // Keep reserved bits as they are.
+        let value: u64;
+        asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags));
+        let value = (value & !Cr0Flags::all().bits()) | flags.bits();
+        asm!("mov cr0, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+
+    /// # Safety
+    /// See `write`.
+    #[inline]
+    pub unsafe fn update(f: impl FnOnce(&mut Cr0Flags)) {
+        let mut flags = Self::read();
+        f(&mut flags);
+        Self::write(flags);
+    }
+}
+
+/// Page fault linear address.
+pub struct Cr2;
+
+impl Cr2 {
+    #[inline]
+    pub fn read() -> u64 {
+        let value: u64;
+        unsafe { asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+}
+
+/// Address of the top level page table, and the current PCID.
+pub struct Cr3;
+
+impl Cr3 {
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+    /// With PCIDs enabled, keep the TLB entries of the new PCID.
+    pub const NO_FLUSH: u64 = 1 << 63;
+
+    #[inline]
+    pub fn read_raw() -> u64 {
+        let value: u64;
+        unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+
+    /// Physical address of the PML4, and the low 12 bits: PCID if enabled,
+    /// cache control flags otherwise.
+    #[inline]
+    pub fn read() -> (u64, u16) {
+        let value = Self::read_raw();
+        (value & Self::ADDRESS_MASK, (value & 0xfff) as u16)
+    }
+
+    /// # Safety
+    /// `value` must point to a valid PML4 mapping the running kernel.
+    #[inline]
+    pub unsafe fn write_raw(value: u64) {
+        asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+
+    /// # Safety
+    /// `pml4` must be a valid PML4 mapping the running kernel.
+    #[inline]
+    pub unsafe fn write(pml4: u64, low_bits: u16) {
+        Self::write_raw((pml4 & Self::ADDRESS_MASK) | (low_bits & 0xfff) as u64);
+    }
+}
+
+pub struct Cr4;
+
+impl Cr4 {
+    #[inline]
+    pub fn read() -> Cr4Flags {
+        let value: u64;
+        unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        Cr4Flags::from_bits_truncate(value)
+    }
+
+    /// # Safety
+    /// Enabling unsupported features raises #GP, and most of them change
+    /// what the kernel is allowed to do.
+    #[inline]
+    pub unsafe fn write(flags: Cr4Flags) {
+        let value: u64;
+        asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags));
+        let value = (value & !Cr4Flags::all().bits()) | flags.bits();
+        asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+
+    /// # Safety
+    /// See `write`.
+    #[inline]
+    pub unsafe fn update(f: impl FnOnce(&mut Cr4Flags)) {
+        let mut flags = Self::read();
+        f(&mut flags);
+        Self::write(flags);
+    }
+}
+
+/// Extended Feature Enable Register.
+pub struct Efer;
+
+impl Efer {
+    #[inline]
+    pub fn read() -> EferFlags {
+        EferFlags::from_bits_truncate(unsafe { Msr::EFER.read() })
+    }
+
+    /// # Safety
+    /// Clearing long mode or enabling unsupported features breaks
+    /// everything.
+    #[inline]
+    pub unsafe fn write(flags: EferFlags) {
+        let value = (Msr::EFER.read() & !EferFlags::all().bits()) | flags.bits();
+        Msr::EFER.write(value);
+    }
+
+    /// # Safety
+    /// See `write`.
+    #[inline]
+    pub unsafe fn update(f: impl FnOnce(&mut EferFlags)) {
+        let mut flags = Self::read();
+        f(&mut flags);
+        Self::write(flags);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn long_mode_is_on() {
+        assert!(Cr0::read().contains(Cr0Flags::PROTECTED_MODE | Cr0Flags::PAGING));
+        assert!(Cr4::read().contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION));
+        assert!(Efer::read().contains(EferFlags::LONG_MODE_ACTIVE));
+    }
+
+    #[test_case]
+    fn cr3_round_trip() {
+        let raw = Cr3::read_raw();
+        let (pml4, low_bits) = Cr3::read();
+        unsafe { Cr3::write(pml4, low_bits) };
+        assert_eq!(Cr3::read_raw(), raw);
+    }
+}
@@ -0,0 +1,102 @@
+//! Descriptor table and segment register instructions.
+
+use core::arch::asm;
+use core::mem::MaybeUninit;
+
+/// Format for use by LIDT and LGDT
+#[repr(C, packed(2))]
+pub struct DescriptorTableRegister {
+    /// Size of the DT in bytes, minus 1.
+    pub limit: u16,
+    /// Base address of the DT.
+    pub base: *const u64,
+}
+
+/// Load the GDT.
+///
+/// # Safety
+/// `gdt` must describe a valid GDT that outlives its use.
+#[inline]
+pub unsafe fn lgdt(gdt: &DescriptorTableRegister) {
+    asm!("lgdt [{}]", in(reg) gdt, options(readonly, nostack, preserves_flags));
+}
+
+/// Load the IDT.
+///
+/// # Safety
+/// `idt` must describe a valid IDT that outlives its use.
+#[inline]
+pub unsafe fn lidt(idt: &DescriptorTableRegister) {
+    asm!("lidt [{}]", in(reg) idt, options(readonly, nostack, preserves_flags));
+}
+
+/// The current GDT register.
+#[inline]
+pub fn sgdt() -> DescriptorTableRegister {
+    let mut gdt = MaybeUninit::<DescriptorTableRegister>::uninit();
+    unsafe {
+        asm!("sgdt [{}]", in(reg) gdt.as_mut_ptr(), options(nostack, preserves_flags));
+        gdt.assume_init()
+    }
+}
+
+/// The current IDT register.
+#[inline]
+pub fn sidt() -> DescriptorTableRegister {
+    let mut idt = MaybeUninit::<DescriptorTableRegister>::uninit();
+    unsafe {
+        asm!("sidt [{}]", in(reg) idt.as_mut_ptr(), options(nostack, preserves_flags));
+        idt.assume_init()
+    }
+}
+
+/// Load the task register.
+///
+/// # Safety
+/// `selector` must point to an available TSS descriptor in the GDT.
+#[inline]
+pub unsafe fn ltr(selector: u16) {
+    asm!("ltr {:x}", in(reg) selector, options(nostack, preserves_flags));
+}
+
+/// Reload CS.
+///
+/// We can't move to CS directly, since it would be a JMP: instructions are
+/// fetched from CS:IP.
+/// https://stackoverflow.com/questions/52490438/why-cant-mov-set-cs-the-code-segment-register-even-though-it-can-set-others
+/// There are no long jumps in long mode, so we use the far return
+/// instruction, which pops IP and CS off the stack.
+///
+/// # Safety
+/// `selector` must point to a long mode code segment of the current GDT.
+#[inline]
+pub unsafe fn set_cs(selector: u16) {
+    asm!(
+        "push {cs}",
+        "lea {ip}, [rip + 2f]",
+        "push {ip}",
+        "retfq",
+        "2:",
+        cs = in(reg) selector as u64,
+        ip = lateout(reg) _,
+        options(preserves_flags)
+    );
+}
+
+/// Load `selector` in SS and every data segment register.
+/// This clears the FS and GS bases.
+///
+/// # Safety
+/// `selector` must point to a data segment of the current GDT.
+#[inline]
+pub unsafe fn load_data_segments(selector: u16) {
+    asm!(
+        "mov ds, {0:x}",
+        "mov es, {0:x}",
+        "mov fs, {0:x}",
+        "mov gs, {0:x}",
+        "mov ss, {0:x}",
+        in(reg) selector,
+        options(nostack, preserves_flags)
+    );
+}
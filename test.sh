@@ -32,7 +32,7 @@ qemu-system-x86_64 -cdrom barebones.iso --no-reboot -d int -D qemulog.log -seria
     -display none
 
 # Use our return values to see if the test suite  suceeded or not.
-# See test::TestResult (in kernel src/test.rs) for why we use these values.
+# See qemu::ExitCode (in kernel src/qemu.rs) for why we use these values.
 if [[ $? -eq 33 ]]; then
     exit 0
 else 